@@ -9,7 +9,7 @@
 //! $ cargo run --example custom_executor <BASE64ENR>
 //! ```
 
-use discv5::{enr, enr::CombinedKey, Discv5, Discv5ConfigBuilder, Discv5Event};
+use discv5::{enr, enr::CombinedKey, Discv5, Discv5ConfigBuilder, Discv5Event, EventStreamError};
 use std::net::SocketAddr;
 
 fn main() {
@@ -69,12 +69,25 @@ fn main() {
 
         loop {
             match event_stream.recv().await {
-                Some(Discv5Event::SocketUpdated(addr)) => {
-                    println!("Nodes ENR socket address has been updated to: {:?}", addr);
+                Ok(Discv5Event::Ipv4SocketUpdated(addr)) => {
+                    println!(
+                        "Nodes ENR IPv4 socket address has been updated to: {:?}",
+                        addr
+                    );
                 }
-                Some(Discv5Event::Discovered(enr)) => {
+                Ok(Discv5Event::Ipv6SocketUpdated(addr)) => {
+                    println!(
+                        "Nodes ENR IPv6 socket address has been updated to: {:?}",
+                        addr
+                    );
+                }
+                Ok(Discv5Event::Discovered(enr)) => {
                     println!("A peer has been discovered: {}", enr.node_id());
                 }
+                Err(EventStreamError::Lagged(missed)) => {
+                    println!("Missed {} events", missed);
+                }
+                Err(EventStreamError::Closed) => break,
                 _ => {}
             }
         }
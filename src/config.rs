@@ -1,4 +1,10 @@
-use crate::{Enr, Executor, FilterConfig, PermitBanList};
+use crate::{
+    kbucket::{
+        MAX_NODES_PER_BUCKET, MAX_NODES_PER_SUBNET_BUCKET, MAX_NODES_PER_SUBNET_TABLE,
+        MAX_REPLACEMENTS_PER_BUCKET,
+    },
+    Enr, Executor, FilterConfig, PermitBanList,
+};
 ///! A set of configuration parameters to tune the discovery protocol.
 use std::time::Duration;
 
@@ -31,7 +37,8 @@ pub struct Discv5Config {
     /// Updates the local ENR IP and port based on PONG responses from peers. Default: true.
     pub enr_update: bool,
 
-    /// The maximum number of nodes we return to a find nodes request. The default is 16.
+    /// The maximum number of nodes we return to a find nodes request. This is at least
+    /// `kbucket_size`, so that a full bucket can be returned. The default is 16.
     pub max_nodes_response: usize,
 
     /// The minimum number of peer's who agree on an external IP port before updating the
@@ -41,26 +48,80 @@ pub struct Discv5Config {
     /// The number of peers to request in parallel in a single query. Default: 3.
     pub query_parallelism: usize,
 
-    /// Limits the number of IP addresses from the same
-    /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks. Default: false.
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table. This is to
+    /// mitigate eclipse attacks. Default: false.
     pub ip_limit: bool,
 
+    /// The prefix length of the IPv4 subnets whose nodes are limited, when `ip_limit` is set.
+    /// Default: 24.
+    pub ipv4_subnet_prefix: u8,
+
+    /// The prefix length of the IPv6 subnets whose nodes are limited, when `ip_limit` is set.
+    /// Default: 56.
+    pub ipv6_subnet_prefix: u8,
+
+    /// The maximum number of nodes held in each bucket of the routing table, i.e. the Kademlia
+    /// `k` parameter. This is also the number of nodes a `FIND_NODE` query searches for.
+    /// Default: 16.
+    pub kbucket_size: usize,
+
+    /// The time a node waits to be inserted into a full bucket, during which the
+    /// least-recently connected node in the bucket can show it is still connected and avoid
+    /// being evicted. Default: 60 seconds.
+    pub kbucket_pending_timeout: Duration,
+
+    /// The maximum number of recently connected nodes kept for each bucket once it is full.
+    /// When a connected node of the bucket fails or is removed, the most recently seen of these
+    /// replaces it straight away, and is marked as connected once it responds to a PING.
+    /// Default: 10.
+    pub kbucket_replacement_cache_size: usize,
+
+    /// The maximum number of nodes from the same subnet in a single bucket, when
+    /// `ip_limit` is set. Default: 2.
+    pub max_nodes_per_subnet_bucket: usize,
+
+    /// The maximum number of nodes from the same subnet in the whole routing table, when
+    /// `ip_limit` is set. Default: 10.
+    pub max_nodes_per_subnet_table: usize,
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter. The default is to accept all nodes.
     pub table_filter: fn(&Enr) -> bool,
 
-    /// The callback for handling TALKREQ requests. The input to this callback is the protocol and
-    /// the output is the response sent back to the requester.
-    // This is a temporary measure and this may change in the future.
-    pub talkreq_callback: fn(&[u8], &[u8]) -> Vec<u8>,
-
     /// The time between pings to ensure connectivity amongst connected nodes. Default: 300
     /// seconds.
     pub ping_interval: Duration,
 
+    /// The time between rounds of routing table maintenance. Each round starts at most one
+    /// lookup, either of the local node id or of a random node id in a bucket that needs
+    /// refreshing. Default: 10 seconds.
+    pub table_maintenance_interval: Duration,
+
+    /// The time a bucket may go without being targeted by a lookup before it is refreshed with a
+    /// lookup of a random node id in its range. A lookup of the local node id is also run after
+    /// startup and whenever the table is bootstrapped from empty. `None` disables these lookups.
+    /// Default: 300 seconds.
+    pub bucket_refresh_interval: Option<Duration>,
+
     /// Reports all discovered ENR's when traversing the DHT to the event stream. Default true.
     pub report_discovered_peers: bool,
 
+    /// Reports the requests received from other nodes to the event stream. As every request
+    /// produces an event, this is best left off unless the events are consumed promptly.
+    /// Default false.
+    pub report_requests: bool,
+
+    /// The maximum number of topic advertisements stored in the local topic table across all
+    /// topics. Default: 1000.
+    pub topic_table_capacity: usize,
+
+    /// The maximum number of advertisements stored for any single topic. Default: 16.
+    pub topic_queue_capacity: usize,
+
+    /// The time an advertisement remains in the topic table once registered. Default: 15
+    /// minutes.
+    pub topic_ad_lifetime: Duration,
+
     /// A set of configuration parameters for the inbound packet filter. See `FilterConfig` for
     /// default values.
     pub filter_config: FilterConfig,
@@ -69,6 +130,19 @@ pub struct Discv5Config {
     /// `crate::PermitBanList`.
     pub permit_ban_list: PermitBanList,
 
+    /// The duration of bans issued automatically, such as for exceeding the request limit or
+    /// sending invalid ENRs. `None` bans permanently. Default: 1 hour.
+    pub ban_duration: Option<Duration>,
+
+    /// The reputation score below which a peer is automatically banned. Scores are lowered by
+    /// timeouts, invalid responses, failed handshakes and request spam. `None` disables banning
+    /// by reputation. Default: -50.
+    pub reputation_ban_threshold: Option<f64>,
+
+    /// The time taken for a peer's reputation score to decay halfway back to zero. Default: 10
+    /// minutes.
+    pub reputation_half_life: Duration,
+
     /// A custom executor which can spawn the discv5 tasks. This must be a tokio runtime, with
     /// timing support. By default, the executor that created the discv5 struct will be used.
     pub executor: Option<Box<dyn Executor + Send + Sync>>,
@@ -89,12 +163,27 @@ impl Default for Discv5Config {
             enr_peer_update_min: 10,
             query_parallelism: 3,
             ip_limit: false,
+            ipv4_subnet_prefix: 24,
+            ipv6_subnet_prefix: 56,
+            kbucket_size: MAX_NODES_PER_BUCKET,
+            kbucket_pending_timeout: Duration::from_secs(60),
+            kbucket_replacement_cache_size: MAX_REPLACEMENTS_PER_BUCKET,
+            max_nodes_per_subnet_bucket: MAX_NODES_PER_SUBNET_BUCKET,
+            max_nodes_per_subnet_table: MAX_NODES_PER_SUBNET_TABLE,
             table_filter: |_| true,
-            talkreq_callback: |_, _| Vec::new(),
             ping_interval: Duration::from_secs(300),
+            table_maintenance_interval: Duration::from_secs(10),
+            bucket_refresh_interval: Some(Duration::from_secs(300)),
             report_discovered_peers: true,
+            report_requests: false,
+            topic_table_capacity: 1000,
+            topic_queue_capacity: 16,
+            topic_ad_lifetime: Duration::from_secs(900),
             filter_config: FilterConfig::default(),
             permit_ban_list: PermitBanList::default(),
+            ban_duration: Some(Duration::from_secs(3600)),
+            reputation_ban_threshold: Some(-50.0),
+            reputation_half_life: Duration::from_secs(600),
             executor: None,
         }
     }
@@ -170,7 +259,8 @@ impl Discv5ConfigBuilder {
         self
     }
 
-    /// The maximum number of nodes we response to a find nodes request.
+    /// The maximum number of nodes we response to a find nodes request. The effective value is at
+    /// least the `kbucket_size`, so that a full bucket can be returned.
     pub fn max_nodes_response(&mut self, max: usize) -> &mut Self {
         self.config.max_nodes_response = max;
         self
@@ -192,13 +282,71 @@ impl Discv5ConfigBuilder {
         self
     }
 
-    /// Limits the number of IP addresses from the same
-    /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks.
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table. This is to
+    /// mitigate eclipse attacks.
     pub fn ip_limit(&mut self) -> &mut Self {
         self.config.ip_limit = true;
         self
     }
 
+    /// The prefix length of the IPv4 subnets whose nodes are limited, when `ip_limit` is set.
+    pub fn ipv4_subnet_prefix(&mut self, prefix: u8) -> &mut Self {
+        if prefix > 32 {
+            panic!("IPv4 subnet prefixes are at most 32 bits long");
+        }
+        self.config.ipv4_subnet_prefix = prefix;
+        self
+    }
+
+    /// The prefix length of the IPv6 subnets whose nodes are limited, when `ip_limit` is set.
+    pub fn ipv6_subnet_prefix(&mut self, prefix: u8) -> &mut Self {
+        if prefix > 128 {
+            panic!("IPv6 subnet prefixes are at most 128 bits long");
+        }
+        self.config.ipv6_subnet_prefix = prefix;
+        self
+    }
+
+    /// The maximum number of nodes held in each bucket of the routing table, i.e. the Kademlia
+    /// `k` parameter. The effective `max_nodes_response` is at least this size, so that a full
+    /// bucket can be returned to a find nodes request.
+    pub fn kbucket_size(&mut self, size: usize) -> &mut Self {
+        if size == 0 {
+            panic!("Buckets must be able to hold at least one node");
+        }
+        self.config.kbucket_size = size;
+        self.config.max_nodes_response = self.config.max_nodes_response.max(size);
+        self
+    }
+
+    /// The time a node waits to be inserted into a full bucket before it may evict the
+    /// least-recently connected node.
+    pub fn kbucket_pending_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.kbucket_pending_timeout = timeout;
+        self
+    }
+
+    /// The maximum number of recently connected nodes kept for each full bucket, to replace its
+    /// nodes that fail or are removed. A size of 0 disables the replacement cache.
+    pub fn kbucket_replacement_cache_size(&mut self, size: usize) -> &mut Self {
+        self.config.kbucket_replacement_cache_size = size;
+        self
+    }
+
+    /// The maximum number of nodes from the same subnet in a single bucket, when
+    /// `ip_limit` is set.
+    pub fn max_nodes_per_subnet_bucket(&mut self, max: usize) -> &mut Self {
+        self.config.max_nodes_per_subnet_bucket = max;
+        self
+    }
+
+    /// The maximum number of nodes from the same subnet in the whole routing table, when
+    /// `ip_limit` is set.
+    pub fn max_nodes_per_subnet_table(&mut self, max: usize) -> &mut Self {
+        self.config.max_nodes_per_subnet_table = max;
+        self
+    }
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter.
     pub fn table_filter(&mut self, filter: fn(&Enr) -> bool) -> &mut Self {
@@ -206,25 +354,57 @@ impl Discv5ConfigBuilder {
         self
     }
 
-    /// The callback function for handling TALK requests. The input is the protocol in bytes and the request data in bytes and
-    /// the output will be the response sent back to the requester.
-    pub fn talkreq_callback(&mut self, callback: fn(&[u8], &[u8]) -> Vec<u8>) -> &mut Self {
-        self.config.talkreq_callback = callback;
-        self
-    }
-
     /// The time between pings to ensure connectivity amongst connected nodes.
     pub fn ping_interval(&mut self, interval: Duration) -> &mut Self {
         self.config.ping_interval = interval;
         self
     }
 
+    /// The time between rounds of routing table maintenance, each of which starts at most one
+    /// lookup.
+    pub fn table_maintenance_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.table_maintenance_interval = interval;
+        self
+    }
+
+    /// The time a bucket may go without being targeted by a lookup before it is refreshed.
+    /// `None` disables the refresh and self-lookups.
+    pub fn bucket_refresh_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.config.bucket_refresh_interval = interval;
+        self
+    }
+
     /// Disables reporting of discovered peers through the event stream.
     pub fn disable_report_discovered_peers(&mut self) -> &mut Self {
         self.config.report_discovered_peers = false;
         self
     }
 
+    /// Enables reporting of the requests received from other nodes through the event stream.
+    pub fn report_requests(&mut self) -> &mut Self {
+        self.config.report_requests = true;
+        self
+    }
+
+    /// The maximum number of topic advertisements stored in the local topic table across all
+    /// topics.
+    pub fn topic_table_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.topic_table_capacity = capacity;
+        self
+    }
+
+    /// The maximum number of advertisements stored for any single topic.
+    pub fn topic_queue_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.topic_queue_capacity = capacity;
+        self
+    }
+
+    /// The time an advertisement remains in the topic table once registered.
+    pub fn topic_ad_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        self.config.topic_ad_lifetime = lifetime;
+        self
+    }
+
     /// A set of configuration parameters for the inbound packet filter.
     pub fn filter_config(&mut self, config: FilterConfig) -> &mut Self {
         self.config.filter_config = config;
@@ -238,6 +418,25 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The duration of bans issued automatically. `None` bans permanently.
+    pub fn ban_duration(&mut self, duration: Option<Duration>) -> &mut Self {
+        self.config.ban_duration = duration;
+        self
+    }
+
+    /// The reputation score below which a peer is automatically banned. `None` disables banning
+    /// by reputation.
+    pub fn reputation_ban_threshold(&mut self, threshold: Option<f64>) -> &mut Self {
+        self.config.reputation_ban_threshold = threshold;
+        self
+    }
+
+    /// The time taken for a peer's reputation score to decay halfway back to zero.
+    pub fn reputation_half_life(&mut self, half_life: Duration) -> &mut Self {
+        self.config.reputation_half_life = half_life;
+        self
+    }
+
     /// A custom executor which can spawn the discv5 tasks. This must be a tokio runtime, with
     /// timing support.
     pub fn executor(&mut self, executor: Box<dyn Executor + Send + Sync>) -> &mut Self {
@@ -246,6 +445,9 @@ impl Discv5ConfigBuilder {
     }
 
     pub fn build(&mut self) -> Discv5Config {
+        // a response must be able to hold a full bucket
+        self.config.max_nodes_response =
+            self.config.max_nodes_response.max(self.config.kbucket_size);
         // If an executor is not provided, assume a current tokio runtime is running.
         if self.config.executor.is_none() {
             self.config.executor = Some(Box::new(crate::executor::TokioExecutor::default()));
@@ -267,8 +469,36 @@ impl std::fmt::Debug for Discv5Config {
         let _ = builder.field("enr_update", &self.enr_update);
         let _ = builder.field("query_parallelism", &self.query_parallelism);
         let _ = builder.field("report_discovered_peers", &self.report_discovered_peers);
+        let _ = builder.field("report_requests", &self.report_requests);
         let _ = builder.field("ip_limit", &self.ip_limit);
+        let _ = builder.field("ipv4_subnet_prefix", &self.ipv4_subnet_prefix);
+        let _ = builder.field("ipv6_subnet_prefix", &self.ipv6_subnet_prefix);
+        let _ = builder.field("kbucket_size", &self.kbucket_size);
+        let _ = builder.field("kbucket_pending_timeout", &self.kbucket_pending_timeout);
+        let _ = builder.field(
+            "kbucket_replacement_cache_size",
+            &self.kbucket_replacement_cache_size,
+        );
+        let _ = builder.field(
+            "max_nodes_per_subnet_bucket",
+            &self.max_nodes_per_subnet_bucket,
+        );
+        let _ = builder.field(
+            "max_nodes_per_subnet_table",
+            &self.max_nodes_per_subnet_table,
+        );
         let _ = builder.field("ping_interval", &self.ping_interval);
+        let _ = builder.field(
+            "table_maintenance_interval",
+            &self.table_maintenance_interval,
+        );
+        let _ = builder.field("bucket_refresh_interval", &self.bucket_refresh_interval);
+        let _ = builder.field("topic_table_capacity", &self.topic_table_capacity);
+        let _ = builder.field("topic_queue_capacity", &self.topic_queue_capacity);
+        let _ = builder.field("topic_ad_lifetime", &self.topic_ad_lifetime);
+        let _ = builder.field("ban_duration", &self.ban_duration);
+        let _ = builder.field("reputation_ban_threshold", &self.reputation_ban_threshold);
+        let _ = builder.field("reputation_half_life", &self.reputation_half_life);
         builder.finish()
     }
 }
@@ -13,32 +13,43 @@
 //! The server can be shutdown using the [`shutdown()`] function.
 
 use crate::{
-    error::{Discv5Error, QueryError, RequestError},
-    kbucket::{self, ip_limiter, KBucketsTable, NodeStatus},
+    error::{Discv5Error, EventStreamError, QueryError, RequestError},
+    kbucket::{self, ip_limiter, snapshot, KBucketsTable, NodeStatus, TableInfo},
     node_info::NodeContact,
-    service::{QueryKind, Service, ServiceRequest},
-    Discv5Config, Enr,
+    permit_ban::{Ban, BanReason, IpNet},
+    query_pool::{QueryId, QueryStats},
+    reputation::Reputation,
+    rpc::RequestKind,
+    service::{QueryCallback, QueryKind, Service, ServiceRequest, TalkRequest},
+    socket::{Socket, Transports},
+    Discv5Config, Enr, ListenConfig, Transport,
 };
 use enr::{CombinedKey, EnrError, EnrKey, NodeId};
 use parking_lot::RwLock;
-use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, warn};
 
 #[cfg(feature = "libp2p")]
 use {libp2p_core::Multiaddr, std::convert::TryFrom};
 
-// Create lazy static variable for the global permit/ban list
-use crate::metrics::{Metrics, METRICS};
-lazy_static! {
-    pub static ref PERMIT_BAN_LIST: RwLock<crate::PermitBanList> =
-        RwLock::new(crate::PermitBanList::default());
-}
+use crate::{
+    metrics::{InternalMetrics, Metrics},
+    PermitBanList,
+};
 
 mod test;
 
 /// Events that can be produced by the `Discv5` event stream.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Discv5Event {
     /// A node has been discovered from a FINDNODES request.
     ///
@@ -53,8 +64,169 @@ pub enum Discv5Event {
         node_id: NodeId,
         replaced: Option<NodeId>,
     },
-    /// Our local ENR IP address has been updated.
-    SocketUpdated(SocketAddr),
+    /// A node has been removed from the routing table, either by the application or by being
+    /// evicted to make room for the node `replaced_by`.
+    NodeRemoved {
+        node_id: NodeId,
+        replaced_by: Option<NodeId>,
+    },
+    /// Our local ENR IPv4 address and UDP port have been updated.
+    Ipv4SocketUpdated(SocketAddrV4),
+    /// Our local ENR IPv6 address and UDP port have been updated.
+    Ipv6SocketUpdated(SocketAddrV6),
+    /// Our local ENR has been updated and its sequence number increased. The updated ENR is
+    /// returned.
+    LocalEnrUpdated(Enr),
+    /// A session has been established with a node. The ENR of the node is returned.
+    SessionEstablished(Enr),
+    /// A session with a node has expired or been dropped, after which a new handshake is
+    /// required to communicate with the node. Sessions that time out are reported once they are
+    /// found to have expired, the next time a session is looked up.
+    SessionExpired {
+        node_id: NodeId,
+        socket_addr: SocketAddr,
+    },
+    /// A request has been received from a node. Only reported if enabled with
+    /// `Discv5ConfigBuilder::report_requests`.
+    RequestReceived { node_id: NodeId, kind: RequestKind },
+    /// A TALKREQ request has been received from a node. Requests for registered protocols are
+    /// also delivered to the stream of the protocol, from which they are answered. Only reported
+    /// if enabled with `Discv5ConfigBuilder::report_requests`.
+    TalkRequestReceived { node_id: NodeId, protocol: Vec<u8> },
+    /// A node has been banned, either by the packet filter or for misbehaving.
+    Banned { node_id: NodeId, reason: BanReason },
+}
+
+/// A stream of the events produced by a running `Discv5` service.
+///
+/// Every stream receives all events. Each stream buffers events independently, so a slow
+/// consumer only loses events from its own stream, and is told how many with an
+/// [`EventStreamError::Lagged`] error before the next event it receives.
+pub struct EventStream {
+    recv: mpsc::Receiver<Result<Discv5Event, EventStreamError>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(recv: mpsc::Receiver<Result<Discv5Event, EventStreamError>>) -> Self {
+        EventStream { recv }
+    }
+
+    /// Receives the next event, or the number of events dropped since the last one received if
+    /// the buffer of this stream overflowed. Returns `EventStreamError::Closed` once the service
+    /// has shut down.
+    pub async fn recv(&mut self) -> Result<Discv5Event, EventStreamError> {
+        self.recv
+            .recv()
+            .await
+            .unwrap_or(Err(EventStreamError::Closed))
+    }
+}
+
+impl futures::Stream for EventStream {
+    type Item = Result<Discv5Event, EventStreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+/// An update on the progress of a streamed query.
+#[derive(Debug, Clone)]
+pub enum QueryUpdate {
+    /// A peer responded with a node that the query is looking for. Nodes are reported once per
+    /// query, as soon as they are received and validated.
+    Found(Enr),
+    /// The number of peers the query has contacted, is waiting on or has failed to reach changed.
+    Progress(QueryStats),
+    /// The query finished or timed out, with the result it would have returned if it was not
+    /// streamed. This is the last update of the stream.
+    Finished(Vec<Enr>),
+}
+
+/// Cancels a query when dropped, unless the query has finished.
+struct QueryGuard {
+    /// The id of the query.
+    id: QueryId,
+    /// The channel to the service running the query.
+    channel: mpsc::Sender<ServiceRequest>,
+    /// Whether all results of the query have been received.
+    finished: bool,
+}
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            // if the channel is full, the service still stops the query before its next request,
+            // as it finds the results are no longer wanted
+            let _ = self.channel.try_send(ServiceRequest::CancelQuery(self.id));
+        }
+    }
+}
+
+/// A handle to a running query, which resolves to the nodes found once the query finishes.
+///
+/// Dropping the handle, or calling `cancel()`, stops the query along with its outstanding
+/// requests.
+pub struct QueryHandle {
+    guard: QueryGuard,
+    result: oneshot::Receiver<Vec<Enr>>,
+}
+
+impl QueryHandle {
+    /// The id of the query.
+    pub fn id(&self) -> QueryId {
+        self.guard.id
+    }
+
+    /// Stops the query.
+    pub fn cancel(self) {}
+}
+
+impl Future for QueryHandle {
+    type Output = Result<Vec<Enr>, QueryError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = futures::ready!(Pin::new(&mut self.result).poll(cx));
+        self.guard.finished = true;
+        Poll::Ready(result.map_err(|e| QueryError::ChannelFailed(e.to_string())))
+    }
+}
+
+/// A stream of the updates of a running query.
+///
+/// Dropping the stream, or calling `cancel()`, stops the query along with its outstanding
+/// requests, so a consumer that has found the nodes it needs can end the query early.
+pub struct QueryStream {
+    guard: QueryGuard,
+    recv: mpsc::UnboundedReceiver<QueryUpdate>,
+}
+
+impl QueryStream {
+    /// The id of the query.
+    pub fn id(&self) -> QueryId {
+        self.guard.id
+    }
+
+    /// Receives the next update of the query. Returns `None` once the query has finished and
+    /// all updates have been received.
+    pub async fn recv(&mut self) -> Option<QueryUpdate> {
+        futures::StreamExt::next(self).await
+    }
+
+    /// Stops the query.
+    pub fn cancel(self) {}
+}
+
+impl futures::Stream for QueryStream {
+    type Item = QueryUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let update = futures::ready!(self.recv.poll_recv(cx));
+        if update.is_none() {
+            self.guard.finished = true;
+        }
+        Poll::Ready(update)
+    }
 }
 
 /// The main Discv5 Service struct. This provides the user-level API for performing queries and
@@ -67,6 +239,15 @@ pub struct Discv5 {
     service_exit: Option<oneshot::Sender<()>>,
     /// The routing table of the discv5 service.
     kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    /// The permit/ban list, shared with the underlying service and packet filter.
+    permit_ban_list: Arc<RwLock<PermitBanList>>,
+    /// The metrics of this instance, updated by the underlying tasks.
+    metrics: Arc<InternalMetrics>,
+    /// The reputation scores of peers, shared with the underlying tasks.
+    reputation: Arc<Reputation>,
+    /// Entries restored from a routing table snapshot, to be re-validated once the service
+    /// starts.
+    restored_enrs: Vec<Enr>,
     /// The local ENR of the server.
     local_enr: Arc<RwLock<Enr>>,
     /// The key associated with the local ENR, required for updating the local ENR.
@@ -93,43 +274,130 @@ impl Discv5 {
         let enr_key = Arc::new(RwLock::new(enr_key));
         let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
             local_enr.read().node_id().into(),
-            Duration::from_secs(60),
+            config.kbucket_pending_timeout,
+            config.kbucket_size,
+            config.kbucket_replacement_cache_size,
+            config.max_nodes_per_subnet_bucket,
+            config.max_nodes_per_subnet_table,
         )));
 
-        // Update the PermitBan list based on initial configuration
-        *PERMIT_BAN_LIST.write() = config.permit_ban_list.clone();
+        // The PermitBan list is initialised from the configuration
+        let permit_ban_list = Arc::new(RwLock::new(config.permit_ban_list.clone()));
+
+        let metrics = Arc::new(InternalMetrics::default());
+        #[cfg(feature = "prometheus")]
+        metrics.prometheus.register_table(
+            local_enr.read().node_id(),
+            kbuckets.clone(),
+            permit_ban_list.clone(),
+        );
+        let reputation = Arc::new(Reputation::new(
+            &config,
+            permit_ban_list.clone(),
+            metrics.clone(),
+        ));
 
         Ok(Discv5 {
             config,
             service_channel: None,
             service_exit: None,
             kbuckets,
+            permit_ban_list,
+            metrics,
+            reputation,
+            restored_enrs: Vec::new(),
             local_enr,
             enr_key,
         })
     }
 
-    /// Starts the required tasks and begins listening on a given UDP SocketAddr.
-    pub async fn start(&mut self, listen_socket: SocketAddr) -> Result<(), Discv5Error> {
+    /// Starts the required tasks and begins listening on the given UDP sockets.
+    ///
+    /// A single `SocketAddr` can be given to listen on one address family, or a
+    /// `ListenConfig::DualStack` to listen on both IPv4 and IPv6. If the local ENR does not yet
+    /// advertise a UDP port for a listening family, it is updated to do so.
+    pub async fn start(
+        &mut self,
+        listen_config: impl Into<ListenConfig>,
+    ) -> Result<(), Discv5Error> {
         if self.service_channel.is_some() {
             warn!("Service is already started");
             return Err(Discv5Error::ServiceAlreadyStarted);
         }
+        let transports = Socket::new_sockets(&listen_config.into())?;
+        self.start_with_transports(transports).await
+    }
+
+    /// Starts the required tasks, sending and receiving over a custom transport rather than a
+    /// UDP socket. The transport's local address determines the address family the server
+    /// listens on.
+    pub async fn start_with_transport(
+        &mut self,
+        transport: impl Transport,
+    ) -> Result<(), Discv5Error> {
+        if self.service_channel.is_some() {
+            warn!("Service is already started");
+            return Err(Discv5Error::ServiceAlreadyStarted);
+        }
+        self.start_with_transports(Transports::single(transport)?)
+            .await
+    }
+
+    async fn start_with_transports(&mut self, transports: Transports) -> Result<(), Discv5Error> {
+        let listen_config = transports.listen_config()?;
+        self.advertise_listen_sockets(&listen_config)
+            .map_err(|e| Discv5Error::Error(format!("Could not update the local ENR: {:?}", e)))?;
 
         // create the main service
         let (service_exit, service_channel) = Service::spawn(
             self.local_enr.clone(),
             self.enr_key.clone(),
             self.kbuckets.clone(),
+            self.permit_ban_list.clone(),
+            self.metrics.clone(),
+            self.reputation.clone(),
             self.config.clone(),
-            listen_socket,
+            transports,
         )
         .await?;
+        // re-validate any entries restored from a routing table snapshot
+        if !self.restored_enrs.is_empty() {
+            let restored_enrs = std::mem::take(&mut self.restored_enrs);
+            service_channel
+                .send(ServiceRequest::Revalidate(restored_enrs))
+                .await
+                .map_err(|_| Discv5Error::ServiceChannelClosed)?;
+        }
         self.service_exit = Some(service_exit);
         self.service_channel = Some(service_channel);
         Ok(())
     }
 
+    /// Adds the UDP port of each listening socket to the local ENR, if the ENR does not already
+    /// advertise one for that address family. The IP address is only added if the socket is
+    /// bound to a specific address.
+    fn advertise_listen_sockets(&mut self, listen_config: &ListenConfig) -> Result<(), EnrError> {
+        let mut local_enr = self.local_enr.write();
+        let enr_key = self.enr_key.read();
+        for socket_addr in listen_config.ipv4().into_iter().chain(listen_config.ipv6()) {
+            let (has_ip, has_port) = match socket_addr {
+                SocketAddr::V4(_) => (local_enr.ip().is_some(), local_enr.udp().is_some()),
+                SocketAddr::V6(_) => (local_enr.ip6().is_some(), local_enr.udp6().is_some()),
+            };
+            if has_port {
+                continue;
+            }
+            if !has_ip && !socket_addr.ip().is_unspecified() {
+                local_enr.set_udp_socket(socket_addr, &enr_key)?;
+            } else if socket_addr.is_ipv4() {
+                local_enr.set_udp(socket_addr.port(), &enr_key)?;
+            } else {
+                local_enr.set_udp6(socket_addr.port(), &enr_key)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Terminates the service.
     pub fn shutdown(&mut self) {
         if let Some(exit) = self.service_exit.take() {
@@ -150,8 +418,19 @@ impl Discv5 {
     /// operations involving one of these peers, without having to dial
     /// them upfront.
     pub fn add_enr(&mut self, enr: Enr) -> Result<(), &'static str> {
-        // only add ENR's that have a valid udp socket.
-        if enr.udp_socket().is_none() {
+        self.insert_enr(enr, NodeStatus::Disconnected, None)
+    }
+
+    /// Inserts an ENR into the routing table with the given status and last seen time, if it
+    /// passes the table filter and IP limits.
+    fn insert_enr(
+        &mut self,
+        enr: Enr,
+        status: NodeStatus,
+        last_seen: Option<SystemTime>,
+    ) -> Result<(), &'static str> {
+        // only add ENR's that have a valid udp socket of either address family.
+        if enr.udp_socket().is_none() && enr.udp6_socket().is_none() {
             warn!("ENR attempted to be added without a UDP socket has been ignored");
             return Err("ENR has no UDP socket to connect to");
         }
@@ -165,10 +444,15 @@ impl Discv5 {
 
         // should the ENR be inserted or updated to a value that would exceed the IP limit ban
         let ip_limit_ban = self.config.ip_limit
-            && !self
-                .kbuckets
-                .read()
-                .check(&key, &enr, |v, o, l| ip_limiter(v, &o, l));
+            && !self.kbuckets.read().check(&key, &enr, |v, o, l| {
+                ip_limiter(
+                    v,
+                    &o,
+                    l,
+                    self.config.ipv4_subnet_prefix,
+                    self.config.ipv6_subnet_prefix,
+                )
+            });
 
         match self.kbuckets.write().entry(&key) {
             kbucket::Entry::Present(mut entry, _) => {
@@ -180,7 +464,7 @@ impl Discv5 {
             }
             kbucket::Entry::Absent(entry) => {
                 if !ip_limit_ban {
-                    match entry.insert(enr, NodeStatus::Disconnected) {
+                    match entry.insert_with_last_seen(enr, status, last_seen) {
                         kbucket::InsertResult::Inserted => {}
                         kbucket::InsertResult::Full => {
                             return Err("Table full");
@@ -194,19 +478,67 @@ impl Discv5 {
         Ok(())
     }
 
+    /// Writes the current contents of the routing table to the file at `path`, so that they can be
+    /// restored with [`Discv5::load_routing_table`] after a restart.
+    ///
+    /// Each node's ENR, status and last seen time are stored. Returns the number of entries
+    /// written.
+    pub fn save_routing_table(&self, path: impl AsRef<Path>) -> Result<usize, Discv5Error> {
+        let entries = snapshot::snapshot(&self.kbuckets.read());
+        snapshot::write(path.as_ref(), &entries)?;
+        Ok(entries.len())
+    }
+
+    /// Populates the routing table from a file written by [`Discv5::save_routing_table`].
+    ///
+    /// This must be called before the service is started. The restored entries keep their
+    /// status and last seen time and are re-validated with a PING once the service starts.
+    /// Entries that no longer pass the table filter or IP limits are ignored. Returns the number
+    /// of entries inserted.
+    pub fn load_routing_table(&mut self, path: impl AsRef<Path>) -> Result<usize, Discv5Error> {
+        if self.service_channel.is_some() {
+            return Err(Discv5Error::ServiceAlreadyStarted);
+        }
+
+        let mut restored = 0;
+        for entry in snapshot::read(path.as_ref())? {
+            let enr = entry.enr.clone();
+            match self.insert_enr(entry.enr, entry.status, entry.last_seen) {
+                Ok(()) => {
+                    self.restored_enrs.push(enr);
+                    restored += 1;
+                }
+                Err(e) => debug!(
+                    "Could not restore node {} from snapshot: {}",
+                    enr.node_id(),
+                    e
+                ),
+            }
+        }
+        Ok(restored)
+    }
+
     /// Removes a `node_id` from the routing table.
     ///
     /// This allows applications, for whatever reason, to remove nodes from the local routing
     /// table. Returns `true` if the node was in the table and `false` otherwise.
     pub fn remove_node(&mut self, node_id: &NodeId) -> bool {
         let key = &kbucket::Key::from(*node_id);
-        self.kbuckets.write().remove(key)
+        let removed = self.kbuckets.write().remove(key);
+        if removed {
+            self.notify(Discv5Event::NodeRemoved {
+                node_id: *node_id,
+                replaced_by: None,
+            });
+        }
+        removed
     }
 
     /// Mark a node in the routing table as `Disconnnected`.
     ///
     /// A `Disconnected` node will be present in the routing table and will be only
-    /// used if there are no other `Connected` peers in the bucket.
+    /// used if there are no other `Connected` peers in the bucket. A previously `Connected`
+    /// node is replaced by a replacement candidate of its bucket, if there is one.
     /// Returns `true` if node was in table and `false` otherwise.
     pub fn disconnect_node(&mut self, node_id: &NodeId) -> bool {
         let key = &kbucket::Key::from(*node_id);
@@ -234,12 +566,25 @@ impl Discv5 {
 
     /// Gets the metrics associated with the Server
     pub fn metrics(&self) -> Metrics {
-        Metrics::from(&METRICS)
+        Metrics::from(self.metrics.as_ref())
     }
 
-    /// Exposes the raw reference to the underlying internal metrics.
-    pub fn raw_metrics() -> &'static METRICS {
-        &METRICS
+    /// Returns the reputation score of a node. Scores start at zero, fall when the node
+    /// misbehaves and rise when it responds correctly, decaying back towards zero over time.
+    pub fn peer_score(&self, node_id: &NodeId) -> f64 {
+        self.reputation.score(node_id)
+    }
+
+    /// Exposes the raw reference to the underlying internal metrics of this instance.
+    pub fn raw_metrics(&self) -> Arc<InternalMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the Prometheus registry holding the detailed metrics of this instance.
+    #[cfg(feature = "prometheus")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "prometheus")))]
+    pub fn prometheus_registry(&self) -> &prometheus::Registry {
+        self.metrics.prometheus.registry()
     }
 
     /// Returns the local ENR of the node.
@@ -258,52 +603,132 @@ impl Discv5 {
     }
 
     /// Bans a node from the server. This will remove the node from the routing table if it exists
-    /// and block all incoming packets from the node.
-    pub fn ban_node(&mut self, node_id: &NodeId) {
+    /// and block all incoming packets from the node. A `duration` of `None` bans the node
+    /// permanently.
+    pub fn ban_node(&mut self, node_id: &NodeId, duration: Option<Duration>) {
         self.remove_node(node_id);
-        PERMIT_BAN_LIST.write().ban_nodes.insert(*node_id);
+        self.permit_ban_list
+            .write()
+            .ban_node(*node_id, BanReason::Manual, duration);
+        self.metrics.banned(BanReason::Manual);
     }
 
     /// Removes a banned node from the banned list.
     pub fn ban_node_remove(&mut self, node_id: &NodeId) {
-        PERMIT_BAN_LIST.write().ban_nodes.remove(node_id);
+        self.permit_ban_list.write().ban_nodes.remove(node_id);
     }
 
     /// Permits a node, allowing the node to bypass the packet filter.  
     pub fn permit_node(&mut self, node_id: &NodeId) {
-        PERMIT_BAN_LIST.write().permit_nodes.insert(*node_id);
+        self.permit_ban_list.write().permit_nodes.insert(*node_id);
     }
 
     /// Removes a node from the permit list.
     pub fn permit_node_remove(&mut self, node_id: &NodeId) {
-        PERMIT_BAN_LIST.write().permit_nodes.remove(node_id);
+        self.permit_ban_list.write().permit_nodes.remove(node_id);
     }
 
-    /// Bans an IP from the server.  This will block all incoming packets from the IP.
-    pub fn ban_ip(&mut self, ip: std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().ban_ips.insert(ip);
+    /// Bans an IP from the server.  This will block all incoming packets from the IP. A
+    /// `duration` of `None` bans the IP permanently.
+    pub fn ban_ip(&mut self, ip: std::net::IpAddr, duration: Option<Duration>) {
+        self.permit_ban_list
+            .write()
+            .ban_ip(ip, BanReason::Manual, duration);
+        self.metrics.banned(BanReason::Manual);
     }
 
     /// Removes a banned IP from the banned list.
     pub fn ban_ip_remove(&mut self, ip: &std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().ban_ips.remove(ip);
+        self.permit_ban_list.write().ban_ips.remove(ip);
+    }
+
+    /// Bans every IP of an IPv4 or IPv6 subnet, such as `192.0.2.0/24`. A `duration` of `None`
+    /// bans the subnet permanently.
+    pub fn ban_subnet(&mut self, subnet: IpNet, duration: Option<Duration>) {
+        self.permit_ban_list
+            .write()
+            .ban_subnet(subnet, BanReason::Manual, duration);
+        self.metrics.banned(BanReason::Manual);
+    }
+
+    /// Removes a banned subnet from the banned list.
+    pub fn ban_subnet_remove(&mut self, subnet: &IpNet) {
+        self.permit_ban_list
+            .write()
+            .ban_subnets
+            .remove(&subnet.trunc());
+    }
+
+    /// Permits every IP of an IPv4 or IPv6 subnet, allowing all packets from the subnet to bypass
+    /// the packet filter.
+    pub fn permit_subnet(&mut self, subnet: IpNet) {
+        self.permit_ban_list
+            .write()
+            .permit_subnets
+            .insert(subnet.trunc());
+    }
+
+    /// Removes a subnet from the permit list.
+    pub fn permit_subnet_remove(&mut self, subnet: &IpNet) {
+        self.permit_ban_list
+            .write()
+            .permit_subnets
+            .remove(&subnet.trunc());
+    }
+
+    /// Returns the currently banned subnets with their bans.
+    pub fn banned_subnets(&self) -> Vec<(IpNet, Ban)> {
+        let mut permit_ban_list = self.permit_ban_list.write();
+        permit_ban_list.remove_expired();
+        permit_ban_list
+            .ban_subnets
+            .iter()
+            .map(|(subnet, ban)| (*subnet, ban.clone()))
+            .collect()
+    }
+
+    /// Returns the currently banned nodes with their bans. `Ban::remaining` gives the time left
+    /// on each ban.
+    pub fn banned_nodes(&self) -> Vec<(NodeId, Ban)> {
+        let mut permit_ban_list = self.permit_ban_list.write();
+        permit_ban_list.remove_expired();
+        permit_ban_list
+            .ban_nodes
+            .iter()
+            .map(|(node_id, ban)| (*node_id, ban.clone()))
+            .collect()
+    }
+
+    /// Returns the currently banned IPs with their bans. `Ban::remaining` gives the time left on
+    /// each ban.
+    pub fn banned_ips(&self) -> Vec<(std::net::IpAddr, Ban)> {
+        let mut permit_ban_list = self.permit_ban_list.write();
+        permit_ban_list.remove_expired();
+        permit_ban_list
+            .ban_ips
+            .iter()
+            .map(|(ip, ban)| (*ip, ban.clone()))
+            .collect()
     }
 
     /// Permits an IP, allowing the all packets from the IP to bypass the packet filter.  
     pub fn permit_ip(&mut self, ip: std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().permit_ips.insert(ip);
+        self.permit_ban_list.write().permit_ips.insert(ip);
     }
 
     /// Removes an IP from the permit list.
     pub fn permit_ip_remove(&mut self, ip: &std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().permit_ips.remove(ip);
+        self.permit_ban_list.write().permit_ips.remove(ip);
     }
 
     /// Updates the local ENR TCP/UDP socket.
     pub fn update_local_enr_socket(&mut self, socket_addr: SocketAddr, is_tcp: bool) -> bool {
-        let local_socket = self.local_enr.read().udp_socket();
+        let local_socket = match socket_addr {
+            SocketAddr::V4(_) => self.local_enr.read().udp_socket(),
+            SocketAddr::V6(_) => self.local_enr.read().udp6_socket(),
+        };
         if local_socket != Some(socket_addr) {
-            if is_tcp {
+            let updated = if is_tcp {
                 self.local_enr
                     .write()
                     .set_tcp_socket(socket_addr, &self.enr_key.read())
@@ -313,7 +738,11 @@ impl Discv5 {
                     .write()
                     .set_udp_socket(socket_addr, &self.enr_key.read())
                     .is_ok()
+            };
+            if updated {
+                self.notify(Discv5Event::LocalEnrUpdated(self.local_enr()));
             }
+            updated
         } else {
             false
         }
@@ -321,10 +750,13 @@ impl Discv5 {
 
     /// Allows application layer to insert an arbitrary field into the local ENR.
     pub fn enr_insert(&mut self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>, EnrError> {
-        self.local_enr
+        let previous = self
+            .local_enr
             .write()
             .insert(key, value, &self.enr_key.read())
-            .map(|v| v.map(|v| v.to_vec()))
+            .map(|v| v.map(|v| v.to_vec()))?;
+        self.notify(Discv5Event::LocalEnrUpdated(self.local_enr()));
+        Ok(previous)
     }
 
     /// Returns an iterator over all ENR node IDs of nodes currently contained in the routing table.
@@ -345,6 +777,16 @@ impl Discv5 {
             .collect()
     }
 
+    /// Returns a view of the routing table, with the contents of each bucket and the number of
+    /// nodes sharing each IP address and subnet across the table.
+    pub fn table_info(&self) -> TableInfo {
+        TableInfo::of(
+            &self.kbuckets.read(),
+            self.config.ipv4_subnet_prefix,
+            self.config.ipv6_subnet_prefix,
+        )
+    }
+
     /// Requests the ENR of a node corresponding to multiaddr or multi-addr string.
     ///
     /// Only `ed25519` and `secp256k1` key types are currently supported.
@@ -417,41 +859,54 @@ impl Discv5 {
         }
     }
 
-    /// Runs an iterative `FIND_NODE` request.
-    ///
-    /// This will return peers containing contactable nodes of the DHT closest to the
-    /// requested `NodeId`.
+    /// Registers a TALK protocol, returning a stream of the TALK requests received for it.
     ///
-    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
-    /// underlying sending channel is cloned.
-    pub fn find_node(
+    /// Each [`TalkRequest`] is answered via [`TalkRequest::respond()`], which can be called at
+    /// any later point. Requests dropped without a response, and requests for protocols that are
+    /// not registered, are answered with an empty response. Registering a protocol again replaces
+    /// the previous stream.
+    pub fn register_talk_protocol(
         &mut self,
-        target_node: NodeId,
-    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        protocol: Vec<u8>,
+    ) -> impl Future<Output = Result<mpsc::Receiver<TalkRequest>, Discv5Error>> + 'static {
         let channel = self.clone_channel();
 
         async move {
-            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
-            let (callback_send, callback_recv) = oneshot::channel();
+            let channel = channel?;
 
-            let query_kind = QueryKind::FindNode { target_node };
+            let (callback_send, callback_recv) = oneshot::channel();
 
-            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            let event = ServiceRequest::RegisterTalkProtocol(protocol, callback_send);
             channel
                 .send(event)
                 .await
-                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+                .map_err(|_| Discv5Error::ServiceChannelClosed)?;
 
-            Ok(callback_recv
+            callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?)
+                .map_err(|_| Discv5Error::ServiceChannelClosed)
         }
     }
 
+    /// Runs an iterative `FIND_NODE` request.
+    ///
+    /// This will return peers containing contactable nodes of the DHT closest to the
+    /// requested `NodeId`. Dropping the returned future stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node(
+        &mut self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.start_find_node(target_node);
+        async move { query.await?.await }
+    }
+
     /// Starts a `FIND_NODE` request.
     ///
     /// This will return less than or equal to `num_nodes` ENRs which satisfy the
-    /// `predicate`.
+    /// `predicate`. Dropping the returned future stops the query.
     ///
     /// The predicate is a boxed function that takes an ENR reference and returns a boolean
     /// indicating if the record is applicable to the query or not.
@@ -471,34 +926,277 @@ impl Discv5 {
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
         target_peer_no: usize,
     ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.start_find_node_predicate(target_node, predicate, target_peer_no);
+        async move { query.await?.await }
+    }
+
+    /// Runs an iterative `FIND_NODE` request along `paths` disjoint paths.
+    ///
+    /// The closest known nodes are split between the paths, and no node is queried by more than
+    /// one path, so a node that answers with fake close nodes only misleads the path that
+    /// queried it. The closest nodes found by any path are returned once all paths have finished.
+    /// Dropping the returned future stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_disjoint(
+        &mut self,
+        target_node: NodeId,
+        paths: usize,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.start_find_node_disjoint(target_node, paths);
+        async move { query.await?.await }
+    }
+
+    /// Starts an iterative `FIND_NODE` request, returning a handle to the query once it has
+    /// started.
+    ///
+    /// The handle resolves to the same nodes as `find_node()`. Dropping the handle, or calling
+    /// `cancel()` on it, stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn start_find_node(
+        &mut self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<QueryHandle, QueryError>> + 'static {
+        self.start_query_handle(QueryKind::FindNode { target_node })
+    }
+
+    /// Starts a `FIND_NODE` request for nodes which satisfy the `predicate`, returning a handle
+    /// to the query once it has started.
+    ///
+    /// The handle resolves to the same nodes as `find_node_predicate()`. Dropping the handle, or
+    /// calling `cancel()` on it, stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn start_find_node_predicate(
+        &mut self,
+        target_node: NodeId,
+        predicate: Box<dyn Fn(&Enr) -> bool + Send>,
+        target_peer_no: usize,
+    ) -> impl Future<Output = Result<QueryHandle, QueryError>> + 'static {
+        self.start_query_handle(QueryKind::Predicate {
+            target_node,
+            predicate,
+            target_peer_no,
+        })
+    }
+
+    /// Starts an iterative `FIND_NODE` request along `paths` disjoint paths, returning a handle
+    /// to the query once it has started.
+    ///
+    /// The handle resolves to the same nodes as `find_node_disjoint()`. Dropping the handle, or
+    /// calling `cancel()` on it, stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn start_find_node_disjoint(
+        &mut self,
+        target_node: NodeId,
+        paths: usize,
+    ) -> impl Future<Output = Result<QueryHandle, QueryError>> + 'static {
+        self.start_query_handle(QueryKind::DisjointFindNode { target_node, paths })
+    }
+
+    /// Starts an iterative `FIND_NODE` request, streaming its progress.
+    ///
+    /// Unlike `find_node()`, the nodes found are reported as soon as they are received, followed
+    /// by the closest nodes once the query finishes. Dropping the returned stream stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_stream(
+        &mut self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<QueryStream, QueryError>> + 'static {
+        let query_kind = QueryKind::FindNode { target_node };
+        self.start_query_stream(query_kind)
+    }
+
+    /// Starts a `FIND_NODE` request for nodes which satisfy the `predicate`, streaming its
+    /// progress.
+    ///
+    /// Unlike `find_node_predicate()`, the nodes that satisfy the predicate are reported as soon
+    /// as they are received, followed by at most `num_nodes` of them once the query finishes.
+    /// Dropping the returned stream stops the query.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    ///
+    /// ### Example
+    /// ```ignore
+    ///  let predicate = Box::new(|enr: &Enr| enr.ip().is_some());
+    ///  let mut stream = discv5.find_node_predicate_stream(NodeId::random(), predicate, 5).await?;
+    ///  while let Some(update) = stream.recv().await {
+    ///      if let QueryUpdate::Found(enr) = update {
+    ///          // dial the node, and drop the stream once enough nodes are found
+    ///      }
+    ///  }
+    ///  ```
+    pub fn find_node_predicate_stream(
+        &mut self,
+        target_node: NodeId,
+        predicate: Box<dyn Fn(&Enr) -> bool + Send>,
+        target_peer_no: usize,
+    ) -> impl Future<Output = Result<QueryStream, QueryError>> + 'static {
+        let query_kind = QueryKind::Predicate {
+            target_node,
+            predicate,
+            target_peer_no,
+        };
+        self.start_query_stream(query_kind)
+    }
+
+    fn start_query_handle(
+        &mut self,
+        query_kind: QueryKind,
+    ) -> impl Future<Output = Result<QueryHandle, QueryError>> + 'static {
+        let (callback_send, callback_recv) = oneshot::channel();
+        let guard = self.start_query(query_kind, QueryCallback::Result(callback_send));
+
+        async move {
+            Ok(QueryHandle {
+                guard: guard.await?,
+                result: callback_recv,
+            })
+        }
+    }
+
+    fn start_query_stream(
+        &mut self,
+        query_kind: QueryKind,
+    ) -> impl Future<Output = Result<QueryStream, QueryError>> + 'static {
+        let (update_send, update_recv) = mpsc::unbounded_channel();
+        let guard = self.start_query(query_kind, QueryCallback::Stream(update_send));
+
+        async move {
+            Ok(QueryStream {
+                guard: guard.await?,
+                recv: update_recv,
+            })
+        }
+    }
+
+    /// Starts a query on the service, returning a guard that cancels the query when dropped.
+    fn start_query(
+        &mut self,
+        query_kind: QueryKind,
+        callback: QueryCallback,
+    ) -> impl Future<Output = Result<QueryGuard, QueryError>> + 'static {
         let channel = self.clone_channel();
 
         async move {
             let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
-            let (callback_send, callback_recv) = oneshot::channel();
+            let (id_send, id_recv) = oneshot::channel();
 
-            let query_kind = QueryKind::Predicate {
-                target_node,
-                predicate,
-                target_peer_no,
-            };
+            let event = ServiceRequest::StartQuery(query_kind, callback, id_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            let id = id_recv
+                .await
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?;
+            Ok(QueryGuard {
+                id,
+                channel,
+                finished: false,
+            })
+        }
+    }
+
+    /// Advertises a topic on the network.
+    ///
+    /// The topic is registered with the registrars closest to the topic hash. Registrations are
+    /// renewed as they expire, and tickets issued by busy registrars are held until their wait
+    /// time has elapsed. The topic is advertised until `deregister_topic()` is called.
+    pub fn register_topic(
+        &mut self,
+        topic: Vec<u8>,
+    ) -> impl Future<Output = Result<(), QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            channel
+                .send(ServiceRequest::RegisterTopic(topic))
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))
+        }
+    }
 
-            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+    /// Stops advertising a topic. Existing advertisements remain at the registrars until they
+    /// expire.
+    pub fn deregister_topic(
+        &mut self,
+        topic: Vec<u8>,
+    ) -> impl Future<Output = Result<(), QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            channel
+                .send(ServiceRequest::DeregisterTopic(topic))
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))
+        }
+    }
+
+    /// Searches for nodes advertising a topic.
+    ///
+    /// The registrars closest to the topic hash are sent a `TOPICQUERY` request. The advertisers
+    /// are returned on the stream as responses arrive, each at most once. The stream ends once
+    /// all registrars have responded.
+    pub fn search_topic(
+        &mut self,
+        topic: Vec<u8>,
+    ) -> impl Future<Output = Result<mpsc::Receiver<Enr>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::SearchTopic(topic, callback_send);
             channel
                 .send(event)
                 .await
                 .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
 
-            Ok(callback_recv
+            callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?)
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
         }
     }
 
-    /// Creates an event stream channel which can be polled to receive Discv5 events.
+    /// Creates an event stream which can be polled to receive Discv5 events.
+    ///
+    /// Any number of streams can be open at once. The buffer of the stream holds 100 events if
+    /// discovered peers are reported and 30 otherwise. See
+    /// [`event_stream_with_capacity()`](Self::event_stream_with_capacity) to choose the buffer size.
     pub fn event_stream(
         &mut self,
-    ) -> impl Future<Output = Result<mpsc::Receiver<Discv5Event>, Discv5Error>> + 'static {
+    ) -> impl Future<Output = Result<EventStream, Discv5Error>> + 'static {
+        // the buffer needs to be large to handle many discovered peers if we are reporting them
+        // on the event stream.
+        let capacity = if self.config.report_discovered_peers {
+            100
+        } else {
+            30
+        };
+        self.event_stream_with_capacity(capacity)
+    }
+
+    /// Creates an event stream which buffers up to `capacity` events. Once the buffer is full,
+    /// further events are dropped for this stream until it is polled again, after which it
+    /// reports the number dropped.
+    pub fn event_stream_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> impl Future<Output = Result<EventStream, Discv5Error>> + 'static {
         let channel = self.clone_channel();
 
         async move {
@@ -506,7 +1204,7 @@ impl Discv5 {
 
             let (callback_send, callback_recv) = oneshot::channel();
 
-            let event = ServiceRequest::RequestEventStream(callback_send);
+            let event = ServiceRequest::RequestEventStream(capacity.max(1), callback_send);
             channel
                 .send(event)
                 .await
@@ -518,6 +1216,13 @@ impl Discv5 {
         }
     }
 
+    /// Sends an event caused by the application to the event streams, if the service is running.
+    fn notify(&self, event: Discv5Event) {
+        if let Some(channel) = self.service_channel.as_ref() {
+            let _ = channel.try_send(ServiceRequest::Event(event));
+        }
+    }
+
     /// Internal helper function to send events to the Service.
     fn clone_channel(&self) -> Result<mpsc::Sender<ServiceRequest>, Discv5Error> {
         if let Some(channel) = self.service_channel.as_ref() {
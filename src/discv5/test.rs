@@ -4,8 +4,9 @@ use crate::{kbucket, Discv5, *};
 use enr::{CombinedKey, Enr, EnrBuilder, EnrKey, NodeId};
 use rand_core::{RngCore, SeedableRng};
 use std::{
-    collections::HashMap,
-    net::{IpAddr, Ipv4Addr},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
 };
 
 fn init() {
@@ -19,12 +20,27 @@ fn update_enr(discv5: &mut Discv5, key: &str, value: &[u8]) -> bool {
 }
 
 async fn build_nodes(n: usize, base_port: u16) -> Vec<Discv5> {
+    build_nodes_on(&MemoryNetwork::default(), n, base_port).await
+}
+
+/// Build `n` nodes on a simulated network.
+async fn build_nodes_on(network: &MemoryNetwork, n: usize, base_port: u16) -> Vec<Discv5> {
+    build_nodes_with(network, n, base_port, &Discv5Config::default()).await
+}
+
+/// Build `n` nodes with the given configuration on a simulated network.
+async fn build_nodes_with(
+    network: &MemoryNetwork,
+    n: usize,
+    base_port: u16,
+    config: &Discv5Config,
+) -> Vec<Discv5> {
     let mut nodes = Vec::new();
     let ip: IpAddr = "127.0.0.1".parse().unwrap();
 
     for port in base_port..base_port + n as u16 {
         let enr_key = CombinedKey::generate_secp256k1();
-        let config = Discv5Config::default();
+        let config = config.clone();
 
         let enr = EnrBuilder::new("v4")
             .ip(ip)
@@ -32,9 +48,9 @@ async fn build_nodes(n: usize, base_port: u16) -> Vec<Discv5> {
             .build(&enr_key)
             .unwrap();
         // transport for building a swarm
-        let socket_addr = enr.udp_socket().unwrap();
+        let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
         let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
-        discv5.start(socket_addr).await.unwrap();
+        discv5.start_with_transport(transport).await.unwrap();
         nodes.push(discv5);
     }
     nodes
@@ -42,6 +58,7 @@ async fn build_nodes(n: usize, base_port: u16) -> Vec<Discv5> {
 
 /// Build `n` swarms using passed keypairs.
 async fn build_nodes_from_keypairs(keys: Vec<CombinedKey>, base_port: u16) -> Vec<Discv5> {
+    let network = MemoryNetwork::default();
     let mut nodes = Vec::new();
     let ip: IpAddr = "127.0.0.1".parse().unwrap();
 
@@ -55,9 +72,9 @@ async fn build_nodes_from_keypairs(keys: Vec<CombinedKey>, base_port: u16) -> Ve
             .build(&enr_key)
             .unwrap();
 
-        let socket_addr = enr.udp_socket().unwrap();
+        let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
         let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
-        discv5.start(socket_addr).await.unwrap();
+        discv5.start_with_transport(transport).await.unwrap();
         nodes.push(discv5);
     }
     nodes
@@ -324,6 +341,152 @@ async fn test_findnode_query() {
     assert!(found_nodes.len() <= expected_node_ids.len());
 }
 
+#[tokio::test]
+async fn test_findnode_disjoint_query() {
+    init();
+    // build a collection of 8 nodes, reporting the requests they receive
+    let total_nodes = 8;
+    let config = Discv5ConfigBuilder::new().report_requests().build();
+    let mut nodes = build_nodes_with(&MemoryNetwork::default(), total_nodes, 30100, &config).await;
+    let node_enrs: Vec<Enr<CombinedKey>> = nodes.iter().map(|n| n.local_enr()).collect();
+    let mut querier = nodes.pop().unwrap();
+
+    // every other node knows all the others, so that the paths are offered the same peers
+    for (i, node) in nodes.iter_mut().enumerate() {
+        for (j, enr) in node_enrs.iter().enumerate().take(total_nodes - 1) {
+            if i != j {
+                node.add_enr(enr.clone()).unwrap();
+            }
+        }
+    }
+    let mut events = Vec::new();
+    for node in nodes.iter_mut() {
+        events.push(node.event_stream().await.unwrap());
+    }
+
+    // start a query on the last node, seeding each path with a different node
+    querier.add_enr(node_enrs[0].clone()).unwrap();
+    querier.add_enr(node_enrs[1].clone()).unwrap();
+    let found_nodes = querier
+        .find_node_disjoint(NodeId::random(), 2)
+        .await
+        .unwrap();
+
+    // the results of the paths are merged without duplicates
+    let found_node_ids: HashSet<NodeId> = found_nodes.iter().map(|enr| enr.node_id()).collect();
+    println!("Query found {} peers", found_nodes.len());
+    assert_eq!(found_node_ids.len(), found_nodes.len());
+    assert!(found_nodes.len() < total_nodes);
+
+    // no peer is queried on more than one path
+    let querier_id = querier.local_enr().node_id();
+    let mut queried = 0;
+    for events in events.iter_mut() {
+        let mut requests = 0;
+        while let Ok(Ok(event)) =
+            tokio::time::timeout(Duration::from_millis(10), events.recv()).await
+        {
+            if let Discv5Event::RequestReceived {
+                node_id,
+                kind: RequestKind::FindNode,
+            } = event
+            {
+                if node_id == querier_id {
+                    requests += 1;
+                }
+            }
+        }
+        assert!(requests <= 1, "A peer was queried {} times", requests);
+        queried += requests;
+    }
+    // both paths made progress beyond their seeds
+    assert!(queried > 2);
+}
+
+#[tokio::test]
+async fn test_nodes_response_holds_full_bucket() {
+    init();
+    let network = MemoryNetwork::default();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let bucket_size = 20;
+
+    // the requester is kept out of the buckets that are requested, so that it doesn't take the
+    // place of a node in the response
+    let responder_key = CombinedKey::generate_secp256k1();
+    let responder_id: kbucket::Key<NodeId> = NodeId::from(responder_key.public()).into();
+    let requester_key = loop {
+        let key = CombinedKey::generate_secp256k1();
+        if responder_id.log2_distance(&NodeId::from(key.public()).into()) < Some(254) {
+            break key;
+        }
+    };
+
+    let mut nodes = Vec::new();
+    for (enr_key, port) in [(responder_key, 30200), (requester_key, 30201)] {
+        let enr = EnrBuilder::new("v4")
+            .ip(ip)
+            .udp(port)
+            .build(&enr_key)
+            .unwrap();
+        // a smaller max_nodes_response is raised to the bucket size
+        let config = Discv5ConfigBuilder::new()
+            .kbucket_size(bucket_size)
+            .max_nodes_response(10)
+            .request_timeout(Duration::from_secs(10))
+            .build();
+        assert_eq!(config.max_nodes_response, bucket_size);
+        let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
+        let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
+        discv5.start_with_transport(transport).await.unwrap();
+        nodes.push(discv5);
+    }
+    let mut requester = nodes.pop().unwrap();
+    let mut responder = nodes.pop().unwrap();
+
+    // fill the furthest bucket of the responder with more nodes than the default bucket size
+    // the nodes are bound but silent, so that requests made to them by the responder time out
+    // rather than fail and have the nodes removed before the bucket is returned
+    let mut silent_nodes = Vec::new();
+    let mut port = 30210;
+    while responder.table_entries_id().len() < bucket_size {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = EnrBuilder::new("v4")
+            .ip(ip)
+            .udp(port)
+            .build(&enr_key)
+            .unwrap();
+        port += 1;
+        if responder_id.log2_distance(&enr.node_id().into()) == Some(256) {
+            silent_nodes.push(network.bind(enr.udp_socket().unwrap()).unwrap());
+            responder.add_enr(enr).unwrap();
+        }
+    }
+
+    // a lookup of a target in that bucket is answered with the whole bucket
+    let target = loop {
+        let target = NodeId::random();
+        if responder_id.log2_distance(&target.into()) == Some(256) {
+            break target;
+        }
+    };
+    let mut events = requester.event_stream().await.unwrap();
+    requester.add_enr(responder.local_enr()).unwrap();
+    let _query = tokio::spawn(requester.find_node(target));
+
+    let discovered = tokio::time::timeout(Duration::from_secs(2), async {
+        let mut discovered = HashSet::new();
+        while discovered.len() < bucket_size {
+            if let Ok(Discv5Event::Discovered(enr)) = events.recv().await {
+                discovered.insert(enr.node_id());
+            }
+        }
+        discovered
+    })
+    .await
+    .expect("The whole bucket should be returned");
+    assert_eq!(discovered.len(), bucket_size);
+}
+
 #[tokio::test]
 async fn test_predicate_search() {
     init();
@@ -393,6 +556,134 @@ async fn test_predicate_search() {
     assert!(found_nodes.len() == num_nodes);
 }
 
+#[tokio::test]
+async fn test_predicate_search_stream() {
+    init();
+    let total_nodes = 6;
+    // Seed is chosen such that all nodes are in the 256th bucket of bootstrap
+    let seed = 1652;
+    let mut keypairs = generate_deterministic_keypair(total_nodes + 2, seed);
+    // the target is in the same bucket of bootstrap as the other nodes
+    let target_node_id = NodeId::from(keypairs.pop().unwrap().public());
+    let mut nodes = build_nodes_from_keypairs(keypairs, 14000).await;
+    let mut bootstrap_node = nodes.remove(0);
+
+    let required_attnet_value = vec![1, 0, 0, 0];
+    for (i, swarm) in nodes.iter_mut().enumerate() {
+        swarm.add_enr(bootstrap_node.local_enr()).unwrap();
+        if i % 2 == 0 {
+            update_enr(swarm, "attnets", &required_attnet_value);
+        }
+        bootstrap_node.add_enr(swarm.local_enr()).unwrap();
+    }
+
+    let predicate =
+        move |enr: &Enr<CombinedKey>| enr.get("attnets") == Some(required_attnet_value.as_slice());
+    let mut stream = nodes
+        .last_mut()
+        .unwrap()
+        .find_node_predicate_stream(target_node_id, Box::new(predicate.clone()), total_nodes)
+        .await
+        .unwrap();
+
+    // matching nodes are streamed as they are found, followed by the result of the query
+    let mut found = Vec::new();
+    let mut contacted = 0;
+    let result = loop {
+        match tokio::time::timeout(Duration::from_secs(5), stream.recv())
+            .await
+            .unwrap()
+        {
+            Some(QueryUpdate::Found(enr)) => {
+                assert!(predicate(&enr));
+                assert!(!found.contains(&enr));
+                found.push(enr);
+            }
+            Some(QueryUpdate::Progress(stats)) => {
+                assert!(stats.contacted() >= contacted);
+                contacted = stats.contacted();
+            }
+            Some(QueryUpdate::Finished(result)) => break result,
+            None => panic!("Query stream closed before the query finished"),
+        }
+    };
+    assert!(contacted > 0);
+    assert_eq!(found.len(), total_nodes / 2);
+    assert!(result.iter().all(|enr| found.contains(enr)));
+    assert!(stream.recv().await.is_none());
+
+    // queries keep running after the stream of another query is dropped
+    let stream = nodes
+        .last_mut()
+        .unwrap()
+        .find_node_stream(NodeId::random())
+        .await
+        .unwrap();
+    drop(stream);
+    let found_nodes = nodes
+        .last_mut()
+        .unwrap()
+        .find_node(NodeId::random())
+        .await
+        .unwrap();
+    assert!(found_nodes.len() <= total_nodes);
+}
+
+#[tokio::test]
+async fn test_table_maintenance_discovers_nodes() {
+    init();
+    let total_nodes = 5;
+    // Seed is chosen such that all nodes are in the 256th bucket of bootstrap
+    let seed = 1652;
+    let mut keypairs = generate_deterministic_keypair(total_nodes + 1, seed);
+    let enr_key = keypairs.pop().unwrap();
+    let network = MemoryNetwork::default();
+    let mut nodes = Vec::new();
+    for (i, key) in keypairs.into_iter().enumerate() {
+        let enr = EnrBuilder::new("v4")
+            .ip(Ipv4Addr::LOCALHOST.into())
+            .udp(15000 + i as u16)
+            .build(&key)
+            .unwrap();
+        let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
+        let config = Discv5ConfigBuilder::new()
+            .bucket_refresh_interval(None)
+            .build();
+        let mut discv5 = Discv5::new(enr, key, config).unwrap();
+        discv5.start_with_transport(transport).await.unwrap();
+        nodes.push(discv5);
+    }
+    let mut bootstrap_node = nodes.remove(0);
+    for node in nodes.iter_mut() {
+        node.add_enr(bootstrap_node.local_enr()).unwrap();
+        bootstrap_node.add_enr(node.local_enr()).unwrap();
+    }
+
+    // the new node only knows of the bootstrap node, and finds the others without a query
+    let enr = EnrBuilder::new("v4")
+        .ip(Ipv4Addr::LOCALHOST.into())
+        .udp(15100)
+        .build(&enr_key)
+        .unwrap();
+    let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .table_maintenance_interval(Duration::from_millis(50))
+        .build();
+    let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    discv5.start_with_transport(transport).await.unwrap();
+    discv5.add_enr(bootstrap_node.local_enr()).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while discv5.table_entries_id().len() < total_nodes {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Table maintenance should find all nodes");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(discv5.metrics().table_entries, total_nodes);
+}
+
 // The kbuckets table can have maximum 10 nodes in the same /24 subnet across all buckets
 #[tokio::test]
 async fn test_table_limits() {
@@ -430,6 +721,41 @@ async fn test_table_limits() {
     assert_eq!(discv5.kbuckets.read().iter_ref().count(), table_limit);
 }
 
+// The kbuckets table can have maximum 10 nodes in the same /56 IPv6 subnet across all buckets
+#[tokio::test]
+async fn test_table_limits_ipv6() {
+    // the same seed as `test_table_limits`, so no more than 2 nodes exist in a single bucket.
+    let mut keypairs = generate_deterministic_keypair(12, 9487);
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let enr_key: CombinedKey = keypairs.remove(0);
+    let config = Discv5ConfigBuilder::new().ip_limit().build();
+    let enr = EnrBuilder::new("v4")
+        .ip(ip)
+        .udp(9070)
+        .build(&enr_key)
+        .unwrap();
+
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    let table_limit: usize = 10;
+    // Generate `table_limit + 1` nodes in the same /56 subnet, each in a different /64.
+    let enrs: Vec<Enr<CombinedKey>> = (1..=table_limit + 1)
+        .map(|i| {
+            let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, i as u16, 0, 0, 0, 1));
+            let enr_key: CombinedKey = keypairs.remove(0);
+            EnrBuilder::new("v4")
+                .ip(ip)
+                .udp6(9070 + i as u16)
+                .build(&enr_key)
+                .unwrap()
+        })
+        .collect();
+    for enr in enrs {
+        discv5.add_enr(enr.clone()).unwrap();
+    }
+    // Number of entries should be `table_limit`, i.e one node got restricted
+    assert_eq!(discv5.kbuckets.read().iter_ref().count(), table_limit);
+}
+
 // Each bucket can have maximum 2 nodes in the same /24 subnet
 #[tokio::test]
 async fn test_bucket_limits() {
@@ -482,3 +808,445 @@ async fn test_bucket_limits() {
     // Number of entries should be equal to `bucket_limit`.
     assert_eq!(discv5.kbuckets.read().iter_ref().count(), bucket_limit);
 }
+
+#[tokio::test]
+async fn test_table_info() {
+    let mut keypairs = generate_deterministic_keypair(5, 9487);
+    let enr_key: CombinedKey = keypairs.remove(0);
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(9080)
+        .build(&enr_key)
+        .unwrap();
+    let local_key = kbucket::Key::from(enr.node_id());
+    let mut discv5 = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+
+    let ips = ["192.168.1.1", "192.168.1.1", "192.168.1.2", "10.0.0.1"];
+    let mut node_ids = Vec::new();
+    for (i, (ip, enr_key)) in ips.iter().zip(keypairs.iter()).enumerate() {
+        let enr = EnrBuilder::new("v4")
+            .ip(ip.parse().unwrap())
+            .udp(9081 + i as u16)
+            .build(enr_key)
+            .unwrap();
+        node_ids.push(enr.node_id());
+        discv5.add_enr(enr).unwrap();
+    }
+
+    let info = discv5.table_info();
+    // every node is listed in the bucket at its distance
+    let mut listed = Vec::new();
+    for bucket in info.buckets.iter() {
+        assert!(bucket.last_updated.is_some());
+        assert!(bucket.pending.is_none());
+        for entry in bucket.entries.iter() {
+            let distance = kbucket::Key::from(entry.enr.node_id()).log2_distance(&local_key);
+            assert_eq!(distance, Some(bucket.distance));
+            assert_eq!(entry.status, NodeStatus::Disconnected);
+            listed.push(entry.enr.node_id());
+        }
+    }
+    listed.sort_by_key(|node_id| node_id.raw());
+    node_ids.sort_by_key(|node_id| node_id.raw());
+    assert_eq!(listed, node_ids);
+
+    // nodes sharing an IP address and subnet are counted together
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+    assert_eq!(
+        info.ips,
+        vec![
+            (ip("192.168.1.1"), 2),
+            (ip("10.0.0.1"), 1),
+            (ip("192.168.1.2"), 1)
+        ]
+    );
+    let net = |net: &str| net.parse::<IpNet>().unwrap();
+    assert_eq!(
+        info.subnets,
+        vec![(net("192.168.1.0/24"), 3), (net("10.0.0.0/24"), 1)]
+    );
+}
+
+#[tokio::test]
+async fn test_topic_registration_and_search() {
+    init();
+    let mut nodes = build_nodes(4, 13000).await;
+    let mut bootstrap_node = nodes.remove(0);
+    for node in nodes.iter_mut() {
+        node.add_enr(bootstrap_node.local_enr()).unwrap();
+        bootstrap_node.add_enr(node.local_enr()).unwrap();
+    }
+    let advertiser_enr = nodes[0].local_enr();
+    nodes[0].register_topic(b"service".to_vec()).await.unwrap();
+
+    // wait for the registration to complete
+    let mut found = Vec::new();
+    for _ in 0..20 {
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        let mut stream = nodes[1].search_topic(b"service".to_vec()).await.unwrap();
+        while let Some(enr) = stream.recv().await {
+            found.push(enr);
+        }
+        if !found.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(found, vec![advertiser_enr]);
+
+    // no advertisers for unregistered topics
+    let mut stream = nodes[1].search_topic(b"other".to_vec()).await.unwrap();
+    assert!(stream.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_talk_protocol_registry() {
+    init();
+    let mut nodes = build_nodes(2, 13100).await;
+    let mut requester = nodes.pop().unwrap();
+    let mut responder = nodes.pop().unwrap();
+
+    let mut talk_requests = responder
+        .register_talk_protocol(b"portal".to_vec())
+        .await
+        .unwrap();
+    let requester_id = requester.local_enr().node_id();
+    tokio::spawn(async move {
+        while let Some(request) = talk_requests.recv().await {
+            assert_eq!(request.node_id(), &requester_id);
+            let mut response = request.body().to_vec();
+            response.reverse();
+            request.respond(response).unwrap();
+        }
+    });
+
+    let response = requester
+        .talk_req(responder.local_enr(), b"portal".to_vec(), vec![1, 2, 3])
+        .await
+        .unwrap();
+    assert_eq!(response, vec![3, 2, 1]);
+
+    // unregistered protocols receive an empty response
+    let response = requester
+        .talk_req(responder.local_enr(), b"other".to_vec(), vec![1, 2, 3])
+        .await
+        .unwrap();
+    assert!(response.is_empty());
+}
+
+/// Receives the next event of a stream, failing if none arrives within a second.
+async fn next_event(events: &mut EventStream) -> Discv5Event {
+    tokio::time::timeout(Duration::from_secs(1), events.recv())
+        .await
+        .expect("Timed out")
+        .expect("Stream error")
+}
+
+#[tokio::test]
+async fn test_event_streams_report_sessions_requests_and_updates() {
+    init();
+    let config = Discv5ConfigBuilder::new().report_requests().build();
+    let mut nodes = build_nodes_with(&MemoryNetwork::default(), 2, 13110, &config).await;
+    let mut requester = nodes.pop().unwrap();
+    let mut responder = nodes.pop().unwrap();
+    let mut events = responder.event_stream().await.unwrap();
+    // every stream receives every event
+    let mut other_events = responder.event_stream().await.unwrap();
+
+    requester
+        .talk_req(responder.local_enr(), b"portal".to_vec(), vec![1])
+        .await
+        .unwrap();
+
+    let requester_id = requester.local_enr().node_id();
+    let mut seen = (false, false, false);
+    while seen != (true, true, true) {
+        match next_event(&mut events).await {
+            Discv5Event::SessionEstablished(enr) => {
+                assert_eq!(enr.node_id(), requester_id);
+                seen.0 = true;
+            }
+            Discv5Event::RequestReceived { node_id, kind } => {
+                assert_eq!(node_id, requester_id);
+                assert_eq!(kind, RequestKind::Talk);
+                seen.1 = true;
+            }
+            Discv5Event::TalkRequestReceived { node_id, protocol } => {
+                assert_eq!(node_id, requester_id);
+                assert_eq!(protocol, b"portal".to_vec());
+                seen.2 = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(matches!(
+        next_event(&mut other_events).await,
+        Discv5Event::SessionEstablished(_)
+    ));
+
+    // changes made by the application are reported
+    assert!(responder.remove_node(&requester_id));
+    assert!(update_enr(&mut responder, "custom", b"value"));
+    let seq = responder.local_enr().seq();
+    let mut seen = (false, false);
+    while seen != (true, true) {
+        match next_event(&mut events).await {
+            Discv5Event::NodeRemoved {
+                node_id,
+                replaced_by,
+            } => {
+                assert_eq!(node_id, requester_id);
+                assert_eq!(replaced_by, None);
+                seen.0 = true;
+            }
+            Discv5Event::LocalEnrUpdated(enr) => {
+                assert_eq!(enr.seq(), seq);
+                seen.1 = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_instances_have_independent_ban_lists_and_metrics() {
+    init();
+    let mut nodes = build_nodes(3, 13200).await;
+    let mut requester = nodes.pop().unwrap();
+    let responder = nodes.pop().unwrap();
+    let mut bystander = nodes.pop().unwrap();
+
+    // banning a node on one instance must not affect any other instance in the process
+    bystander.ban_node(&requester.local_enr().node_id(), None);
+
+    let response = requester
+        .talk_req(responder.local_enr(), b"portal".to_vec(), vec![1, 2, 3])
+        .await
+        .unwrap();
+    assert!(response.is_empty());
+
+    assert_eq!(requester.metrics().active_sessions, 1);
+    assert_eq!(responder.metrics().active_sessions, 1);
+    assert_eq!(bystander.metrics().active_sessions, 0);
+}
+
+#[test]
+fn test_bans_are_listed_until_expired() {
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+    let mut discv5 = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+
+    let permanent = NodeId::random();
+    let temporary = NodeId::random();
+    let ip: std::net::IpAddr = "10.0.0.1".parse().unwrap();
+    discv5.ban_node(&permanent, None);
+    discv5.ban_node(&temporary, Some(Duration::from_millis(50)));
+    discv5.ban_ip(ip, Some(Duration::from_secs(60)));
+
+    let banned_nodes = discv5.banned_nodes();
+    assert_eq!(banned_nodes.len(), 2);
+    assert!(banned_nodes
+        .iter()
+        .all(|(_, ban)| ban.reason == BanReason::Manual));
+    let banned_ips = discv5.banned_ips();
+    assert_eq!(banned_ips[0].0, ip);
+    assert!(banned_ips[0].1.remaining().unwrap() > Duration::from_secs(50));
+
+    std::thread::sleep(Duration::from_millis(100));
+    let banned_nodes = discv5.banned_nodes();
+    assert_eq!(banned_nodes.len(), 1);
+    assert_eq!(banned_nodes[0].0, permanent);
+    assert_eq!(banned_nodes[0].1.remaining(), None);
+}
+
+#[tokio::test]
+async fn test_routing_table_persistence() {
+    init();
+    let network = MemoryNetwork::default();
+    let mut nodes = build_nodes_on(&network, 3, 13300).await;
+    let mut node = nodes.remove(0);
+    for peer in nodes.iter() {
+        node.add_enr(peer.local_enr()).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("discv5-table-{}", std::process::id()));
+    assert_eq!(node.save_routing_table(&path).unwrap(), 2);
+    node.shutdown();
+
+    // restart the node from the persisted table
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(13303)
+        .build(&enr_key)
+        .unwrap();
+    let transport = network.bind(enr.udp_socket().unwrap()).unwrap();
+    let mut restarted = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+    assert_eq!(restarted.load_routing_table(&path).unwrap(), 2);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restarted.connected_peers(), 0);
+
+    // the restored entries are re-validated once the service starts
+    restarted.start_with_transport(transport).await.unwrap();
+    assert!(matches!(
+        restarted.load_routing_table("unused"),
+        Err(Discv5Error::ServiceAlreadyStarted)
+    ));
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert_eq!(restarted.connected_peers(), 2);
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    init();
+    let mut nodes = build_nodes(2, 13400).await;
+    let mut requester = nodes.pop().unwrap();
+    let responder = nodes.pop().unwrap();
+    requester.add_enr(responder.local_enr()).unwrap();
+
+    requester
+        .talk_req(responder.local_enr(), b"portal".to_vec(), vec![])
+        .await
+        .unwrap();
+
+    let gathered = |node: &Discv5| {
+        let mut buffer = Vec::new();
+        use prometheus::Encoder;
+        prometheus::TextEncoder::new()
+            .encode(&node.prometheus_registry().gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    };
+
+    let requester_metrics = gathered(&requester);
+    assert!(requester_metrics.contains("discv5_messages_sent_total{message_type=\"talkreq\"} 1"));
+    assert!(
+        requester_metrics.contains("discv5_messages_received_total{message_type=\"talkresp\"} 1")
+    );
+    assert!(requester_metrics.contains("discv5_handshake_successes_total 1"));
+    assert!(requester_metrics.contains("discv5_routing_table_bucket_size{distance="));
+
+    let responder_metrics = gathered(&responder);
+    assert!(
+        responder_metrics.contains("discv5_messages_received_total{message_type=\"talkreq\"} 1")
+    );
+    assert!(responder_metrics.contains("discv5_messages_sent_total{message_type=\"talkresp\"} 1"));
+}
+
+#[tokio::test]
+async fn test_dual_stack() {
+    init();
+    // the sockets are bound to ports assigned by the OS, read back from the local ENRs
+    let ipv4: std::net::SocketAddrV4 = "127.0.0.1:0".parse().unwrap();
+    let ipv6: std::net::SocketAddrV6 = "[::1]:0".parse().unwrap();
+
+    // the dual-stack node only advertises its IPv4 address up front
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip((*ipv4.ip()).into())
+        .build(&enr_key)
+        .unwrap();
+    let mut dual_stack = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+    dual_stack
+        .start(ListenConfig::DualStack { ipv4, ipv6 })
+        .await
+        .unwrap();
+    let dual_stack_enr = dual_stack.local_enr();
+    let ipv4_port = dual_stack_enr.udp().unwrap();
+    assert_ne!(ipv4_port, 0);
+    assert_eq!(
+        dual_stack_enr.udp_socket(),
+        Some(SocketAddr::new((*ipv4.ip()).into(), ipv4_port))
+    );
+    let ipv6_port = dual_stack_enr.udp6().unwrap();
+    assert_ne!(ipv6_port, 0);
+    assert_eq!(
+        dual_stack_enr.udp6_socket(),
+        Some(SocketAddr::new((*ipv6.ip()).into(), ipv6_port))
+    );
+
+    // an IPv6-only node
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+    let mut ipv6_only = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+    ipv6_only.start(SocketAddr::from(ipv6)).await.unwrap();
+
+    // an IPv4-only node
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+    let mut ipv4_only = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+    ipv4_only.start(SocketAddr::from(ipv4)).await.unwrap();
+
+    for node in [&mut ipv6_only, &mut ipv4_only].iter_mut() {
+        let response = node
+            .talk_req(dual_stack_enr.clone(), b"portal".to_vec(), vec![1])
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+    }
+
+    // the dual-stack node reaches each peer on the family it advertises
+    for peer in [ipv6_only.local_enr(), ipv4_only.local_enr()].iter() {
+        let response = dual_stack
+            .talk_req(peer.clone(), b"portal".to_vec(), vec![2])
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_nat_hole_punching() {
+    init();
+    let network = MemoryNetwork::default();
+    let mut nodes = Vec::new();
+    // the initiator and relay are publicly reachable, the target is behind a NAT
+    let addresses = [
+        ("10.0.0.1:9000", "10.0.0.1:9000"),
+        ("10.0.0.2:9000", "10.0.0.2:9000"),
+        ("192.168.0.3:9000", "10.0.0.3:9000"),
+    ];
+    for (internal, external) in addresses.iter() {
+        let internal: SocketAddr = internal.parse().unwrap();
+        let external: SocketAddr = external.parse().unwrap();
+        let enr_key = CombinedKey::generate_secp256k1();
+        let mut enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+        enr.set_udp_socket(external, &enr_key).unwrap();
+        // a single attempt, so that an unanswered request fails after one timeout
+        let config = Discv5ConfigBuilder::new()
+            .request_timeout(Duration::from_secs(1))
+            .request_retries(1)
+            .build();
+        let transport = if internal == external {
+            network.bind(internal).unwrap()
+        } else {
+            network.bind_behind_nat(internal, external).unwrap()
+        };
+        let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
+        discv5.start_with_transport(transport).await.unwrap();
+        nodes.push(discv5);
+    }
+    let mut target = nodes.pop().unwrap();
+    let relay = nodes.pop().unwrap();
+    let mut initiator = nodes.pop().unwrap();
+
+    // the target keeps a session with the relay, which keeps its NAT open for the relay
+    target
+        .talk_req(relay.local_enr(), b"portal".to_vec(), vec![])
+        .await
+        .unwrap();
+
+    // the initiator learns of the target from the relay
+    initiator.add_enr(relay.local_enr()).unwrap();
+    initiator
+        .find_node(target.local_enr().node_id())
+        .await
+        .unwrap();
+
+    // the first packet is dropped by the NAT, but the relay has the target open a path to the
+    // initiator
+    let response = initiator
+        .talk_req(target.local_enr(), b"portal".to_vec(), vec![])
+        .await
+        .unwrap();
+    assert!(response.is_empty());
+}
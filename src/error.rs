@@ -46,6 +46,16 @@ impl From<std::io::Error> for Discv5Error {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+/// An error returned when receiving from an event stream.
+pub enum EventStreamError {
+    /// The buffer of the stream overflowed, so this many events were dropped before the next
+    /// one received.
+    Lagged(u64),
+    /// The discv5 service has shut down and no further events will be produced.
+    Closed,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 /// Types of packet errors.
 pub enum PacketError {
@@ -129,7 +129,7 @@ where
     /// Retains only the elements specified by the predicate.
     ///
     /// In other words, remove all pairs `(k, v)` such that `f(&k,&mut v)` returns false.
-    pub fn _retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
         let expiration = &mut self.expirations;
         self.entries.retain(|key, entry| {
             let result = f(key, &mut entry.value);
@@ -14,6 +14,11 @@
 //! establishing/established session is dropped. Once the IP is updated
 //! to match the source, the [`Session`] is promoted to an established state and reported back.
 //!
+//! Nodes behind a NAT drop packets from peers they have not contacted themselves. If a request to
+//! a node goes unanswered, the node that told us about it is sent a RELAYINIT notification. It
+//! relays a RELAYMSG notification to the target over its own session, and the target responds by
+//! sending a WHOAREYOU packet to us, which opens its NAT and completes the original handshake.
+//!
 //! # Usage
 //!
 //! Interacting with a handler is done via channels. A Handler is spawned using the [`spawn()`]
@@ -25,10 +30,12 @@
 use crate::{
     config::Discv5Config,
     error::{Discv5Error, RequestError},
+    ipmode::IpMode,
     packet::{ChallengeData, IdNonce, MessageNonce, Packet, PacketKind},
-    rpc::{Message, Request, RequestBody, RequestId, Response, ResponseBody},
+    permit_ban::BanReason,
+    rpc::{Message, Notification, Request, RequestBody, RequestId, Response, ResponseBody},
     socket,
-    socket::Socket,
+    socket::{ListenConfig, RateLimiter, Socket, Transports},
     Enr,
 };
 use enr::{CombinedKey, NodeId};
@@ -36,27 +43,50 @@ use futures::prelude::*;
 use lru_time_cache::LruCache;
 use parking_lot::RwLock;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
     default::Default,
     net::SocketAddr,
     sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, trace, warn};
 
 mod crypto;
-mod hashmap_delay;
+pub(crate) mod hashmap_delay;
 mod session;
 mod tests;
 
 pub use crate::node_info::{NodeAddress, NodeContact};
 
-use crate::metrics::METRICS;
+use crate::{
+    metrics::InternalMetrics,
+    reputation::{Behaviour, Reputation},
+    PermitBanList,
+};
 
 use hashmap_delay::HashMapDelay;
 use session::Session;
 
+/// The maximum number of peers for which we remember the node that told us about them.
+const RELAY_CACHE_CAPACITY: usize = 1000;
+
+/// The RELAYMSG notifications acted upon per second from each relay.
+const RELAY_MSG_RATE: f64 = 0.2;
+
+/// The window over which a relay may send a burst of RELAYMSG notifications.
+const RELAY_MSG_BURST: Duration = Duration::from_secs(10);
+
+/// The RELAYINIT notifications relayed per second for each initiator.
+const RELAY_INIT_RATE: f64 = 0.2;
+
+/// The window over which an initiator may send a burst of RELAYINIT notifications.
+const RELAY_INIT_BURST: Duration = Duration::from_secs(10);
+
+/// The window over which a node may send a burst of requests before it is penalised.
+const REQUEST_BURST: Duration = Duration::from_secs(1);
+
 /// Events sent to the handler to be executed.
 #[derive(Debug, Clone, PartialEq)]
 #[allow(clippy::large_enum_variant)]
@@ -111,6 +141,12 @@ pub enum HandlerResponse {
     ///
     /// This returns the request ID and an error indicating why the request failed.
     RequestFailed(RequestId, RequestError),
+
+    /// An established session has expired or been dropped.
+    SessionExpired(NodeAddress),
+
+    /// A node has been banned by the packet filter or for failing handshakes.
+    Banned(NodeId, BanReason),
 }
 
 /// A reference for the application layer to send back when the handler requests any known
@@ -139,6 +175,8 @@ pub(crate) struct RequestCall {
     /// If we receive a Nodes Response with a total greater than 1. This keeps track of the
     /// remaining responses expected.
     remaining_responses: Option<u64>,
+    /// Whether a relay has been asked to have the node contact us, in case it is behind a NAT.
+    relayed: bool,
 }
 
 impl RequestCall {
@@ -150,6 +188,7 @@ impl RequestCall {
             handshake_sent: false,
             retries: 1,
             remaining_responses: None,
+            relayed: false,
         }
     }
 
@@ -184,16 +223,42 @@ pub struct Handler {
     active_challenges: LruCache<NodeAddress, Challenge>,
     /// Established sessions with peers.
     sessions: LruCache<NodeAddress, Session>,
+    /// The first node that sent us each peer's ENR in a NODES response to one of our FINDNODE
+    /// requests. If a peer never answers our first packet, it may be behind a NAT and this node
+    /// is asked to relay a hole punching notification to it. Keyed by the raw node id of the peer.
+    relays: LruCache<[u8; 32], NodeAddress>,
+    /// Limits the RELAYMSG notifications acted upon from each relay, as each makes us send a
+    /// packet to an address we have not verified ourselves.
+    relay_msg_limiter: RateLimiter<NodeId>,
+    /// Limits the RELAYINIT notifications relayed for each initiator, as each makes us send a
+    /// packet to another node.
+    relay_init_limiter: RateLimiter<NodeId>,
+    /// Limits the requests each node sends over its session, if the packet filter limits requests
+    /// per node. Unlike the filter, which sees the unauthenticated node id of the packet header,
+    /// this counts only requests whose sender is authenticated by the session, so nodes exceeding
+    /// it can be penalised.
+    request_limiter: Option<RateLimiter<NodeId>>,
     /// The channel that receives requests from the application layer.
     inbound_channel: mpsc::UnboundedReceiver<HandlerRequest>,
     /// The channel to send responses to the application layer.
     outbound_channel: mpsc::Sender<HandlerResponse>,
-    /// The listening socket to filter out any attempted requests to self.
-    listen_socket: SocketAddr,
+    /// The listening sockets to filter out any attempted requests to self.
+    listen_config: ListenConfig,
+    /// The address families peers can be contacted on.
+    ip_mode: IpMode,
     /// The discovery v5 UDP socket tasks.
     socket: Socket,
     /// Exit channel to shutdown the handler.
     exit: oneshot::Receiver<()>,
+    /// The metrics of the server.
+    metrics: Arc<InternalMetrics>,
+    /// The reputation of peers, lowered when handshakes fail or requests exceed their limit.
+    reputation: Arc<Reputation>,
+    /// The nodes with which a session has been reported as established, used to report the
+    /// sessions once they expire.
+    established_sessions: HashSet<NodeAddress>,
+    /// Bans issued by the packet filter, to be reported to the application.
+    ban_events: mpsc::UnboundedReceiver<(NodeId, BanReason)>,
 }
 
 type HandlerReturn = (
@@ -202,17 +267,21 @@ type HandlerReturn = (
     mpsc::Receiver<HandlerResponse>,
 );
 impl Handler {
-    /// A new Session service which instantiates the UDP socket send/recv tasks.
+    /// A new Session service which instantiates the send/recv tasks over the given transports.
     pub(crate) async fn spawn(
         enr: Arc<RwLock<Enr>>,
         key: Arc<RwLock<CombinedKey>>,
-        listen_socket: SocketAddr,
+        transports: Transports,
         config: Discv5Config,
+        permit_ban_list: Arc<RwLock<PermitBanList>>,
+        metrics: Arc<InternalMetrics>,
+        reputation: Arc<Reputation>,
     ) -> Result<HandlerReturn, std::io::Error> {
         let (exit_sender, exit) = oneshot::channel();
         // create the channels to send/receive messages from the application
         let (inbound_send, inbound_channel) = mpsc::unbounded_channel();
         let (outbound_channel, outbound_recv) = mpsc::channel(50);
+        let (ban_events_send, ban_events) = mpsc::unbounded_channel();
 
         // Creates a SocketConfig to pass to the underlying UDP socket tasks.
 
@@ -225,24 +294,31 @@ impl Handler {
         // enable the packet filter if required
         let mut filter_config = config.filter_config.clone();
         filter_config.enabled = config.enable_packet_filter;
+        let request_limiter = filter_config
+            .max_requests_per_node_per_second
+            .filter(|_| filter_config.enabled)
+            .map(|rate| RateLimiter::new(rate, REQUEST_BURST));
 
         let socket_config = socket::SocketConfig {
             executor: config.executor.clone().expect("Executor must exist"),
-            socket_addr: listen_socket,
             filter_config,
+            permit_ban_list,
+            ban_duration: config.ban_duration,
+            metrics: metrics.clone(),
+            ban_events: ban_events_send,
             local_node_id: node_id,
             expected_responses: filter_expected_responses.clone(),
         };
 
-        // Attempt to bind to the socket before spinning up the send/recv tasks.
-        let socket = socket::Socket::new_socket(&socket_config.socket_addr).await?;
+        // The listening addresses, used to filter requests to ourselves.
+        let listen_config = transports.listen_config()?;
 
         config
             .executor
             .clone()
             .expect("Executor must be present")
             .spawn(Box::pin(async move {
-                let socket = match socket::Socket::new(socket, socket_config) {
+                let socket = match socket::Socket::new(transports, socket_config) {
                     Ok(v) => v,
                     Err(e) => {
                         error!("Could not bind UDP socket. {}", e);
@@ -263,12 +339,21 @@ impl Handler {
                         config.session_timeout,
                         config.session_cache_capacity,
                     ),
+                    relays: LruCache::with_capacity(RELAY_CACHE_CAPACITY),
+                    relay_msg_limiter: RateLimiter::new(RELAY_MSG_RATE, RELAY_MSG_BURST),
+                    relay_init_limiter: RateLimiter::new(RELAY_INIT_RATE, RELAY_INIT_BURST),
+                    request_limiter,
                     active_challenges: LruCache::with_expiry_duration(config.request_timeout * 2),
                     inbound_channel,
                     outbound_channel,
-                    listen_socket,
+                    listen_config,
+                    ip_mode: listen_config.ip_mode(),
                     socket,
                     exit,
+                    metrics,
+                    reputation,
+                    established_sessions: HashSet::new(),
+                    ban_events,
                 };
                 debug!("Handler Starting");
                 handler.start().await;
@@ -300,6 +385,9 @@ impl Handler {
                 Some(Ok((node_address, pending_request))) = self.active_requests.next() => {
                     self.handle_request_timeout(node_address, pending_request).await;
                 }
+                Some((node_id, reason)) = self.ban_events.recv() => {
+                    let _ = self.outbound_channel.send(HandlerResponse::Banned(node_id, reason)).await;
+                }
                 _ = &mut self.exit => {
                     return;
                 }
@@ -387,12 +475,22 @@ impl Handler {
         mut request_call: RequestCall,
     ) {
         if request_call.retries >= self.request_retries {
+            // The node may be behind a NAT, dropping our packets until it contacts us. Give it
+            // another timeout period to do so, if a relay is able to ask it to.
+            if self
+                .request_hole_punch(&node_address, &mut request_call)
+                .await
+            {
+                self.active_requests.insert(node_address, request_call);
+                return;
+            }
             trace!("Request timed out with {}", node_address);
             // Remove the request from the awaiting packet_filter
             // Remove the associated nonce mapping.
             self.active_requests_nonce_mapping
                 .remove(request_call.packet.message_nonce());
             self.remove_expected_response(node_address.socket_addr);
+            self.metrics.request_timed_out();
             self.fail_request(request_call, RequestError::Timeout).await;
         } else {
             // increment the request retry count and restart the timeout
@@ -408,6 +506,40 @@ impl Handler {
         }
     }
 
+    /// Asks the node that told us about the destination of an unanswered request to relay a
+    /// RELAYINIT notification to it, so that it sends us a WHOAREYOU packet and opens its NAT
+    /// mapping for our address. Returns whether the notification was sent.
+    async fn request_hole_punch(
+        &mut self,
+        node_address: &NodeAddress,
+        request_call: &mut RequestCall,
+    ) -> bool {
+        self.expire_sessions(node_address).await;
+        // Only a node that has never answered can be hidden behind a NAT.
+        if request_call.relayed
+            || request_call.handshake_sent
+            || !request_call.contact.is_enr()
+            || self.sessions.get(node_address).is_some()
+        {
+            return false;
+        }
+        let relay = match self.relays.remove(&node_address.node_id.raw()) {
+            Some(relay) => relay,
+            None => return false,
+        };
+        let notification = Notification::RelayInit {
+            initiator_enr: self.enr.read().clone(),
+            target: node_address.node_id,
+            nonce: *request_call.packet.message_nonce(),
+        };
+        debug!(
+            "Requesting a hole punch to {} via relay {}",
+            node_address, relay
+        );
+        request_call.relayed = self.send_notification(relay, notification).await;
+        request_call.relayed
+    }
+
     /// Sends a `Request` to a node.
     async fn send_request(
         &mut self,
@@ -415,10 +547,10 @@ impl Handler {
         request: Request,
     ) -> Result<(), RequestError> {
         let node_address = contact
-            .node_address()
+            .node_address(self.ip_mode)
             .map_err(|e| RequestError::InvalidEnr(e.into()))?;
 
-        if node_address.socket_addr == self.listen_socket {
+        if self.listen_config.contains(&node_address.socket_addr) {
             debug!("Filtered request to self");
             return Err(RequestError::SelfRequest);
         }
@@ -434,6 +566,7 @@ impl Handler {
         }
 
         let packet = {
+            self.expire_sessions(&node_address).await;
             if let Some(session) = self.sessions.get_mut(&node_address) {
                 // Encrypt the message and send
                 session
@@ -449,6 +582,7 @@ impl Handler {
             }
         };
 
+        self.metrics.message_sent(request.msg_type());
         let call = RequestCall::new(contact, packet.clone(), request);
         // let the filter know we are expecting a response
         self.add_expected_response(node_address.socket_addr);
@@ -464,7 +598,9 @@ impl Handler {
     /// Sends an RPC Response.
     async fn send_response(&mut self, node_address: NodeAddress, response: Response) {
         // Check for an established session
+        self.expire_sessions(&node_address).await;
         if let Some(session) = self.sessions.get_mut(&node_address) {
+            let msg_type = response.msg_type();
             // Encrypt the message and send
             let packet = match session.encrypt_message(self.node_id, &response.encode()) {
                 Ok(packet) => packet,
@@ -474,6 +610,7 @@ impl Handler {
                 }
             };
             self.send(node_address, packet).await;
+            self.metrics.message_sent(msg_type);
         } else {
             // Either the session is being established or has expired. We simply drop the
             // response in this case.
@@ -484,6 +621,37 @@ impl Handler {
         }
     }
 
+    /// Sends a notification over an established session. Returns whether the notification was
+    /// sent.
+    async fn send_notification(
+        &mut self,
+        node_address: NodeAddress,
+        notification: Notification,
+    ) -> bool {
+        self.expire_sessions(&node_address).await;
+        let session = match self.sessions.get_mut(&node_address) {
+            Some(session) => session,
+            None => {
+                debug!(
+                    "Session is not established. Dropping notification {} for node: {}",
+                    notification, node_address.node_id
+                );
+                return false;
+            }
+        };
+        let msg_type = notification.msg_type();
+        let packet = match session.encrypt_message(self.node_id, &notification.encode()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Could not encrypt notification: {:?}", e);
+                return false;
+            }
+        };
+        self.send(node_address, packet).await;
+        self.metrics.message_sent(msg_type);
+        true
+    }
+
     /// This is called in response to a `HandlerResponse::WhoAreYou` event. The applications finds the
     /// highest known ENR for a node then we respond to the node with a WHOAREYOU packet.
     async fn send_challenge(&mut self, wru_ref: WhoAreYouRef, remote_enr: Option<Enr>) {
@@ -496,6 +664,7 @@ impl Handler {
         }
 
         // Ignore this request if the session is already established
+        self.expire_sessions(&node_address).await;
         if self.sessions.get(&node_address).is_some() {
             trace!(
                 "Session already established. WHOAREYOU not sent to {}",
@@ -592,6 +761,7 @@ impl Handler {
                 "Authentication response already sent. Dropping session. Node: {}",
                 request_call.contact
             );
+            self.metrics.handshake_failed("duplicate_challenge");
             self.fail_request(request_call, RequestError::InvalidRemotePacket)
                 .await;
             return;
@@ -618,6 +788,7 @@ impl Handler {
             Ok(v) => v,
             Err(e) => {
                 error!("Could not generate a session. Error: {:?}", e);
+                self.metrics.handshake_failed("session_generation");
                 self.fail_request(request_call, RequestError::InvalidRemotePacket)
                     .await;
                 return;
@@ -640,7 +811,7 @@ impl Handler {
         // must not panic.
         let node_address = request_call
             .contact
-            .node_address()
+            .node_address(self.ip_mode)
             .expect("All sent requests must have a node address");
         match request_call.contact.clone() {
             NodeContact::Enr(enr) => {
@@ -655,10 +826,7 @@ impl Handler {
                 self.send(node_address.clone(), auth_packet).await;
 
                 // Notify the application that the session has been established
-                self.outbound_channel
-                    .send(HandlerResponse::Established(*enr))
-                    .await
-                    .unwrap_or_else(|e| warn!("Error with sending channel: {}", e));
+                self.session_established(node_address.clone(), *enr).await;
             }
             NodeContact::Raw { .. } => {
                 // Don't know the ENR. Establish the session, but request an ENR also
@@ -669,7 +837,7 @@ impl Handler {
                     "Sending Authentication response to node: {}",
                     request_call
                         .contact
-                        .node_address()
+                        .node_address(self.ip_mode)
                         .expect("Sanitized contact")
                 );
                 request_call.packet = auth_packet.clone();
@@ -688,16 +856,20 @@ impl Handler {
                 let _ = self.send_request(contact, request).await;
             }
         }
-        self.new_session(node_address, session);
+        self.new_session(node_address, session).await;
     }
 
     /// Verifies a Node ENR to it's observed address. If it fails, any associated session is also
     /// considered failed. If it succeeds, we notify the application.
     fn verify_enr(&self, enr: &Enr, node_address: &NodeAddress) -> bool {
         // If the ENR does not match the observed IP addresses, we consider the Session
-        // failed.
+        // failed. Only the socket of the observed address family is compared.
+        let enr_socket = match node_address.socket_addr {
+            SocketAddr::V4(_) => enr.udp_socket(),
+            SocketAddr::V6(_) => enr.udp6_socket(),
+        };
         enr.node_id() == node_address.node_id
-            && (enr.udp_socket().is_none() || enr.udp_socket() == Some(node_address.socket_addr))
+            && (enr_socket.is_none() || enr_socket == Some(node_address.socket_addr))
     }
 
     /// Handle a message that contains an authentication header.
@@ -736,11 +908,8 @@ impl Handler {
                     if self.verify_enr(&enr, &node_address) {
                         // Session is valid
                         // Notify the application
-                        let _ = self
-                            .outbound_channel
-                            .send(HandlerResponse::Established(enr))
-                            .await;
-                        self.new_session(node_address.clone(), session);
+                        self.session_established(node_address.clone(), enr).await;
+                        self.new_session(node_address.clone(), session).await;
                         self.handle_message(
                             node_address,
                             message_nonce,
@@ -751,10 +920,18 @@ impl Handler {
                     } else {
                         // IP's or NodeAddress don't match. Drop the session.
                         warn!(
-                            "Session has invalid ENR. Enr socket: {:?}, {}",
+                            "Session has invalid ENR. Enr sockets: {:?}, {:?}, {}",
                             enr.udp_socket(),
+                            enr.udp6_socket(),
                             node_address
                         );
+                        self.metrics.handshake_failed("invalid_enr");
+                        // The challenge was signed with the key of the ENR, so only penalise the
+                        // node if the ENR is its own. Anyone can claim a node id and attach an
+                        // ENR of their own.
+                        if enr.node_id() == node_address.node_id {
+                            self.report_handshake_failure(&node_address.node_id).await;
+                        }
                         self.fail_session(&node_address, RequestError::InvalidRemoteEnr)
                             .await;
                     }
@@ -764,6 +941,7 @@ impl Handler {
                         "Authentication header contained invalid signature. Ignoring packet from: {}",
                         node_address
                     );
+                    self.metrics.handshake_failed("invalid_signature");
                     // insert back the challenge
                     self.active_challenges.insert(node_address, challenge);
                 }
@@ -772,6 +950,7 @@ impl Handler {
                         "Invalid Authentication header. Dropping session. Error: {:?}",
                         e
                     );
+                    self.metrics.handshake_failed("invalid_auth_header");
                     self.fail_session(&node_address, RequestError::InvalidRemotePacket)
                         .await;
                 }
@@ -781,6 +960,7 @@ impl Handler {
                 "Received an authenticated header without a matching WHOAREYOU request. {}",
                 node_address
             );
+            self.metrics.handshake_failed("unexpected_auth_header");
         }
     }
 
@@ -812,6 +992,7 @@ impl Handler {
         authenticated_data: &[u8],
     ) {
         // check if we have an available session
+        self.expire_sessions(&node_address).await;
         if let Some(session) = self.sessions.get_mut(&node_address) {
             // attempt to decrypt and process the message.
             let message = match session.decrypt_message(message_nonce, message, &authenticated_data)
@@ -847,13 +1028,25 @@ impl Handler {
             // Remove any associated request from pending_request
             match message {
                 Message::Request(request) => {
+                    self.metrics.message_received(request.msg_type());
+                    // the session authenticates the sender, so it can be held to its limit
+                    if let Some(limiter) = self.request_limiter.as_mut() {
+                        if !limiter.allow(node_address.node_id, Instant::now()) {
+                            self.report_rate_limited(&node_address.node_id).await;
+                        }
+                    }
                     // report the request to the application
                     let _ = self
                         .outbound_channel
                         .send(HandlerResponse::Request(node_address, Box::new(request)))
                         .await;
                 }
+                Message::Notification(notification) => {
+                    self.metrics.message_received(notification.msg_type());
+                    self.handle_notification(node_address, notification).await;
+                }
                 Message::Response(response) => {
+                    self.metrics.message_received(response.msg_type());
                     // Sessions could be awaiting an ENR response. Check if this response matches
                     // these
                     if let Some(request_id) = session.awaiting_enr.as_ref() {
@@ -865,10 +1058,7 @@ impl Handler {
                                     if let Some(enr) = nodes.pop() {
                                         if self.verify_enr(&enr, &node_address) {
                                             // Notify the application
-                                            let _ = self
-                                                .outbound_channel
-                                                .send(HandlerResponse::Established(enr))
-                                                .await;
+                                            self.session_established(node_address, enr).await;
                                             return;
                                         }
                                     }
@@ -876,6 +1066,10 @@ impl Handler {
                                 _ => {}
                             }
                             debug!("Session failed invalid ENR response");
+                            self.metrics.handshake_failed("invalid_enr");
+                            // The response was encrypted with the keys of the session, which only
+                            // the holder of the node id's key can have derived.
+                            self.report_handshake_failure(&node_address.node_id).await;
                             self.fail_session(&node_address, RequestError::InvalidRemoteEnr)
                                 .await;
                             return;
@@ -898,6 +1092,99 @@ impl Handler {
         }
     }
 
+    /// Handles a hole punching notification received over an established session.
+    async fn handle_notification(&mut self, node_address: NodeAddress, notification: Notification) {
+        match notification {
+            Notification::RelayInit {
+                initiator_enr,
+                target,
+                nonce,
+            } => {
+                // Only relay on behalf of the sender itself, so that we can't be used to direct
+                // packets at arbitrary addresses.
+                if !verify_relay_initiator(&initiator_enr, &node_address) {
+                    warn!(
+                        "RELAYINIT with an initiator ENR not matching the source. {}",
+                        node_address
+                    );
+                    return;
+                }
+                if !self
+                    .relay_init_limiter
+                    .allow(node_address.node_id, Instant::now())
+                {
+                    debug!(
+                        "RELAYINIT from an initiator above its rate limit. {}",
+                        node_address
+                    );
+                    return;
+                }
+                let target_address = self
+                    .sessions
+                    .peek_iter()
+                    .map(|(address, _)| address)
+                    .find(|address| address.node_id == target)
+                    .cloned();
+                match target_address {
+                    Some(target_address) => {
+                        trace!(
+                            "Relaying hole punch request from {} to {}",
+                            node_address,
+                            target_address
+                        );
+                        let notification = Notification::RelayMsg {
+                            initiator_enr,
+                            nonce,
+                        };
+                        self.send_notification(target_address, notification).await;
+                    }
+                    None => debug!("RELAYINIT for a node without a session. Target: {}", target),
+                }
+            }
+            Notification::RelayMsg {
+                initiator_enr,
+                nonce,
+            } => {
+                // The relay checked the initiator ENR against the source of its RELAYINIT, so the
+                // address is only trusted from relays whose own ENR has been verified, and at a
+                // limited rate per relay.
+                if !self.established_sessions.contains(&node_address) {
+                    debug!(
+                        "RELAYMSG from a relay without a verified ENR. {}",
+                        node_address
+                    );
+                    return;
+                }
+                if !self
+                    .relay_msg_limiter
+                    .allow(node_address.node_id, Instant::now())
+                {
+                    debug!(
+                        "RELAYMSG from a relay above its rate limit. {}",
+                        node_address
+                    );
+                    return;
+                }
+                let socket_addr = match self.ip_mode.contactable_socket(&initiator_enr) {
+                    Some(socket_addr) if !self.listen_config.contains(&socket_addr) => socket_addr,
+                    _ => {
+                        debug!("RELAYMSG with a non-contactable initiator ENR");
+                        return;
+                    }
+                };
+                let initiator_address = NodeAddress {
+                    socket_addr,
+                    node_id: initiator_enr.node_id(),
+                };
+                // Sending a WHOAREYOU to the initiator opens our NAT mapping for its address and
+                // lets it complete the handshake of its unanswered message.
+                trace!("Hole punching to {}", initiator_address);
+                self.send_challenge(WhoAreYouRef(initiator_address, nonce), Some(initiator_enr))
+                    .await;
+            }
+        }
+    }
+
     /// Handles a response to a request. Re-inserts the request call if the response is a multiple
     /// Nodes response.
     async fn handle_response(&mut self, node_address: NodeAddress, response: Response) {
@@ -915,6 +1202,19 @@ impl Handler {
 
             // The response matches a request
 
+            // Remember which node first told us about each peer, in case the peer is behind a
+            // NAT. Later responders can't take over relaying to a peer.
+            if let (RequestBody::FindNode { .. }, ResponseBody::Nodes { nodes, .. }) =
+                (&request_call.request.body, &response.body)
+            {
+                for enr in nodes {
+                    let key = enr.node_id().raw();
+                    if enr.node_id() != node_address.node_id && !self.relays.contains_key(&key) {
+                        self.relays.insert(key, node_address.clone());
+                    }
+                }
+            }
+
             // Check to see if this is a Nodes response, in which case we may require to wait for
             // extra responses
             if let ResponseBody::Nodes { total, .. } = response.body {
@@ -974,7 +1274,7 @@ impl Handler {
     fn insert_active_request(&mut self, request_call: RequestCall) {
         let node_address = request_call
             .contact
-            .node_address()
+            .node_address(self.ip_mode)
             .expect("Can only add requests with a valid destination");
         // adds the mapping of message nonce to node address
         self.active_requests_nonce_mapping
@@ -982,14 +1282,48 @@ impl Handler {
         self.active_requests.insert(node_address, request_call);
     }
 
-    fn new_session(&mut self, node_address: NodeAddress, session: Session) {
-        if let Some(current_session) = self.sessions.get_mut(&node_address) {
-            current_session.update(session);
-        } else {
-            self.sessions.insert(node_address, session);
-            METRICS
-                .active_sessions
-                .store(self.sessions.len(), Ordering::Relaxed);
+    async fn new_session(&mut self, node_address: NodeAddress, session: Session) {
+        self.metrics.handshake_succeeded();
+        let mut removed: Vec<NodeAddress> = match self.sessions.notify_get_mut(&node_address) {
+            (Some(current_session), expired) => {
+                current_session.update(session);
+                expired
+                    .into_iter()
+                    .map(|(node_address, _)| node_address)
+                    .collect()
+            }
+            (None, mut expired) => {
+                // a full cache evicts its least-recently used session to make room
+                let least_recent = self
+                    .sessions
+                    .peek_iter()
+                    .last()
+                    .map(|(node_address, _)| node_address.clone());
+                expired.extend(self.sessions.notify_insert(node_address.clone(), session).1);
+                let mut removed: Vec<NodeAddress> = expired
+                    .into_iter()
+                    .map(|(node_address, _)| node_address)
+                    .collect();
+                if let Some(least_recent) = least_recent {
+                    if !self.sessions.contains_key(&least_recent) {
+                        removed.push(least_recent);
+                    }
+                }
+                removed
+            }
+        };
+        // an expired session with the node is superseded by the new one, rather than lost
+        removed.retain(|address| address != &node_address);
+        self.sessions_removed(removed).await;
+    }
+
+    /// Removes the sessions that have expired from the cache, reporting them, before the session
+    /// with a node is looked up.
+    async fn expire_sessions(&mut self, node_address: &NodeAddress) {
+        let (_, expired) = self.sessions.notify_get(node_address);
+        if !expired.is_empty() {
+            let removed = expired.into_iter().map(|(node_address, _)| node_address);
+            self.sessions_removed(removed.collect()).await;
         }
     }
 
@@ -1007,16 +1341,37 @@ impl Handler {
 
         let node_address = request_call
             .contact
-            .node_address()
+            .node_address(self.ip_mode)
             .expect("All Request calls have been sanitized");
         self.fail_session(&node_address, error).await;
     }
 
+    /// Penalises a node for a failed handshake. This must only be called once the remote has
+    /// proven it holds the key of `node_id`, otherwise anyone could lower the score of any node.
+    async fn report_handshake_failure(&mut self, node_id: &NodeId) {
+        if self.reputation.report(node_id, Behaviour::HandshakeFailure) {
+            let _ = self
+                .outbound_channel
+                .send(HandlerResponse::Banned(*node_id, BanReason::LowReputation))
+                .await;
+        }
+    }
+
+    /// Penalises a node for exceeding its request limit. As with handshake failures, this must
+    /// only be called for requests received over a session with `node_id`.
+    async fn report_rate_limited(&mut self, node_id: &NodeId) {
+        if self.reputation.report(node_id, Behaviour::RateLimited) {
+            let _ = self
+                .outbound_channel
+                .send(HandlerResponse::Banned(*node_id, BanReason::LowReputation))
+                .await;
+        }
+    }
+
     async fn fail_session(&mut self, node_address: &NodeAddress, error: RequestError) {
-        self.sessions.remove(&node_address);
-        METRICS
-            .active_sessions
-            .store(self.sessions.len(), Ordering::Relaxed);
+        if self.sessions.remove(&node_address).is_some() {
+            self.sessions_removed(vec![node_address.clone()]).await;
+        }
         for request in self
             .pending_requests
             .remove(&node_address)
@@ -1029,6 +1384,31 @@ impl Handler {
         }
     }
 
+    /// Notifies the application that a session has been established with the node of `enr`.
+    async fn session_established(&mut self, node_address: NodeAddress, enr: Enr) {
+        self.established_sessions.insert(node_address);
+        self.outbound_channel
+            .send(HandlerResponse::Established(enr))
+            .await
+            .unwrap_or_else(|e| warn!("Error with sending channel: {}", e));
+    }
+
+    /// Notifies the application of established sessions that have been removed from the session
+    /// cache, having expired, been evicted or failed.
+    async fn sessions_removed(&mut self, removed: Vec<NodeAddress>) {
+        self.metrics
+            .active_sessions
+            .store(self.sessions.len(), Ordering::Relaxed);
+        for node_address in removed {
+            if self.established_sessions.remove(&node_address) {
+                let _ = self
+                    .outbound_channel
+                    .send(HandlerResponse::SessionExpired(node_address))
+                    .await;
+            }
+        }
+    }
+
     /// Sends a packet to the send handler to be encoded and sent.
     async fn send(&mut self, node_address: NodeAddress, packet: Packet) {
         let outbound_packet = socket::OutboundPacket {
@@ -1038,3 +1418,17 @@ impl Handler {
         let _ = self.socket.send.send(outbound_packet).await;
     }
 }
+
+/// Checks that the initiator ENR of a RELAYINIT belongs to the sender and advertises no address
+/// other than the one the notification was received from. The target picks the address to punch
+/// a hole to from the ENR, so a socket of the other address family, which can't be checked,
+/// could point it at any host.
+fn verify_relay_initiator(enr: &Enr, node_address: &NodeAddress) -> bool {
+    let (observed, other) = match node_address.socket_addr {
+        SocketAddr::V4(_) => (enr.udp_socket(), enr.udp6_socket()),
+        SocketAddr::V6(_) => (enr.udp6_socket(), enr.udp_socket()),
+    };
+    enr.node_id() == node_address.node_id
+        && observed == Some(node_address.socket_addr)
+        && other.is_none()
+}
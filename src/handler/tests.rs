@@ -2,9 +2,10 @@
 use super::*;
 use crate::{
     rpc::{Request, Response},
-    Discv5ConfigBuilder,
+    socket::Transport,
+    Discv5ConfigBuilder, FilterConfigBuilder, MemoryNetwork,
 };
-use enr::EnrBuilder;
+use enr::{EnrBuilder, EnrKey};
 use std::{net::IpAddr, time::Duration};
 use tokio::time::sleep;
 
@@ -20,6 +21,20 @@ macro_rules! arc_rw {
     };
 }
 
+/// A reputation tracker with the default configuration.
+fn reputation() -> Arc<Reputation> {
+    Arc::new(Reputation::new(
+        &Discv5Config::default(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+    ))
+}
+
+/// Binds an in-memory transport to the UDP socket of `enr`.
+fn transports(network: &MemoryNetwork, enr: &Enr) -> Transports {
+    Transports::single(network.bind(enr.udp_socket().unwrap()).unwrap()).unwrap()
+}
+
 #[tokio::test]
 // Tests the construction and sending of a simple message
 async fn simple_session_message() {
@@ -33,6 +48,7 @@ async fn simple_session_message() {
     let key2 = CombinedKey::generate_secp256k1();
 
     let config = Discv5ConfigBuilder::new().build();
+    let network = MemoryNetwork::default();
 
     let sender_enr = EnrBuilder::new("v4")
         .ip(ip)
@@ -48,8 +64,11 @@ async fn simple_session_message() {
     let (_exit_send, sender_send, _sender_recv) = Handler::spawn(
         arc_rw!(sender_enr.clone()),
         arc_rw!(key1),
-        sender_enr.udp_socket().unwrap(),
+        transports(&network, &sender_enr),
         config.clone(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
     )
     .await
     .unwrap();
@@ -57,8 +76,11 @@ async fn simple_session_message() {
     let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
         arc_rw!(receiver_enr.clone()),
         arc_rw!(key2),
-        receiver_enr.udp_socket().unwrap(),
+        transports(&network, &receiver_enr),
         config,
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
     )
     .await
     .unwrap();
@@ -110,6 +132,7 @@ async fn multiple_messages() {
     let key2 = CombinedKey::generate_secp256k1();
 
     let config = Discv5ConfigBuilder::new().build();
+    let network = MemoryNetwork::default();
     let sender_enr = EnrBuilder::new("v4")
         .ip(ip)
         .udp(sender_port)
@@ -124,8 +147,11 @@ async fn multiple_messages() {
     let (_exit_send, sender_handler, mut sender_handler_recv) = Handler::spawn(
         arc_rw!(sender_enr.clone()),
         arc_rw!(key1),
-        sender_enr.udp_socket().unwrap(),
+        transports(&network, &sender_enr),
         config.clone(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
     )
     .await
     .unwrap();
@@ -133,8 +159,11 @@ async fn multiple_messages() {
     let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
         arc_rw!(receiver_enr.clone()),
         arc_rw!(key2),
-        receiver_enr.udp_socket().unwrap(),
+        transports(&network, &receiver_enr),
         config,
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
     )
     .await
     .unwrap();
@@ -217,3 +246,344 @@ async fn multiple_messages() {
         }
     }
 }
+
+#[tokio::test]
+// Tests that sessions are reported as established, and as expired once they are found to have
+// timed out
+async fn session_expiry_is_reported() {
+    init();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new()
+        .session_timeout(Duration::from_millis(200))
+        .build();
+    let network = MemoryNetwork::default();
+    let sender_enr = EnrBuilder::new("v4").ip(ip).udp(5006).build(&key1).unwrap();
+    let receiver_enr = EnrBuilder::new("v4").ip(ip).udp(5007).build(&key2).unwrap();
+
+    let (_exit_send, sender_send, _sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(key1),
+        transports(&network, &sender_enr),
+        config.clone(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(key2),
+        transports(&network, &receiver_enr),
+        config,
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
+    )
+    .await
+    .unwrap();
+
+    let _ = sender_send.send(HandlerRequest::Request(
+        receiver_enr.into(),
+        Box::new(Request {
+            id: RequestId(vec![1]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        }),
+    ));
+
+    let sender_id = sender_enr.node_id();
+    let receiver = async move {
+        let mut established = false;
+        loop {
+            match receiver_recv.recv().await {
+                Some(HandlerResponse::WhoAreYou(wru_ref)) => {
+                    let _ = recv_send
+                        .send(HandlerRequest::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                }
+                Some(HandlerResponse::Established(enr)) => {
+                    assert_eq!(enr.node_id(), sender_id);
+                    established = true;
+                }
+                Some(HandlerResponse::Request(node_address, request)) => {
+                    // the expiry is noticed when the session is next looked up, to respond
+                    sleep(Duration::from_millis(300)).await;
+                    let response = Response {
+                        id: request.id,
+                        body: ResponseBody::Pong {
+                            enr_seq: 1,
+                            ip,
+                            port: 5006,
+                        },
+                    };
+                    let _ =
+                        recv_send.send(HandlerRequest::Response(node_address, Box::new(response)));
+                }
+                Some(HandlerResponse::SessionExpired(node_address)) => {
+                    assert!(established);
+                    assert_eq!(node_address.node_id, sender_id);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => {}
+        _ = sleep(Duration::from_secs(3)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+// Tests that handshakes forged for another node's id do not lower the reputation of that node
+async fn forged_handshakes_do_not_penalise_claimed_id() {
+    init();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let attacker_key = CombinedKey::generate_secp256k1();
+    let victim_id = NodeId::random();
+
+    let network = MemoryNetwork::default();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip(ip)
+        .udp(5008)
+        .build(&receiver_key)
+        .unwrap();
+    let attacker_enr = EnrBuilder::new("v4")
+        .ip(ip)
+        .udp(5009)
+        .build(&attacker_key)
+        .unwrap();
+    let attacker_socket = attacker_enr.udp_socket().unwrap();
+    let attacker = network.bind(attacker_socket).unwrap();
+
+    let reputation = reputation();
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        transports(&network, &receiver_enr),
+        Discv5ConfigBuilder::new().build(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation.clone(),
+    )
+    .await
+    .unwrap();
+
+    let receiver_address = receiver_enr.udp_socket().unwrap();
+    let receiver_contact: NodeContact = receiver_enr.clone().into();
+    let attacker_key = arc_rw!(attacker_key);
+    let attempts = async move {
+        // the first handshakes attach the attacker's ENR, the last one none at all
+        for attempt in 0..4 {
+            let packet = Packet::new_random(&victim_id).unwrap();
+            attacker
+                .send_to(&packet.encode(&receiver_enr.node_id()), receiver_address)
+                .await
+                .unwrap();
+            loop {
+                match receiver_recv.recv().await {
+                    Some(HandlerResponse::WhoAreYou(wru_ref)) => {
+                        let _ = recv_send.send(HandlerRequest::WhoAreYou(wru_ref, None));
+                        break;
+                    }
+                    Some(HandlerResponse::Banned(..)) => panic!("Claimed node id was banned"),
+                    _ => {}
+                }
+            }
+
+            let mut buf = [0; 1280];
+            let (length, _) = attacker.recv_from(&mut buf).await.unwrap();
+            let (challenge, authenticated_data) =
+                Packet::decode(&victim_id, &buf[..length]).unwrap();
+            assert!(challenge.is_whoareyou());
+            let challenge_data = ChallengeData::try_from(authenticated_data.as_slice()).unwrap();
+
+            let enr = if attempt < 3 {
+                Some(attacker_enr.clone())
+            } else {
+                None
+            };
+            let request = Request {
+                id: RequestId(vec![attempt]),
+                body: RequestBody::Ping { enr_seq: 1 },
+            };
+            let (auth_packet, _) = Session::encrypt_with_header(
+                &receiver_contact,
+                attacker_key.clone(),
+                enr,
+                &victim_id,
+                &challenge_data,
+                &request.encode(),
+            )
+            .unwrap();
+            attacker
+                .send_to(
+                    &auth_packet.encode(&receiver_enr.node_id()),
+                    receiver_address,
+                )
+                .await
+                .unwrap();
+        }
+        // give the receiver time to process the last handshake
+        sleep(Duration::from_millis(100)).await;
+    };
+
+    tokio::select! {
+        _ = attempts => {}
+        _ = sleep(Duration::from_secs(3)) => {
+            panic!("Test timed out");
+        }
+    }
+    assert_eq!(reputation.score(&victim_id), 0.0);
+}
+
+#[tokio::test]
+// Tests that requests over a session above the node's limit lower the reputation of the sender
+async fn requests_above_limit_penalise_authenticated_sender() {
+    init();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let network = MemoryNetwork::default();
+    let sender_enr = EnrBuilder::new("v4").ip(ip).udp(5012).build(&key1).unwrap();
+    let receiver_enr = EnrBuilder::new("v4").ip(ip).udp(5013).build(&key2).unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(key1),
+        transports(&network, &sender_enr),
+        Discv5ConfigBuilder::new().build(),
+        arc_rw!(PermitBanList::default()),
+        Arc::new(InternalMetrics::default()),
+        reputation(),
+    )
+    .await
+    .unwrap();
+
+    // the packet filter lets the permitted sender through, leaving its requests to the handler
+    let mut permit_ban_list = PermitBanList::default();
+    permit_ban_list.permit_nodes.insert(sender_enr.node_id());
+    let filter_config = FilterConfigBuilder::default()
+        .max_requests_per_node_per_second(1.0)
+        .max_requests_per_ip_per_second(5.0)
+        .build();
+    let receiver_config = Discv5ConfigBuilder::new()
+        .filter_config(filter_config)
+        .enable_packet_filter()
+        .build();
+    let reputation = reputation();
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(key2),
+        transports(&network, &receiver_enr),
+        receiver_config,
+        arc_rw!(permit_ban_list),
+        Arc::new(InternalMetrics::default()),
+        reputation.clone(),
+    )
+    .await
+    .unwrap();
+
+    let request = Box::new(Request {
+        id: RequestId(vec![1]),
+        body: RequestBody::Ping { enr_seq: 1 },
+    });
+    let _ = sender_send.send(HandlerRequest::Request(
+        receiver_enr.clone().into(),
+        request.clone(),
+    ));
+
+    let sender_id = sender_enr.node_id();
+    let messages = async move {
+        let mut received = 0;
+        loop {
+            tokio::select! {
+                Some(response) = sender_recv.recv() => {
+                    if let HandlerResponse::Established(_) = response {
+                        for _ in 0..3 {
+                            let _ = sender_send.send(HandlerRequest::Request(
+                                receiver_enr.clone().into(),
+                                request.clone(),
+                            ));
+                        }
+                    }
+                }
+                Some(response) = receiver_recv.recv() => match response {
+                    HandlerResponse::WhoAreYou(wru_ref) => {
+                        let _ = recv_send
+                            .send(HandlerRequest::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerResponse::Request(addr, request) => {
+                        let pong = Response {
+                            id: request.id,
+                            body: ResponseBody::Pong { enr_seq: 1, ip, port: 5012 },
+                        };
+                        let _ = recv_send.send(HandlerRequest::Response(addr, Box::new(pong)));
+                        received += 1;
+                        if received == 4 {
+                            return;
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = messages => {}
+        _ = sleep(Duration::from_millis(500)) => {
+            panic!("Test timed out");
+        }
+    }
+    // the first request is within the limit, the rest exceed it
+    assert!((reputation.score(&sender_id) + 3.0).abs() < 0.01);
+}
+
+#[test]
+// Tests that only initiators advertising nothing but their observed address are relayed
+fn relay_initiator_must_advertise_only_its_source() {
+    let key = CombinedKey::generate_secp256k1();
+    let ip4: IpAddr = "127.0.0.1".parse().unwrap();
+    let ip6: IpAddr = "::1".parse().unwrap();
+    let enr = |builder: &mut EnrBuilder<CombinedKey>| builder.build(&key).unwrap();
+    let source = NodeAddress {
+        socket_addr: SocketAddr::new(ip4, 5010),
+        node_id: NodeId::from(key.public()),
+    };
+
+    assert!(verify_relay_initiator(
+        &enr(EnrBuilder::new("v4").ip(ip4).udp(5010)),
+        &source
+    ));
+    // no socket, another socket of the observed family, or one of the other family
+    assert!(!verify_relay_initiator(
+        &enr(&mut EnrBuilder::new("v4")),
+        &source
+    ));
+    assert!(!verify_relay_initiator(
+        &enr(EnrBuilder::new("v4").ip(ip4).udp(5011)),
+        &source
+    ));
+    assert!(!verify_relay_initiator(
+        &enr(EnrBuilder::new("v4").ip(ip4).udp(5010).ip(ip6).udp6(5010)),
+        &source
+    ));
+
+    // the ENR must belong to the sender
+    let other = CombinedKey::generate_secp256k1();
+    let other_enr = EnrBuilder::new("v4")
+        .ip(ip4)
+        .udp(5010)
+        .build(&other)
+        .unwrap();
+    assert!(!verify_relay_initiator(&other_enr, &source));
+}
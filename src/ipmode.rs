@@ -0,0 +1,58 @@
+//! The address families a server can reach its peers on.
+
+use crate::Enr;
+use std::net::SocketAddr;
+
+/// The address families the server listens on, which determines which of a peer's advertised
+/// sockets can be contacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpMode {
+    /// Only IPv4 peers can be contacted.
+    Ip4,
+    /// Only IPv6 peers can be contacted.
+    Ip6,
+    /// Peers can be contacted on either family. IPv6 is preferred if a peer advertises both.
+    DualStack,
+}
+
+impl IpMode {
+    /// The socket on which the node of `enr` can be contacted, if any.
+    pub fn contactable_socket(&self, enr: &Enr) -> Option<SocketAddr> {
+        match self {
+            IpMode::Ip4 => enr.udp_socket(),
+            IpMode::Ip6 => enr.udp6_socket(),
+            IpMode::DualStack => enr.udp6_socket().or_else(|| enr.udp_socket()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, EnrBuilder};
+
+    #[test]
+    fn contactable_socket_follows_ip_mode() {
+        let key = CombinedKey::generate_secp256k1();
+        let ipv4_enr = EnrBuilder::new("v4")
+            .ip("127.0.0.1".parse().unwrap())
+            .udp(9000)
+            .build(&key)
+            .unwrap();
+        let mut dual_enr = ipv4_enr.clone();
+        dual_enr
+            .set_udp_socket("[::1]:9001".parse().unwrap(), &key)
+            .unwrap();
+
+        let ipv4: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let ipv6: SocketAddr = "[::1]:9001".parse().unwrap();
+
+        assert_eq!(IpMode::Ip4.contactable_socket(&ipv4_enr), Some(ipv4));
+        assert_eq!(IpMode::Ip6.contactable_socket(&ipv4_enr), None);
+        assert_eq!(IpMode::DualStack.contactable_socket(&ipv4_enr), Some(ipv4));
+
+        assert_eq!(IpMode::Ip4.contactable_socket(&dual_enr), Some(ipv4));
+        assert_eq!(IpMode::Ip6.contactable_socket(&dual_enr), Some(ipv6));
+        assert_eq!(IpMode::DualStack.contactable_socket(&dual_enr), Some(ipv6));
+    }
+}
@@ -72,22 +72,26 @@
 
 mod bucket;
 mod entry;
+mod info;
 mod key;
+pub mod snapshot;
 
 pub use entry::*;
+pub use info::{BucketInfo, EntryInfo, PendingInfo, TableInfo};
 
 use crate::Enr;
-use arrayvec::{self, ArrayVec};
 use bucket::KBucket;
+pub(crate) use bucket::{MAX_NODES_PER_SUBNET_BUCKET, MAX_REPLACEMENTS_PER_BUCKET};
+use ipnet::{Ipv4Net, Ipv6Net};
 use std::{
     collections::VecDeque,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 /// Maximum number of k-buckets.
-const NUM_BUCKETS: usize = 256;
-/// Number of permitted nodes in the same /24 subnet
-const MAX_NODES_PER_SUBNET_TABLE: usize = 10;
+pub(crate) const NUM_BUCKETS: usize = 256;
+/// The default number of permitted nodes in the same subnet across the table.
+pub(crate) const MAX_NODES_PER_SUBNET_TABLE: usize = 10;
 
 /// A key that can be returned from the `closest_keys` function, which indicates if the key matches the
 /// predicate or not.
@@ -115,6 +119,8 @@ pub struct KBucketsTable<TNodeId, TVal> {
     local_key: Key<TNodeId>,
     /// The buckets comprising the routing table.
     buckets: Vec<KBucket<TNodeId, TVal>>,
+    /// The number of permitted nodes in the same subnet across the table.
+    max_nodes_per_subnet: usize,
     /// The list of evicted entries that have been replaced with pending
     /// entries since the last call to [`KBucketsTable::take_applied_pending`].
     applied_pending: VecDeque<AppliedPending<TNodeId, TVal>>,
@@ -155,17 +161,42 @@ where
     /// The given `pending_timeout` specifies the duration after creation of
     /// a [`PendingEntry`] after which it becomes eligible for insertion into
     /// a full bucket, replacing the least-recently (dis)connected node.
-    pub fn new(local_key: Key<TNodeId>, pending_timeout: Duration) -> Self {
+    ///
+    /// Each bucket holds up to `bucket_size` nodes, of which at most
+    /// `max_nodes_per_subnet_bucket` may share a subnet, while at most
+    /// `max_nodes_per_subnet_table` may share one across the whole table. Up to
+    /// `max_replacements` recently connected nodes which did not fit are kept per bucket, to
+    /// replace nodes that fail or are removed.
+    pub fn new(
+        local_key: Key<TNodeId>,
+        pending_timeout: Duration,
+        bucket_size: usize,
+        max_replacements: usize,
+        max_nodes_per_subnet_bucket: usize,
+        max_nodes_per_subnet_table: usize,
+    ) -> Self {
         KBucketsTable {
             local_key,
             buckets: (0..NUM_BUCKETS)
-                .map(|_| KBucket::new(pending_timeout))
+                .map(|_| {
+                    KBucket::new(
+                        pending_timeout,
+                        bucket_size,
+                        max_replacements,
+                        max_nodes_per_subnet_bucket,
+                    )
+                })
                 .collect(),
+            max_nodes_per_subnet: max_nodes_per_subnet_table,
             applied_pending: VecDeque::new(),
         }
     }
 
-    /// Removes a node from the routing table. Returns `true` of the node existed.
+    /// Removes a node from the routing table, or from the replacement candidates of its
+    /// bucket. Returns `true` of the node existed.
+    ///
+    /// A node removed from its bucket is replaced by the most recently seen replacement
+    /// candidate, if any, which is inserted as disconnected.
     pub fn remove(&mut self, key: &Key<TNodeId>) -> bool {
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
@@ -173,7 +204,14 @@ where
             if let Some(applied) = bucket.apply_pending() {
                 self.applied_pending.push_back(applied)
             }
-            bucket.remove(key)
+            if bucket.remove(key) {
+                if let Some(applied) = bucket.promote_replacement() {
+                    self.applied_pending.push_back(applied)
+                }
+                true
+            } else {
+                bucket.remove_replacement(key)
+            }
         } else {
             false
         }
@@ -188,7 +226,7 @@ where
             if let Some(applied) = bucket.apply_pending() {
                 self.applied_pending.push_back(applied)
             }
-            Entry::new(bucket, key)
+            Entry::new(bucket, &mut self.applied_pending, key)
         } else {
             Entry::SelfEntry
         }
@@ -205,6 +243,7 @@ where
                 node: NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    last_seen: n.last_seen,
                 },
                 status,
             })
@@ -220,6 +259,7 @@ where
                 node: NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    last_seen: n.last_seen,
                 },
                 status,
             })
@@ -273,6 +313,7 @@ where
                 let node = NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    last_seen: n.last_seen,
                 };
                 EntryRefView { node, status }
             }) {
@@ -301,9 +342,7 @@ where
             iter: None,
             table: self,
             buckets_iter: ClosestBucketsIter::new(distance),
-            fmap: |b: &KBucket<_, _>| -> ArrayVec<_> {
-                b.iter().map(|(n, _)| n.key.clone()).collect()
-            },
+            fmap: |b: &KBucket<_, _>| -> Vec<_> { b.iter().map(|(n, _)| n.key.clone()).collect() },
         }
     }
 
@@ -324,7 +363,7 @@ where
             iter: None,
             table: self,
             buckets_iter: ClosestBucketsIter::new(distance),
-            fmap: move |b: &KBucket<TNodeId, TVal>| -> ArrayVec<_> {
+            fmap: move |b: &KBucket<TNodeId, TVal>| -> Vec<_> {
                 b.iter()
                     .map(|(n, _)| PredicateKey {
                         key: n.key.clone(),
@@ -335,6 +374,15 @@ where
         }
     }
 
+    /// Returns an iterator over the buckets of the table, together with their log2 distance
+    /// from the local key.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, &KBucket<TNodeId, TVal>)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| (index as u64 + 1, bucket))
+    }
+
     /// Returns a reference to a bucket given the key. Returns None if bucket does not exist.
     pub fn get_bucket<'a>(&'a self, key: &Key<TNodeId>) -> Option<&'a KBucket<TNodeId, TVal>> {
         let index = BucketIndex::new(&self.local_key.distance(key));
@@ -347,8 +395,9 @@ where
     }
 
     /// Checks if key and value can be inserted into the kbuckets table.
-    /// A single bucket can only have `MAX_NODES_PER_SUBNET_BUCKET` nodes per /24 subnet.
-    /// The entire table can only have `MAX_NODES_PER_SUBNET_TABLE` nodes per /24 subnet.
+    /// A single bucket can only have `max_nodes_per_subnet_bucket` nodes per subnet.
+    /// The entire table can only have `max_nodes_per_subnet_table` nodes per subnet.
+    /// If the key is already in the table, its value is compared against the other entries.
     pub fn check(
         &self,
         key: &Key<TNodeId>,
@@ -357,8 +406,12 @@ where
     ) -> bool {
         let bucket = self.get_bucket(key);
         if let Some(b) = bucket {
-            let others = self.iter_ref().map(|e| e.node.value).collect();
-            f(value, others, MAX_NODES_PER_SUBNET_TABLE) && b.check(value, f)
+            let others = self
+                .iter_ref()
+                .filter(|e| e.node.key != key)
+                .map(|e| e.node.value)
+                .collect();
+            f(value, others, self.max_nodes_per_subnet) && b.check(key, value, f)
         } else {
             true
         }
@@ -379,7 +432,7 @@ struct ClosestIter<'a, TTarget, TNodeId, TVal, TMap, TOut> {
     /// distance of the local key to the target.
     buckets_iter: ClosestBucketsIter,
     /// The iterator over the entries in the currently traversed bucket.
-    iter: Option<arrayvec::IntoIter<[TOut; MAX_NODES_PER_BUCKET]>>,
+    iter: Option<std::vec::IntoIter<TOut>>,
     /// The projection function / mapping applied on each bucket as
     /// it is encountered, producing the next `iter`ator.
     fmap: TMap,
@@ -420,7 +473,8 @@ impl ClosestBucketsIter {
     fn new(distance: Distance) -> Self {
         let state = match BucketIndex::new(&distance) {
             Some(i) => ClosestBucketsIterState::Start(i),
-            None => ClosestBucketsIterState::Done,
+            // the target is the local key, so every bucket is visited from the closest outwards
+            None => ClosestBucketsIterState::ZoomIn(BucketIndex(0)),
         };
         Self { distance, state }
     }
@@ -483,7 +537,7 @@ impl<TTarget, TNodeId, TVal, TMap, TOut> Iterator
     for ClosestIter<'_, TTarget, TNodeId, TVal, TMap, TOut>
 where
     TNodeId: Clone,
-    TMap: Fn(&KBucket<TNodeId, TVal>) -> ArrayVec<[TOut; MAX_NODES_PER_BUCKET]>,
+    TMap: Fn(&KBucket<TNodeId, TVal>) -> Vec<TOut>,
     TOut: AsRef<Key<TNodeId>>,
 {
     type Item = TOut;
@@ -527,7 +581,14 @@ mod tests {
         let local_key = Key::from(NodeId::random());
         let other_id = Key::from(NodeId::random());
 
-        let mut table = KBucketsTable::<_, ()>::new(local_key, Duration::from_secs(5));
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key,
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+            MAX_NODES_PER_SUBNET_TABLE,
+        );
         if let Entry::Absent(entry) = table.entry(&other_id) {
             match entry.insert((), NodeStatus::Connected) {
                 InsertResult::Inserted => (),
@@ -545,7 +606,14 @@ mod tests {
     #[test]
     fn update_local_id_fails() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(local_key.clone(), Duration::from_secs(5));
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key.clone(),
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+            MAX_NODES_PER_SUBNET_TABLE,
+        );
         match table.entry(&local_key) {
             Entry::SelfEntry => (),
             _ => panic!(),
@@ -555,7 +623,14 @@ mod tests {
     #[test]
     fn closest() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(local_key, Duration::from_secs(5));
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key.clone(),
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+            MAX_NODES_PER_SUBNET_TABLE,
+        );
         let mut count = 0;
         loop {
             if count == 100 {
@@ -585,12 +660,24 @@ mod tests {
             expected_keys.sort_by_key(|k| k.distance(&target_key));
             assert_eq!(keys, expected_keys);
         }
+
+        // a lookup of the local key visits every bucket
+        let keys = table.closest_keys(&local_key).collect::<Vec<_>>();
+        expected_keys.sort_by_key(|k| k.distance(&local_key));
+        assert_eq!(keys, expected_keys);
     }
 
     #[test]
     fn applied_pending() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(local_key.clone(), Duration::from_millis(1));
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key.clone(),
+            Duration::from_millis(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+            MAX_NODES_PER_SUBNET_TABLE,
+        );
         let expected_applied;
         let full_bucket_index;
         loop {
@@ -606,6 +693,7 @@ mod tests {
                                         evicted: Some(Node {
                                             key: disconnected,
                                             value: (),
+                                            last_seen: None,
                                         }),
                                     };
                                     full_bucket_index = BucketIndex::new(&key.distance(&local_key));
@@ -642,25 +730,74 @@ mod tests {
         assert_eq!(Some(expected_applied), table.take_applied_pending());
         assert_eq!(None, table.take_applied_pending());
     }
+
+    #[test]
+    fn removed_nodes_are_replaced_by_candidates() {
+        let local_key = Key::from(NodeId::random());
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key.clone(),
+            Duration::from_secs(5),
+            1,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+            MAX_NODES_PER_SUBNET_TABLE,
+        );
+
+        // fill a bucket of one node and keep another connected node as its candidate
+        let mut keys = Vec::new();
+        while keys.len() < 2 {
+            let key = Key::from(NodeId::random());
+            if key.log2_distance(&local_key) == Some(256) {
+                if let Entry::Absent(entry) = table.entry(&key) {
+                    let _ = entry.insert((), NodeStatus::Connected);
+                }
+                keys.push(key);
+            }
+        }
+        assert!(matches!(table.entry(&keys[1]), Entry::Absent(_)));
+
+        assert!(table.remove(&keys[0]));
+        assert!(matches!(
+            table.entry(&keys[1]),
+            Entry::Present(_, NodeStatus::Disconnected)
+        ));
+        let applied = table.take_applied_pending().unwrap();
+        assert_eq!(applied.inserted, keys[1]);
+        assert_eq!(applied.evicted, None);
+    }
 }
 
 /// Takes an `ENR` to insert and a list of other `ENR`s to compare against.
 /// Returns `true` if `ENR` can be inserted and `false` otherwise.
-/// `enr` can be inserted if the count of enrs in `others` in the same /24 subnet as `ENR`
-/// is less than `limit`.
-pub fn ip_limiter(enr: &Enr, others: &[&Enr], limit: usize) -> bool {
-    let mut allowed = true;
-    if let Some(ip) = enr.ip() {
-        let count = others.iter().flat_map(|e| e.ip()).fold(0, |acc, x| {
-            if x.octets()[0..3] == ip.octets()[0..3] {
-                acc + 1
-            } else {
-                acc
-            }
-        });
+/// `enr` can be inserted if, for each of its IPv4 and IPv6 addresses, the count of enrs in
+/// `others` in the same subnet is less than `limit`. Subnets are the `ipv4_prefix` and
+/// `ipv6_prefix` bit prefixes of the addresses.
+pub fn ip_limiter(
+    enr: &Enr,
+    others: &[&Enr],
+    limit: usize,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+) -> bool {
+    if let Some(subnet) = enr.ip().and_then(|ip| Ipv4Net::new(ip, ipv4_prefix).ok()) {
+        let count = others
+            .iter()
+            .flat_map(|e| e.ip())
+            .filter(|ip| subnet.contains(ip))
+            .count();
+        if count >= limit {
+            return false;
+        }
+    }
+    if let Some(subnet) = enr.ip6().and_then(|ip| Ipv6Net::new(ip, ipv6_prefix).ok()) {
+        let count = others
+            .iter()
+            .flat_map(|e| e.ip6())
+            .filter(|ip| subnet.contains(ip))
+            .count();
         if count >= limit {
-            allowed = false;
+            return false;
         }
-    };
-    allowed
+    }
+    true
 }
@@ -32,9 +32,12 @@
 
 use super::*;
 
-/// Maximum number of nodes in a bucket, i.e. the (fixed) `k` parameter.
+/// The default maximum number of nodes in a bucket, i.e. the `k` parameter.
 pub const MAX_NODES_PER_BUCKET: usize = 16;
-const MAX_NODES_PER_SUBNET_BUCKET: usize = 2;
+/// The default maximum number of replacement candidates kept for a bucket.
+pub const MAX_REPLACEMENTS_PER_BUCKET: usize = 10;
+/// The default number of permitted nodes in the same subnet per bucket.
+pub const MAX_NODES_PER_SUBNET_BUCKET: usize = 2;
 
 /// A `PendingNode` is a `Node` that is pending insertion into a `KBucket`.
 #[derive(Debug, Clone)]
@@ -67,6 +70,15 @@ impl<TNodeId, TVal> PendingNode<TNodeId, TVal> {
         self.status
     }
 
+    pub fn node(&self) -> &Node<TNodeId, TVal> {
+        &self.node
+    }
+
+    /// The instant at which the pending node is eligible for insertion into the bucket.
+    pub fn ready_at(&self) -> Instant {
+        self.replace
+    }
+
     pub fn value_mut(&mut self) -> &mut TVal {
         &mut self.node.value
     }
@@ -85,19 +97,27 @@ pub struct Node<TNodeId, TVal> {
     pub key: Key<TNodeId>,
     /// The associated value.
     pub value: TVal,
+    /// The last time the node was known to be connected, if ever.
+    pub last_seen: Option<SystemTime>,
 }
 
 /// The position of a node in a `KBucket`, i.e. a non-negative integer
-/// in the range `[0, MAX_NODES_PER_BUCKET)`.
+/// in the range `[0, max_nodes)`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Position(usize);
 
-/// A `KBucket` is a list of up to `MAX_NODES_PER_BUCKET` `Key`s and associated values,
+/// A `KBucket` is a list of up to `max_nodes` `Key`s and associated values,
 /// ordered from least-recently connected to most-recently connected.
 #[derive(Debug, Clone)]
 pub struct KBucket<TNodeId, TVal> {
     /// The nodes contained in the bucket.
-    nodes: ArrayVec<[Node<TNodeId, TVal>; MAX_NODES_PER_BUCKET]>,
+    nodes: Vec<Node<TNodeId, TVal>>,
+
+    /// The maximum number of nodes in the bucket, i.e. the `k` parameter.
+    max_nodes: usize,
+
+    /// The number of permitted nodes in the same subnet.
+    max_nodes_per_subnet: usize,
 
     /// The position (index) in `nodes` that marks the first connected node.
     ///
@@ -105,7 +125,7 @@ pub struct KBucket<TNodeId, TVal> {
     /// most-recently connected, all entries above this index are also considered
     /// connected, i.e. the range `[0, first_connected_pos)` marks the sub-list of entries
     /// that are considered disconnected and the range
-    /// `[first_connected_pos, MAX_NODES_PER_BUCKET)` marks sub-list of entries that are
+    /// `[first_connected_pos, max_nodes)` marks sub-list of entries that are
     /// considered connected.
     ///
     /// `None` indicates that there are no connected entries in the bucket, i.e.
@@ -122,6 +142,16 @@ pub struct KBucket<TNodeId, TVal> {
     /// if the least-recently connected node is not updated as being connected
     /// in the meantime.
     pending_timeout: Duration,
+
+    /// Recently connected nodes which did not fit in the full bucket, ordered from
+    /// least-recently to most-recently seen. These replace nodes that fail or are removed.
+    replacements: VecDeque<Node<TNodeId, TVal>>,
+
+    /// The maximum number of nodes in `replacements`.
+    max_replacements: usize,
+
+    /// The last time a node was inserted, removed or changed status, if ever.
+    last_updated: Option<Instant>,
 }
 
 /// The result of inserting an entry into a bucket.
@@ -161,13 +191,25 @@ impl<TNodeId, TVal> KBucket<TNodeId, TVal>
 where
     TNodeId: Clone,
 {
-    /// Creates a new `KBucket` with the given timeout for pending entries.
-    pub fn new(pending_timeout: Duration) -> Self {
+    /// Creates a new `KBucket` with the given timeout for pending entries, holding up to
+    /// `max_nodes` nodes of which at most `max_nodes_per_subnet` share a subnet, along with up
+    /// to `max_replacements` replacement candidates.
+    pub fn new(
+        pending_timeout: Duration,
+        max_nodes: usize,
+        max_replacements: usize,
+        max_nodes_per_subnet: usize,
+    ) -> Self {
         KBucket {
-            nodes: ArrayVec::new(),
+            nodes: Vec::with_capacity(max_nodes),
+            max_nodes,
+            max_nodes_per_subnet,
             first_connected_pos: None,
             pending: None,
             pending_timeout,
+            replacements: VecDeque::new(),
+            max_replacements,
+            last_updated: None,
         }
     }
 
@@ -204,13 +246,18 @@ where
     pub fn apply_pending(&mut self) -> Option<AppliedPending<TNodeId, TVal>> {
         if let Some(pending) = self.pending.take() {
             if pending.replace <= Instant::now() {
-                if self.nodes.is_full() {
+                if self.is_full() {
                     if self.status(Position(0)) == NodeStatus::Connected {
-                        // The bucket is full with connected nodes. Drop the pending node.
+                        // The bucket is full with connected nodes. Keep a connected pending
+                        // node as a replacement candidate.
+                        if pending.status == NodeStatus::Connected {
+                            self.add_replacement(pending.node);
+                        }
                         return None;
                     }
                     // The pending node will be inserted.
                     let inserted = pending.node.key.clone();
+                    self.last_updated = Some(Instant::now());
                     // A connected pending node goes at the end of the list for
                     // the connected peers, removing the least-recently connected.
                     if pending.status == NodeStatus::Connected {
@@ -266,7 +313,15 @@ where
 
     /// Updates the status of the node referred to by the given key, if it is
     /// in the bucket.
-    pub fn update(&mut self, key: &Key<TNodeId>, status: NodeStatus) {
+    ///
+    /// A connected node becoming disconnected is replaced by the most recently seen
+    /// replacement candidate straight away, if the bucket holds any and no node is pending.
+    /// The result of the replacement is returned.
+    pub fn update(
+        &mut self,
+        key: &Key<TNodeId>,
+        status: NodeStatus,
+    ) -> Option<AppliedPending<TNodeId, TVal>> {
         // Remove the node from its current position and then reinsert it
         // with the desired status, which puts it at the end of either the
         // prefix list of disconnected nodes or the suffix list of connected
@@ -275,22 +330,7 @@ where
         if let Some(pos) = self.position(key) {
             // Remove the node from its current position.
             let old_status = self.status(pos);
-            let node = self.nodes.remove(pos.0);
-            // Adjust `first_connected_pos` accordingly.
-            match old_status {
-                NodeStatus::Connected => {
-                    if self.first_connected_pos.map_or(false, |p| p == pos.0)
-                        && pos.0 == self.nodes.len()
-                    {
-                        // It was the last connected node.
-                        self.first_connected_pos = None
-                    }
-                }
-                NodeStatus::Disconnected => {
-                    self.first_connected_pos =
-                        self.first_connected_pos.and_then(|p| p.checked_sub(1))
-                }
-            }
+            let node = self.remove_at(pos);
             // If the least-recently connected node re-establishes its
             // connected status, drop the pending node.
             if pos == Position(0) && status == NodeStatus::Connected {
@@ -301,7 +341,77 @@ where
                 InsertResult::Inserted => {}
                 _ => unreachable!("The node is removed before being (re)inserted."),
             }
+            if old_status == NodeStatus::Connected && status == NodeStatus::Disconnected {
+                return self.promote_replacement();
+            }
+        }
+        None
+    }
+
+    /// Inserts the most recently seen replacement candidate into the bucket as a disconnected
+    /// node, if there is room for it or a disconnected node to evict, and no node is pending.
+    /// The candidate may have gone offline since it was last seen, so it is only marked as
+    /// connected once it is heard from again.
+    ///
+    /// The inserted node is returned together with the node it evicted, if any.
+    pub fn promote_replacement(&mut self) -> Option<AppliedPending<TNodeId, TVal>> {
+        if self.pending.is_some() || (self.is_full() && self.first_connected_pos == Some(0)) {
+            return None;
+        }
+        let node = self.replacements.pop_back()?;
+        self.pending = Some(PendingNode {
+            node,
+            status: NodeStatus::Disconnected,
+            replace: Instant::now(),
+        });
+        self.apply_pending()
+    }
+
+    /// Keeps a connected node which does not fit in the bucket as a replacement candidate,
+    /// dropping the least-recently seen candidate if there are too many.
+    fn add_replacement(&mut self, node: Node<TNodeId, TVal>) {
+        if self.max_replacements == 0 {
+            return;
         }
+        self.remove_replacement(&node.key);
+        if self.replacements.len() >= self.max_replacements {
+            self.replacements.pop_front();
+        }
+        self.replacements.push_back(node);
+    }
+
+    /// Removes a node from the replacement candidates. Returns `true` if it was a candidate.
+    pub fn remove_replacement(&mut self, key: &Key<TNodeId>) -> bool {
+        let len = self.replacements.len();
+        self.replacements.retain(|node| &node.key != key);
+        self.replacements.len() != len
+    }
+
+    /// Returns an iterator over the replacement candidates of the bucket, from the
+    /// least-recently to the most-recently seen.
+    pub fn replacements(&self) -> impl Iterator<Item = &Node<TNodeId, TVal>> {
+        self.replacements.iter()
+    }
+
+    /// Removes the node at the given position, adjusting `first_connected_pos` accordingly.
+    fn remove_at(&mut self, pos: Position) -> Node<TNodeId, TVal> {
+        let status = self.status(pos);
+        let node = self.nodes.remove(pos.0);
+        self.last_updated = Some(Instant::now());
+        match status {
+            NodeStatus::Connected => {
+                if self.first_connected_pos.map_or(false, |p| p == pos.0)
+                    && pos.0 == self.nodes.len()
+                {
+                    // It was the last connected node.
+                    self.first_connected_pos = None
+                }
+            }
+            NodeStatus::Disconnected => {
+                self.first_connected_pos = self.first_connected_pos.and_then(|p| p.checked_sub(1))
+            }
+        }
+        node
     }
 
     /// Inserts a new node into the bucket with the given status.
@@ -309,7 +419,8 @@ where
     /// The status of the node to insert determines the result as follows:
     ///
     ///   * `NodeStatus::Connected`: If the bucket is full and either all nodes are connected
-    ///     or there is already a pending node, insertion fails with `InsertResult::Full`.
+    ///     or there is already a pending node, insertion fails with `InsertResult::Full` and
+    ///     the node is kept as a replacement candidate.
     ///     If the bucket is full but at least one node is disconnected and there is no pending
     ///     node, the new node is inserted as pending, yielding `InsertResult::Pending`.
     ///     Otherwise the bucket has free slots and the new node is added to the end of the
@@ -328,8 +439,9 @@ where
     ) -> InsertResult<TNodeId> {
         match status {
             NodeStatus::Connected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
                     if self.first_connected_pos == Some(0) || self.pending.is_some() {
+                        self.add_replacement(node);
                         return InsertResult::Full;
                     } else {
                         self.pending = Some(PendingNode {
@@ -342,15 +454,19 @@ where
                         };
                     }
                 }
+                self.remove_replacement(&node.key);
+                self.last_updated = Some(Instant::now());
                 let pos = self.nodes.len();
                 self.first_connected_pos = self.first_connected_pos.or(Some(pos));
                 self.nodes.push(node);
                 InsertResult::Inserted
             }
             NodeStatus::Disconnected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
                     return InsertResult::Full;
                 }
+                self.remove_replacement(&node.key);
+                self.last_updated = Some(Instant::now());
                 if let Some(ref mut first_connected_pos) = self.first_connected_pos {
                     self.nodes.insert(*first_connected_pos, node);
                     *first_connected_pos += 1;
@@ -362,10 +478,37 @@ where
         }
     }
 
+    /// Moves the disconnected node with the lowest `score` to the front of a full bucket, making
+    /// it the node challenged, and evicted if unresponsive, when a new node is pending insertion.
+    /// The bucket is left unchanged while a node is already pending.
+    pub fn order_eviction_by(&mut self, score: impl Fn(&TVal) -> f64) {
+        if !self.is_full() || self.pending.is_some() {
+            return;
+        }
+        let num_disconnected = self.first_connected_pos.unwrap_or(self.nodes.len());
+        let lowest = self.nodes[..num_disconnected]
+            .iter()
+            .enumerate()
+            .map(|(pos, node)| (pos, score(&node.value)))
+            .fold(
+                None,
+                |lowest: Option<(usize, f64)>, (pos, score)| match lowest {
+                    Some((_, lowest_score)) if lowest_score <= score => lowest,
+                    _ => Some((pos, score)),
+                },
+            );
+        if let Some((pos, _)) = lowest {
+            if pos > 0 {
+                let node = self.nodes.remove(pos);
+                self.nodes.insert(0, node);
+            }
+        }
+    }
+
     /// Removes a node from the bucket.
     pub fn remove(&mut self, key: &Key<TNodeId>) -> bool {
         if let Some(position) = self.position(key) {
-            self.nodes.remove(position.0);
+            self.remove_at(position);
             true
         } else {
             false
@@ -381,6 +524,16 @@ where
         }
     }
 
+    /// Returns `true` if the bucket holds the maximum number of nodes.
+    pub fn is_full(&self) -> bool {
+        self.nodes.len() >= self.max_nodes
+    }
+
+    /// The last time a node was inserted into or removed from the bucket, or changed status.
+    pub fn last_updated(&self) -> Option<Instant> {
+        self.last_updated
+    }
+
     /// Gets the number of entries currently in the bucket.
     pub fn num_entries(&self) -> usize {
         self.nodes.len()
@@ -409,13 +562,22 @@ where
         self.nodes.iter_mut().find(move |p| &p.key == key)
     }
 
-    /// Checks if value can be inserted into the kbuckets table.
-    /// A single bucket can only have 2 nodes per /24 subnet
-    pub fn check(&self, value: &TVal, f: impl Fn(&TVal, Vec<&TVal>, usize) -> bool) -> bool {
+    /// Checks if key and value can be inserted into the bucket.
+    /// A single bucket can only have `max_nodes_per_subnet` nodes per subnet.
+    /// If the key is already in the bucket, its value is compared against the other nodes.
+    pub fn check(
+        &self,
+        key: &Key<TNodeId>,
+        value: &TVal,
+        f: impl Fn(&TVal, Vec<&TVal>, usize) -> bool,
+    ) -> bool {
         f(
             value,
-            self.iter().map(|(e, _)| &e.value).collect(),
-            MAX_NODES_PER_SUBNET_BUCKET,
+            self.iter()
+                .filter(|(e, _)| &e.key != key)
+                .map(|(e, _)| &e.value)
+                .collect(),
+            self.max_nodes_per_subnet,
         )
     }
 }
@@ -431,13 +593,19 @@ mod tests {
     impl Arbitrary for KBucket<NodeId, ()> {
         fn arbitrary<G: Gen>(g: &mut G) -> KBucket<NodeId, ()> {
             let timeout = Duration::from_secs(g.gen_range(1, g.size() as u64));
-            let mut bucket = KBucket::<NodeId, ()>::new(timeout);
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                timeout,
+                MAX_NODES_PER_BUCKET,
+                MAX_REPLACEMENTS_PER_BUCKET,
+                MAX_NODES_PER_SUBNET_BUCKET,
+            );
             let num_nodes = g.gen_range(1, MAX_NODES_PER_BUCKET + 1);
             for _ in 0..num_nodes {
                 let key = Key::from(NodeId::random());
                 let node = Node {
                     key: key.clone(),
                     value: (),
+                    last_seen: None,
                 };
                 let status = NodeStatus::arbitrary(g);
                 match bucket.insert(node, status) {
@@ -468,9 +636,13 @@ mod tests {
     // Fill a bucket with random nodes with the given status.
     fn fill_bucket(bucket: &mut KBucket<NodeId, ()>, status: NodeStatus) {
         let num_entries_start = bucket.num_entries();
-        for i in 0..MAX_NODES_PER_BUCKET - num_entries_start {
+        for i in 0..bucket.max_nodes - num_entries_start {
             let key = Key::from(NodeId::random());
-            let node = Node { key, value: () };
+            let node = Node {
+                key,
+                value: (),
+                last_seen: None,
+            };
             assert_eq!(InsertResult::Inserted, bucket.insert(node, status));
             assert_eq!(bucket.num_entries(), num_entries_start + i + 1);
         }
@@ -479,7 +651,12 @@ mod tests {
     #[test]
     fn ordering() {
         fn prop(status: Vec<NodeStatus>) -> bool {
-            let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1));
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(1),
+                MAX_NODES_PER_BUCKET,
+                MAX_REPLACEMENTS_PER_BUCKET,
+                MAX_NODES_PER_SUBNET_BUCKET,
+            );
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -491,6 +668,7 @@ mod tests {
                 let node = Node {
                     key: key.clone(),
                     value: (),
+                    last_seen: None,
                 };
                 let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
                 if let InsertResult::Inserted = bucket.insert(node, status) {
@@ -527,14 +705,23 @@ mod tests {
 
     #[test]
     fn full_bucket() {
-        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1));
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+        );
 
         // Fill the bucket with disconnected nodes.
         fill_bucket(&mut bucket, NodeStatus::Disconnected);
 
         // Trying to insert another disconnected node fails.
         let key = Key::from(NodeId::random());
-        let node = Node { key, value: () };
+        let node = Node {
+            key,
+            value: (),
+            last_seen: None,
+        };
         match bucket.insert(node, NodeStatus::Disconnected) {
             InsertResult::Full => {}
             x => panic!("{:?}", x),
@@ -552,6 +739,7 @@ mod tests {
             let node = Node {
                 key: key.clone(),
                 value: (),
+                last_seen: None,
             };
             match bucket.insert(node.clone(), NodeStatus::Connected) {
                 InsertResult::Pending { disconnected } => {
@@ -592,16 +780,71 @@ mod tests {
 
         // Trying to insert another connected node fails.
         let key = Key::from(NodeId::random());
-        let node = Node { key, value: () };
+        let node = Node {
+            key,
+            value: (),
+            last_seen: None,
+        };
         match bucket.insert(node, NodeStatus::Connected) {
             InsertResult::Full => {}
             x => panic!("{:?}", x),
         }
     }
 
+    #[test]
+    fn lowest_scored_node_is_evicted() {
+        let mut bucket = KBucket::<NodeId, usize>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+        );
+        let keys = (0..MAX_NODES_PER_BUCKET)
+            .map(|i| {
+                let key = Key::from(NodeId::random());
+                let node = Node {
+                    key: key.clone(),
+                    value: i,
+                    last_seen: None,
+                };
+                assert_eq!(
+                    bucket.insert(node, NodeStatus::Disconnected),
+                    InsertResult::Inserted
+                );
+                key
+            })
+            .collect::<Vec<_>>();
+
+        // the node scored lowest is challenged rather than the least-recently connected
+        bucket.order_eviction_by(|value| if *value == 7 { -1.0 } else { 0.0 });
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: MAX_NODES_PER_BUCKET,
+            last_seen: None,
+        };
+        match bucket.insert(node, NodeStatus::Connected) {
+            InsertResult::Pending { disconnected } => assert_eq!(disconnected, keys[7]),
+            x => panic!("{:?}", x),
+        }
+
+        // the order is kept while the node is pending
+        bucket.order_eviction_by(|value| if *value == 3 { -2.0 } else { 0.0 });
+        let pending = bucket.pending_mut().expect("No pending node.");
+        pending.set_ready_at(Instant::now() - Duration::from_secs(1));
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(applied.inserted, key);
+        assert_eq!(applied.evicted.unwrap().key, keys[7]);
+    }
+
     #[test]
     fn full_bucket_discard_pending() {
-        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1));
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_REPLACEMENTS_PER_BUCKET,
+            MAX_NODES_PER_SUBNET_BUCKET,
+        );
         fill_bucket(&mut bucket, NodeStatus::Disconnected);
         let (first, _) = bucket.iter().next().unwrap();
         let first_disconnected = first.clone();
@@ -611,6 +854,7 @@ mod tests {
         let node = Node {
             key: key.clone(),
             value: (),
+            last_seen: None,
         };
         if let InsertResult::Pending { disconnected } = bucket.insert(node, NodeStatus::Connected) {
             assert_eq!(&disconnected, &first_disconnected.key);
@@ -674,4 +918,84 @@ mod tests {
 
         quickcheck(prop as fn(_, _, _) -> _);
     }
+
+    #[test]
+    fn bucket_of_configured_size() {
+        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1), 4, 0, 1);
+        fill_bucket(&mut bucket, NodeStatus::Disconnected);
+        assert_eq!(4, bucket.num_entries());
+        assert!(bucket.is_full());
+
+        // A connected node waits for the least-recently connected node to be evicted.
+        let node = Node {
+            key: Key::from(NodeId::random()),
+            value: (),
+            last_seen: None,
+        };
+        match bucket.insert(node, NodeStatus::Connected) {
+            InsertResult::Pending { disconnected } => {
+                assert_eq!(disconnected, bucket.nodes[0].key)
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // The subnet limit of the bucket is handed to the check.
+        let key = Key::from(NodeId::random());
+        assert!(bucket.check(&key, &(), |_, others, limit| others.len() == 4
+            && limit == 1));
+    }
+
+    #[test]
+    fn failed_nodes_are_replaced_by_candidates() {
+        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1), 4, 2, 1);
+        fill_bucket(&mut bucket, NodeStatus::Connected);
+
+        // Connected nodes which don't fit are kept as candidates, the oldest being dropped.
+        let candidates = (0..3)
+            .map(|_| Node {
+                key: Key::from(NodeId::random()),
+                value: (),
+                last_seen: None,
+            })
+            .collect::<Vec<_>>();
+        for node in candidates.iter() {
+            assert_eq!(
+                InsertResult::Full,
+                bucket.insert(node.clone(), NodeStatus::Connected)
+            );
+        }
+        assert_eq!(
+            bucket.replacements().collect::<Vec<_>>(),
+            vec![&candidates[1], &candidates[2]]
+        );
+
+        // A failed node is replaced by the most recently seen candidate.
+        let failed = bucket.nodes[1].clone();
+        assert_eq!(
+            bucket.update(&failed.key, NodeStatus::Disconnected),
+            Some(AppliedPending {
+                inserted: candidates[2].key.clone(),
+                evicted: Some(failed),
+            })
+        );
+        // The candidate is only connected once it is heard from again.
+        assert_eq!(
+            Some((&candidates[2], NodeStatus::Disconnected)),
+            bucket.iter().next()
+        );
+        assert_eq!(3, bucket.num_connected());
+
+        // A removed node is replaced by the remaining candidate.
+        let removed = bucket.nodes[3].key.clone();
+        assert!(bucket.remove(&removed));
+        assert_eq!(2, bucket.num_connected());
+        assert!(bucket.promote_replacement().is_some());
+        assert_eq!(2, bucket.num_disconnected());
+        assert_eq!(bucket.replacements().count(), 0);
+
+        // Without candidates, a failed node stays in the bucket as disconnected.
+        let failed = bucket.nodes[3].key.clone();
+        assert_eq!(bucket.update(&failed, NodeStatus::Disconnected), None);
+        assert_eq!(3, bucket.num_disconnected());
+    }
 }
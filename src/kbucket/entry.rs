@@ -43,6 +43,7 @@ pub struct EntryRefView<'a, TPeerId, TVal> {
 pub struct NodeRefView<'a, TPeerId, TVal> {
     pub key: &'a Key<TPeerId>,
     pub value: &'a TVal,
+    pub last_seen: Option<SystemTime>,
 }
 
 /// A cloned, immutable view of an entry that is either present in a bucket
@@ -86,11 +87,16 @@ impl<'a, TPeerId, TVal> Entry<'a, TPeerId, TVal>
 where
     TPeerId: Clone,
 {
-    /// Creates a new `Entry` for a `Key`, encapsulating access to a bucket.
-    pub(super) fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
+    /// Creates a new `Entry` for a `Key`, encapsulating access to a bucket and to the
+    /// table's record of applied pending nodes.
+    pub(super) fn new(
+        bucket: &'a mut KBucket<TPeerId, TVal>,
+        applied_pending: &'a mut VecDeque<AppliedPending<TPeerId, TVal>>,
+        key: &'a Key<TPeerId>,
+    ) -> Self {
         if let Some(pos) = bucket.position(key) {
             let status = bucket.status(pos);
-            Entry::Present(PresentEntry::new(bucket, key), status)
+            Entry::Present(PresentEntry::new(bucket, applied_pending, key), status)
         } else if let Some(pending) = bucket.as_pending(key) {
             let status = pending.status();
             Entry::Pending(PendingEntry::new(bucket, key), status)
@@ -102,14 +108,21 @@ where
 
 /// An entry present in a bucket.
 #[derive(Debug)]
-pub struct PresentEntry<'a, TPeerId, TVal>(EntryRef<'a, TPeerId, TVal>);
+pub struct PresentEntry<'a, TPeerId, TVal>(
+    EntryRef<'a, TPeerId, TVal>,
+    &'a mut VecDeque<AppliedPending<TPeerId, TVal>>,
+);
 
 impl<'a, TPeerId, TVal> PresentEntry<'a, TPeerId, TVal>
 where
     TPeerId: Clone,
 {
-    fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
-        PresentEntry(EntryRef { bucket, key })
+    fn new(
+        bucket: &'a mut KBucket<TPeerId, TVal>,
+        applied_pending: &'a mut VecDeque<AppliedPending<TPeerId, TVal>>,
+        key: &'a Key<TPeerId>,
+    ) -> Self {
+        PresentEntry(EntryRef { bucket, key }, applied_pending)
     }
 
     /// Returns the value associated with the key.
@@ -122,10 +135,26 @@ where
             .value
     }
 
+    /// Returns the last time the node was known to be connected.
+    pub fn last_seen(&mut self) -> &mut Option<SystemTime> {
+        &mut self
+            .0
+            .bucket
+            .get_mut(self.0.key)
+            .expect("We can only build a ConnectedEntry if the entry is in the bucket; QED")
+            .last_seen
+    }
+
     /// Sets the status of the entry to `NodeStatus::Disconnected`.
-    pub fn update(self, status: NodeStatus) -> Self {
-        self.0.bucket.update(self.0.key, status);
-        Self::new(self.0.bucket, self.0.key)
+    ///
+    /// A connected entry becoming disconnected may be evicted in favour of a replacement
+    /// candidate of its bucket, which is recorded as an applied pending node. The returned
+    /// `Entry` reflects the state of the key after the update.
+    pub fn update(self, status: NodeStatus) -> Entry<'a, TPeerId, TVal> {
+        if let Some(applied) = self.0.bucket.update(self.0.key, status) {
+            self.1.push_back(applied);
+        }
+        Entry::new(self.0.bucket, self.1, self.0.key)
     }
 }
 
@@ -170,11 +199,48 @@ where
     }
 
     /// Attempts to insert the entry into a bucket.
+    ///
+    /// Nodes inserted as connected are recorded as having been seen now.
     pub fn insert(self, value: TVal, status: NodeStatus) -> InsertResult<TPeerId> {
+        let last_seen = if status == NodeStatus::Connected {
+            Some(SystemTime::now())
+        } else {
+            None
+        };
+        self.insert_with_last_seen(value, status, last_seen)
+    }
+
+    /// Attempts to insert the entry into a bucket. Should the bucket be full, the disconnected
+    /// node with the lowest `score` is the one challenged, and replaced by this entry if it
+    /// remains unresponsive.
+    pub fn insert_scored(
+        self,
+        value: TVal,
+        status: NodeStatus,
+        score: impl Fn(&TVal) -> f64,
+    ) -> InsertResult<TPeerId> {
+        self.0.bucket.order_eviction_by(score);
+        self.insert(value, status)
+    }
+
+    /// Removes the entry from the replacement candidates of its bucket, if it is one.
+    pub fn remove_replacement(self) -> bool {
+        self.0.bucket.remove_replacement(self.0.key)
+    }
+
+    /// Attempts to insert the entry into a bucket, with a known time at which the node was last
+    /// seen. This is used when restoring a previously persisted routing table.
+    pub fn insert_with_last_seen(
+        self,
+        value: TVal,
+        status: NodeStatus,
+        last_seen: Option<SystemTime>,
+    ) -> InsertResult<TPeerId> {
         self.0.bucket.insert(
             Node {
                 key: self.0.key.clone(),
                 value,
+                last_seen,
             },
             status,
         )
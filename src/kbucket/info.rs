@@ -0,0 +1,143 @@
+//! A structured view of the routing table, used to inspect its shape.
+//!
+//! Besides the contents of each bucket, the view counts the nodes sharing an IP address or a
+//! subnet across the whole table, which shows when a few hosts or networks dominate the table.
+
+use super::{KBucketsTable, NodeStatus};
+use crate::Enr;
+use enr::NodeId;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    time::{Instant, SystemTime},
+};
+
+/// A view of the routing table.
+#[derive(Debug, Clone)]
+pub struct TableInfo {
+    /// The buckets holding nodes, pending nodes or replacement candidates, ordered by
+    /// increasing distance.
+    pub buckets: Vec<BucketInfo>,
+    /// The number of nodes of the table advertising each IP address, ordered from the most to
+    /// the least common.
+    pub ips: Vec<(IpAddr, usize)>,
+    /// The number of nodes of the table advertising an IP address in each subnet, ordered from
+    /// the most to the least common. Subnets have the prefix lengths used to limit the nodes of
+    /// the table.
+    pub subnets: Vec<(IpNet, usize)>,
+}
+
+/// A view of a single bucket of the routing table.
+#[derive(Debug, Clone)]
+pub struct BucketInfo {
+    /// The log2 distance of the nodes in the bucket from the local node.
+    pub distance: u64,
+    /// The nodes in the bucket, ordered from the least-recently to the most-recently connected.
+    pub entries: Vec<EntryInfo>,
+    /// The node waiting to be inserted into the bucket, if any.
+    pub pending: Option<PendingInfo>,
+    /// The replacement candidates of the bucket, ordered from the least-recently to the
+    /// most-recently seen.
+    pub replacements: Vec<Enr>,
+    /// The last time a node was inserted into or removed from the bucket, or changed status.
+    pub last_updated: Option<Instant>,
+}
+
+/// A view of a node in a bucket.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    /// The ENR of the node.
+    pub enr: Enr,
+    /// The status of the node.
+    pub status: NodeStatus,
+    /// The last time the node was known to be connected, if ever.
+    pub last_seen: Option<SystemTime>,
+}
+
+/// A view of the node waiting to be inserted into a full bucket.
+#[derive(Debug, Clone)]
+pub struct PendingInfo {
+    /// The ENR of the node.
+    pub enr: Enr,
+    /// The status of the node.
+    pub status: NodeStatus,
+    /// The instant at which the node replaces the least-recently connected node of the bucket,
+    /// unless that node is found to be connected in the meantime.
+    pub replace_at: Instant,
+}
+
+impl TableInfo {
+    /// Takes a view of the routing table. The `ipv4_prefix` and `ipv6_prefix` lengths determine
+    /// the subnets the nodes are counted in.
+    pub fn of(table: &KBucketsTable<NodeId, Enr>, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        let buckets = table
+            .buckets()
+            .filter(|(_, bucket)| {
+                bucket.num_entries() > 0
+                    || bucket.pending().is_some()
+                    || bucket.replacements().next().is_some()
+            })
+            .map(|(distance, bucket)| BucketInfo {
+                distance,
+                entries: bucket
+                    .iter()
+                    .map(|(node, status)| EntryInfo {
+                        enr: node.value.clone(),
+                        status,
+                        last_seen: node.last_seen,
+                    })
+                    .collect(),
+                pending: bucket.pending().map(|pending| PendingInfo {
+                    enr: pending.node().value.clone(),
+                    status: pending.status(),
+                    replace_at: pending.ready_at(),
+                }),
+                replacements: bucket
+                    .replacements()
+                    .map(|node| node.value.clone())
+                    .collect(),
+                last_updated: bucket.last_updated(),
+            })
+            .collect();
+
+        let ips = table
+            .iter_ref()
+            .flat_map(|entry| {
+                let enr = entry.node.value;
+                let ip4 = enr.ip().map(IpAddr::V4);
+                let ip6 = enr.ip6().map(IpAddr::V6);
+                ip4.into_iter().chain(ip6)
+            })
+            .collect::<Vec<_>>();
+        let subnets = ips
+            .iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Ipv4Net::new(*ip, ipv4_prefix)
+                    .ok()
+                    .map(|net| IpNet::V4(net.trunc())),
+                IpAddr::V6(ip) => Ipv6Net::new(*ip, ipv6_prefix)
+                    .ok()
+                    .map(|net| IpNet::V6(net.trunc())),
+            })
+            .collect::<Vec<_>>();
+
+        TableInfo {
+            buckets,
+            ips: count(ips),
+            subnets: count(subnets),
+        }
+    }
+}
+
+/// Counts the occurrences of each item, ordered from the most to the least common.
+fn count<T: Hash + Ord>(items: Vec<T>) -> Vec<(T, usize)> {
+    let mut counts = HashMap::new();
+    for item in items {
+        *counts.entry(item).or_insert(0) += 1;
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    counts
+}
@@ -0,0 +1,172 @@
+//! Snapshots of the routing table, used to persist its contents across restarts.
+//!
+//! A snapshot is stored as an RLP list of entries, each consisting of the node's ENR, its
+//! `NodeStatus` and the time (in seconds since the UNIX epoch) at which it was last seen.
+
+use super::{KBucketsTable, NodeStatus};
+use crate::{Discv5Error, Enr};
+use enr::NodeId;
+use rlp::{DecoderError, Encodable, Rlp, RlpStream};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A single routing table entry of a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    /// The ENR of the node.
+    pub enr: Enr,
+    /// The status of the node at the time the snapshot was taken.
+    pub status: NodeStatus,
+    /// The last time the node was known to be connected, if ever.
+    pub last_seen: Option<SystemTime>,
+}
+
+impl Encodable for SnapshotEntry {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        let status: u8 = match self.status {
+            NodeStatus::Connected => 1,
+            NodeStatus::Disconnected => 0,
+        };
+        // a value of 0 indicates the node has never been seen
+        let last_seen = self
+            .last_seen
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+        s.begin_list(3);
+        s.append(&self.enr);
+        s.append(&status);
+        s.append(&last_seen);
+    }
+}
+
+impl rlp::Decodable for SnapshotEntry {
+    fn decode(rlp: &Rlp<'_>) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 3 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let enr = rlp.val_at::<Enr>(0)?;
+        let status = match rlp.val_at::<u8>(1)? {
+            0 => NodeStatus::Disconnected,
+            1 => NodeStatus::Connected,
+            _ => return Err(DecoderError::Custom("Invalid node status")),
+        };
+        let last_seen = match rlp.val_at::<u64>(2)? {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        Ok(SnapshotEntry {
+            enr,
+            status,
+            last_seen,
+        })
+    }
+}
+
+/// Takes a snapshot of the entries currently in the routing table. Pending entries are not
+/// included.
+pub fn snapshot(table: &KBucketsTable<NodeId, Enr>) -> Vec<SnapshotEntry> {
+    table
+        .iter_ref()
+        .map(|entry| SnapshotEntry {
+            enr: entry.node.value.clone(),
+            status: entry.status,
+            last_seen: entry.node.last_seen,
+        })
+        .collect()
+}
+
+/// Writes a snapshot to `path`. The snapshot is first written to a temporary file alongside
+/// `path`, so an interrupted write never leaves a truncated snapshot behind.
+pub fn write(path: &Path, entries: &[SnapshotEntry]) -> Result<(), Discv5Error> {
+    // append to the file name rather than replace its extension, so that snapshots differing only
+    // in their extension don't share a temporary file
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, rlp::encode_list(entries))?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads a snapshot previously written with [`write`].
+pub fn read(path: &Path) -> Result<Vec<SnapshotEntry>, Discv5Error> {
+    let data = std::fs::read(path)?;
+    Rlp::new(&data).as_list().map_err(Discv5Error::RLPError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, EnrBuilder};
+
+    fn entry(port: u16, status: NodeStatus, last_seen: Option<SystemTime>) -> SnapshotEntry {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = EnrBuilder::new("v4")
+            .ip("127.0.0.1".parse().unwrap())
+            .udp(port)
+            .build(&key)
+            .unwrap();
+        SnapshotEntry {
+            enr,
+            status,
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn encode_decode_snapshot() {
+        let last_seen = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let entries = vec![
+            entry(9000, NodeStatus::Connected, Some(last_seen)),
+            entry(9001, NodeStatus::Disconnected, None),
+        ];
+
+        let encoded = rlp::encode_list(&entries);
+        let decoded: Vec<SnapshotEntry> = Rlp::new(&encoded).as_list().unwrap();
+        assert_eq!(decoded, entries);
+    }
+
+    #[test]
+    fn write_read_snapshot() {
+        let path = std::env::temp_dir().join(format!("discv5-snapshot-{}", std::process::id()));
+        let entries = vec![entry(9002, NodeStatus::Disconnected, None)];
+
+        write(&path, &entries).unwrap();
+        let read_entries = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_entries, entries);
+    }
+
+    #[test]
+    fn snapshots_differing_in_extension_are_kept_apart() {
+        let path = std::env::temp_dir().join(format!("discv5-snapshot-{}", std::process::id()));
+        let first = path.with_extension("first");
+        let second = path.with_extension("second");
+        let first_entries = vec![entry(9004, NodeStatus::Connected, None)];
+        let second_entries = vec![entry(9005, NodeStatus::Disconnected, None)];
+
+        write(&first, &first_entries).unwrap();
+        write(&second, &second_entries).unwrap();
+        let read_first = read(&first).unwrap();
+        let read_second = read(&second).unwrap();
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!(read_first, first_entries);
+        assert_eq!(read_second, second_entries);
+        assert!(!path.with_extension("first.tmp").exists());
+    }
+
+    #[test]
+    fn invalid_snapshot_is_rejected() {
+        let encoded = {
+            let mut s = RlpStream::new_list(1);
+            s.begin_list(3);
+            s.append(&entry(9003, NodeStatus::Connected, None).enr);
+            s.append(&2u8);
+            s.append(&0u64);
+            s.out()
+        };
+        assert!(Rlp::new(&encoded).as_list::<SnapshotEntry>().is_err());
+    }
+}
@@ -37,9 +37,19 @@
 //!
 //!  ## Event Stream
 //!
-//!  The [`Discv5`] struct provides access to an event-stream which allows the user to listen to
-//!  [`Discv5Event`] that get generated from the underlying server. The stream can be obtained
-//!  from the [`Discv5::event_stream()`] function.
+//!  The [`Discv5`] struct provides access to event streams which allow the user to listen to
+//!  [`Discv5Event`] that get generated from the underlying server. A stream can be obtained
+//!  from the [`Discv5::event_stream()`] function. Any number of streams can be open at once and
+//!  each receives every event. Each stream has its own buffer; if a stream falls behind, events
+//!  are dropped for that stream only and the number dropped is reported as
+//!  [`EventStreamError::Lagged`].
+//!
+//!  ## Query Streams
+//!
+//!  Queries started with [`Discv5::find_node_stream()`] or
+//!  [`Discv5::find_node_predicate_stream()`] report each node as soon as it is found, along with
+//!  the number of peers contacted, waiting on and failed, as a [`QueryStream`] of [`QueryUpdate`]s.
+//!  Dropping the stream stops the query.
 //!
 //!  ## Runtimes
 //!
@@ -96,6 +106,7 @@
 //!
 //! [`Discv5`]: struct.Discv5.html
 //! [`Discv5Event`]: enum.Discv5Event.html
+//! [`EventStreamError::Lagged`]: enum.EventStreamError.html#variant.Lagged
 //! [`Discv5Config`]: config/struct.Discv5Config.html
 //! [`Discv5ConfigBuilder`]: config/struct.Discv5ConfigBuilder.html
 //! [Packet]: packet/enum.Packet.html
@@ -107,26 +118,35 @@ mod discv5;
 mod error;
 mod executor;
 pub mod handler;
+mod ipmode;
 mod kbucket;
 pub mod metrics;
 mod node_info;
 pub mod packet;
 pub mod permit_ban;
 mod query_pool;
+mod reputation;
 mod rpc;
 pub mod service;
 mod socket;
 
-#[macro_use]
-extern crate lazy_static;
-
 pub type Enr = enr::Enr<enr::CombinedKey>;
 
-pub use crate::discv5::{Discv5, Discv5Event};
+pub use crate::discv5::{Discv5, Discv5Event, EventStream, QueryHandle, QueryStream, QueryUpdate};
 pub use config::{Discv5Config, Discv5ConfigBuilder};
-pub use error::{Discv5Error, QueryError, RequestError};
+pub use error::{Discv5Error, EventStreamError, QueryError, RequestError};
 pub use executor::{Executor, TokioExecutor};
-pub use permit_ban::PermitBanList;
-pub use socket::{FilterConfig, FilterConfigBuilder};
+pub use kbucket::{BucketInfo, EntryInfo, NodeStatus, PendingInfo, TableInfo};
+pub use permit_ban::{Ban, BanReason, IpNet, PermitBanList};
+pub use query_pool::{QueryId, QueryStats};
+pub use rpc::RequestKind;
+pub use service::TalkRequest;
+pub use socket::{
+    memory::{MemoryNetwork, MemoryTransport, NetworkConditions},
+    FilterConfig, FilterConfigBuilder, ListenConfig, RateLimitAction, Transport,
+};
 // re-export the ENR crate
 pub use enr;
+// re-export the prometheus crate
+#[cfg(feature = "prometheus")]
+pub use prometheus;
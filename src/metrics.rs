@@ -1,69 +1,177 @@
+use crate::permit_ban::BanReason;
 use enr::NodeId;
-use parking_lot::RwLock;
+use parking_lot::Mutex;
+use rates::{RequestRate, RequestRates};
 use std::{
     collections::HashMap,
     net::IpAddr,
     sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
-lazy_static! {
-    pub static ref METRICS: InternalMetrics = InternalMetrics::default();
-}
+mod rates;
+
+#[cfg(feature = "prometheus")]
+mod exporter;
+#[cfg(feature = "prometheus")]
+pub use exporter::PrometheusMetrics;
 
-/// A collection of metrics used throughout the server.
+/// A collection of metrics used throughout the server. Each `Discv5` instance keeps its own set
+/// of metrics.
 pub struct InternalMetrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: AtomicUsize,
+    /// The number of nodes in the routing table, as of the last table maintenance.
+    pub table_entries: AtomicUsize,
+    /// The number of full routing table buckets, as of the last table maintenance.
+    pub full_buckets: AtomicUsize,
     /// The number of seconds to store received packets to taking a moving average over.
     pub moving_window: u64,
-    /// The number of unsolicited requests received per moving window.
-    pub unsolicited_requests_per_window: AtomicUsize,
-    /// The number of unsolicited requests per node per second taken as a moving average over the
-    /// `moving_window`.
-    pub requests_per_node_per_second: RwLock<HashMap<NodeId, f64>>,
-    /// The number of unsolicited requests per IP per second taken as a moving average over the
-    /// `moving_window`.
-    pub requests_per_ip_per_second: RwLock<HashMap<IpAddr, f64>>,
+    /// The rate of unsolicited requests received, averaged over the `moving_window`.
+    unsolicited_requests: Mutex<RequestRate>,
+    /// The rate of unsolicited requests per node, averaged over the `moving_window`.
+    requests_per_node: Mutex<RequestRates<NodeId>>,
+    /// The rate of unsolicited requests per IP, averaged over the `moving_window`.
+    requests_per_ip: Mutex<RequestRates<IpAddr>>,
+    /// The detailed metrics exported to Prometheus.
+    #[cfg(feature = "prometheus")]
+    pub prometheus: PrometheusMetrics,
 }
 
 impl Default for InternalMetrics {
     fn default() -> Self {
+        let moving_window = 5;
+        let window = Duration::from_secs(moving_window);
         InternalMetrics {
-            moving_window: 5,
+            moving_window,
             active_sessions: AtomicUsize::new(0),
-            unsolicited_requests_per_window: AtomicUsize::new(0),
-            requests_per_node_per_second: RwLock::new(HashMap::new()),
-            requests_per_ip_per_second: RwLock::new(HashMap::new()),
+            table_entries: AtomicUsize::new(0),
+            full_buckets: AtomicUsize::new(0),
+            unsolicited_requests: Mutex::new(RequestRate::new(Instant::now())),
+            requests_per_node: Mutex::new(RequestRates::new(window)),
+            requests_per_ip: Mutex::new(RequestRates::new(window)),
+            #[cfg(feature = "prometheus")]
+            prometheus: PrometheusMetrics::default(),
         }
     }
 }
 
+impl InternalMetrics {
+    /// Records an unsolicited request from `ip`.
+    pub(crate) fn unsolicited_request(&self, ip: IpAddr, now: Instant) {
+        self.unsolicited_requests
+            .lock()
+            .record(Duration::from_secs(self.moving_window), now);
+        self.requests_per_ip.lock().record(ip, now);
+    }
+
+    /// Records an unsolicited request from `node_id`.
+    pub(crate) fn node_request(&self, node_id: NodeId, now: Instant) {
+        self.requests_per_node.lock().record(node_id, now);
+    }
+
+    /// Records the number of nodes in the routing table and the number of full buckets.
+    pub(crate) fn table_updated(&self, entries: usize, full_buckets: usize) {
+        self.table_entries.store(entries, Ordering::Relaxed);
+        self.full_buckets.store(full_buckets, Ordering::Relaxed);
+    }
+}
+
+/// Recording of the detailed metrics. These are no-ops unless the `prometheus` feature is
+/// enabled.
+#[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+impl InternalMetrics {
+    /// Records an RPC message of type `msg_type` being sent.
+    pub(crate) fn message_sent(&self, msg_type: u8) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.message_sent(msg_type);
+    }
+
+    /// Records an RPC message of type `msg_type` being received.
+    pub(crate) fn message_received(&self, msg_type: u8) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.message_received(msg_type);
+    }
+
+    /// Records a session being established.
+    pub(crate) fn handshake_succeeded(&self) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.handshake_succeeded();
+    }
+
+    /// Records a handshake failing.
+    pub(crate) fn handshake_failed(&self, reason: &'static str) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.handshake_failed(reason);
+    }
+
+    /// Records a request timing out after all retries.
+    pub(crate) fn request_timed_out(&self) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.request_timed_out();
+    }
+
+    /// Records a TALK request being dropped as the queue of its protocol is full.
+    pub(crate) fn talk_request_dropped(&self) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.talk_request_dropped();
+    }
+
+    /// Records the duration and number of results of a completed query.
+    pub(crate) fn query_finished(&self, duration: Duration, results: usize) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.query_finished(duration, results);
+    }
+
+    /// Records an incoming packet being dropped by the packet filter.
+    pub(crate) fn packet_dropped(&self, reason: &'static str) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.packet_dropped(reason);
+    }
+
+    /// Records a node or IP being banned.
+    pub(crate) fn banned(&self, reason: BanReason) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.banned(reason.as_str());
+    }
+}
+
 #[derive(Clone, Debug)]
 /// The publicly accessible metrics that can be obtained from the Discv5 server.
 pub struct Metrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: usize,
-    /// The number of unsolicited requests received per second (averaged over a moving window).
+    /// The number of nodes in the routing table. This is measured on each round of table
+    /// maintenance.
+    pub table_entries: usize,
+    /// The number of routing table buckets that hold the maximum number of nodes. This is
+    /// measured on each round of table maintenance.
+    pub full_buckets: usize,
+    /// The number of unsolicited requests received per second (an exponential moving average
+    /// over the moving window).
     pub unsolicited_requests_per_second: f64,
-    /// The number of unsolicited requests per node per second (averaged over a moving window).
+    /// The number of unsolicited requests per node per second (an exponential moving average over
+    /// the moving window).
     pub requests_per_node_per_second: HashMap<NodeId, f64>,
-    /// The number of unsolicited requests per IP per second (averaged over a moving window).
+    /// The number of unsolicited requests per IP per second (an exponential moving average over
+    /// the moving window).
     pub requests_per_ip_per_second: HashMap<IpAddr, f64>,
 }
 
-impl From<&METRICS> for Metrics {
-    fn from(internal_metrics: &METRICS) -> Self {
+impl From<&InternalMetrics> for Metrics {
+    fn from(internal_metrics: &InternalMetrics) -> Self {
+        // the rates are only computed when requested
+        let now = Instant::now();
         Metrics {
             active_sessions: internal_metrics.active_sessions.load(Ordering::Relaxed),
+            table_entries: internal_metrics.table_entries.load(Ordering::Relaxed),
+            full_buckets: internal_metrics.full_buckets.load(Ordering::Relaxed),
             unsolicited_requests_per_second: internal_metrics
-                .unsolicited_requests_per_window
-                .load(Ordering::Relaxed) as f64
-                / internal_metrics.moving_window as f64,
-            requests_per_node_per_second: internal_metrics
-                .requests_per_node_per_second
-                .read()
-                .clone(),
-            requests_per_ip_per_second: internal_metrics.requests_per_ip_per_second.read().clone(),
+                .unsolicited_requests
+                .lock()
+                .per_second(Duration::from_secs(internal_metrics.moving_window), now),
+            requests_per_node_per_second: internal_metrics.requests_per_node.lock().per_second(now),
+            requests_per_ip_per_second: internal_metrics.requests_per_ip.lock().per_second(now),
         }
     }
 }
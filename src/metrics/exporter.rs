@@ -0,0 +1,299 @@
+//! A Prometheus registry exposing detailed metrics of a discv5 server.
+//!
+//! Each `Discv5` instance owns a separate registry, which can be scraped directly or merged
+//! into an application's own registry.
+
+use crate::{
+    kbucket::{KBucketsTable, Key},
+    permit_ban::Ban,
+    Enr, PermitBanList,
+};
+use enr::NodeId;
+use parking_lot::RwLock;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+use std::{sync::Arc, time::Duration};
+
+/// The histogram buckets, in seconds, used for query durations.
+const QUERY_DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// The histogram buckets used for the number of results returned by a query.
+const QUERY_RESULTS_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0];
+
+/// The Prometheus metrics of a single discv5 server.
+pub struct PrometheusMetrics {
+    /// The registry all metrics are registered with.
+    registry: Registry,
+    /// The number of messages sent, by message type.
+    messages_sent: IntCounterVec,
+    /// The number of messages received, by message type.
+    messages_received: IntCounterVec,
+    /// The number of sessions successfully established.
+    handshake_successes: IntCounter,
+    /// The number of failed handshakes, by reason.
+    handshake_failures: IntCounterVec,
+    /// The number of requests that timed out after all retries.
+    request_timeouts: IntCounter,
+    /// The number of TALK requests dropped as the queue of their protocol was full.
+    talk_request_drops: IntCounter,
+    /// The time taken for queries to complete.
+    query_duration: Histogram,
+    /// The number of results returned by each query.
+    query_results: Histogram,
+    /// The number of incoming packets dropped by the packet filter, by reason.
+    filter_drops: IntCounterVec,
+    /// The number of bans issued, by reason.
+    bans: IntCounterVec,
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let messages_sent = IntCounterVec::new(
+            Opts::new("discv5_messages_sent_total", "Messages sent, by type"),
+            &["message_type"],
+        )
+        .expect("Valid metric");
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "discv5_messages_received_total",
+                "Messages received, by type",
+            ),
+            &["message_type"],
+        )
+        .expect("Valid metric");
+        let handshake_successes = IntCounter::new(
+            "discv5_handshake_successes_total",
+            "Sessions successfully established",
+        )
+        .expect("Valid metric");
+        let handshake_failures = IntCounterVec::new(
+            Opts::new(
+                "discv5_handshake_failures_total",
+                "Failed handshakes, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("Valid metric");
+        let request_timeouts = IntCounter::new(
+            "discv5_request_timeouts_total",
+            "Requests that timed out after all retries",
+        )
+        .expect("Valid metric");
+        let talk_request_drops = IntCounter::new(
+            "discv5_talk_request_drops_total",
+            "TALK requests dropped as the queue of their protocol was full",
+        )
+        .expect("Valid metric");
+        let query_duration = Histogram::with_opts(
+            HistogramOpts::new("discv5_query_duration_seconds", "Time taken by queries")
+                .buckets(QUERY_DURATION_BUCKETS.to_vec()),
+        )
+        .expect("Valid metric");
+        let query_results = Histogram::with_opts(
+            HistogramOpts::new(
+                "discv5_query_results",
+                "Number of results returned per query",
+            )
+            .buckets(QUERY_RESULTS_BUCKETS.to_vec()),
+        )
+        .expect("Valid metric");
+        let filter_drops = IntCounterVec::new(
+            Opts::new(
+                "discv5_filter_drops_total",
+                "Incoming packets dropped by the packet filter, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("Valid metric");
+        let bans = IntCounterVec::new(
+            Opts::new("discv5_bans_total", "Bans issued, by reason"),
+            &["reason"],
+        )
+        .expect("Valid metric");
+
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(messages_sent.clone()),
+            Box::new(messages_received.clone()),
+            Box::new(handshake_successes.clone()),
+            Box::new(handshake_failures.clone()),
+            Box::new(request_timeouts.clone()),
+            Box::new(talk_request_drops.clone()),
+            Box::new(query_duration.clone()),
+            Box::new(query_results.clone()),
+            Box::new(filter_drops.clone()),
+            Box::new(bans.clone()),
+        ];
+        for collector in collectors {
+            registry
+                .register(collector)
+                .expect("Metric names are unique");
+        }
+
+        PrometheusMetrics {
+            registry,
+            messages_sent,
+            messages_received,
+            handshake_successes,
+            handshake_failures,
+            request_timeouts,
+            talk_request_drops,
+            query_duration,
+            query_results,
+            filter_drops,
+            bans,
+        }
+    }
+}
+
+impl PrometheusMetrics {
+    /// The registry holding all metrics of the server.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Registers the routing table and permit/ban list gauges, which are read on each scrape.
+    pub(crate) fn register_table(
+        &self,
+        local_id: NodeId,
+        kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        permit_ban_list: Arc<RwLock<PermitBanList>>,
+    ) {
+        let collector = TableCollector {
+            local_id,
+            kbuckets,
+            permit_ban_list,
+            bucket_size: IntGaugeVec::new(
+                Opts::new(
+                    "discv5_routing_table_bucket_size",
+                    "Entries in each routing table bucket, by log2 distance",
+                ),
+                &["distance"],
+            )
+            .expect("Valid metric"),
+            banned: IntGaugeVec::new(
+                Opts::new(
+                    "discv5_banned",
+                    "Currently banned node ids, IPs and subnets",
+                ),
+                &["kind"],
+            )
+            .expect("Valid metric"),
+        };
+        self.registry
+            .register(Box::new(collector))
+            .expect("Metric names are unique");
+    }
+
+    pub(crate) fn message_sent(&self, msg_type: u8) {
+        self.messages_sent
+            .with_label_values(&[message_name(msg_type)])
+            .inc();
+    }
+
+    pub(crate) fn message_received(&self, msg_type: u8) {
+        self.messages_received
+            .with_label_values(&[message_name(msg_type)])
+            .inc();
+    }
+
+    pub(crate) fn handshake_succeeded(&self) {
+        self.handshake_successes.inc();
+    }
+
+    pub(crate) fn handshake_failed(&self, reason: &str) {
+        self.handshake_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub(crate) fn request_timed_out(&self) {
+        self.request_timeouts.inc();
+    }
+
+    pub(crate) fn talk_request_dropped(&self) {
+        self.talk_request_drops.inc();
+    }
+
+    pub(crate) fn query_finished(&self, duration: Duration, results: usize) {
+        self.query_duration.observe(duration.as_secs_f64());
+        self.query_results.observe(results as f64);
+    }
+
+    pub(crate) fn packet_dropped(&self, reason: &str) {
+        self.filter_drops.with_label_values(&[reason]).inc();
+    }
+
+    pub(crate) fn banned(&self, reason: &str) {
+        self.bans.with_label_values(&[reason]).inc();
+    }
+}
+
+/// The label used for each RPC message type.
+fn message_name(msg_type: u8) -> &'static str {
+    match msg_type {
+        1 => "ping",
+        2 => "pong",
+        3 => "findnode",
+        4 => "nodes",
+        5 => "talkreq",
+        6 => "talkresp",
+        7 => "regtopic",
+        8 => "ticket",
+        9 => "regconfirmation",
+        10 => "topicquery",
+        11 => "relayinit",
+        12 => "relaymsg",
+        _ => "unknown",
+    }
+}
+
+/// The number of bans that have not yet lapsed.
+fn active_bans<'a>(bans: impl Iterator<Item = &'a Ban>) -> i64 {
+    bans.filter(|ban| !ban.is_expired()).count() as i64
+}
+
+/// Reports the routing table and permit/ban list sizes at the time of a scrape.
+struct TableCollector {
+    local_id: NodeId,
+    kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    permit_ban_list: Arc<RwLock<PermitBanList>>,
+    bucket_size: IntGaugeVec,
+    banned: IntGaugeVec,
+}
+
+impl Collector for TableCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut desc = self.bucket_size.desc();
+        desc.extend(self.banned.desc());
+        desc
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // only report the buckets that currently hold entries
+        self.bucket_size.reset();
+        let local_key: Key<NodeId> = self.local_id.into();
+        for entry in self.kbuckets.read().iter_ref() {
+            if let Some(distance) = entry.node.key.log2_distance(&local_key) {
+                self.bucket_size
+                    .with_label_values(&[&distance.to_string()])
+                    .inc();
+            }
+        }
+
+        let permit_ban_list = self.permit_ban_list.read();
+        self.banned
+            .with_label_values(&["node"])
+            .set(active_bans(permit_ban_list.ban_nodes.values()));
+        self.banned
+            .with_label_values(&["ip"])
+            .set(active_bans(permit_ban_list.ban_ips.values()));
+        self.banned
+            .with_label_values(&["subnet"])
+            .set(active_bans(permit_ban_list.ban_subnets.values()));
+
+        let mut families = self.bucket_size.collect();
+        families.extend(self.banned.collect());
+        families
+    }
+}
@@ -0,0 +1,128 @@
+//! Moving averages of request rates, updated in O(1) per request and read on demand.
+//!
+//! Rather than storing each request, a rate keeps an exponentially decaying count of requests
+//! whose time constant is the moving window. For a steady rate of requests, the count converges
+//! to the number of requests made within the window.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// The number of rates held before the first prune.
+const MIN_PRUNE_THRESHOLD: usize = 64;
+/// Rates whose decayed count falls below this are pruned.
+const NEGLIGIBLE_COUNT: f64 = 0.01;
+
+/// The request rate of a single source.
+#[derive(Debug, Clone)]
+pub struct RequestRate {
+    /// The decayed number of requests at `updated`.
+    count: f64,
+    /// The time of the last request.
+    updated: Instant,
+}
+
+impl RequestRate {
+    pub fn new(now: Instant) -> Self {
+        RequestRate {
+            count: 0.0,
+            updated: now,
+        }
+    }
+
+    /// Records a request at `now`.
+    pub fn record(&mut self, window: Duration, now: Instant) {
+        self.count = self.count_at(window, now) + 1.0;
+        self.updated = now;
+    }
+
+    /// The requests per second at `now`, averaged over `window`.
+    pub fn per_second(&self, window: Duration, now: Instant) -> f64 {
+        self.count_at(window, now) / window.as_secs_f64()
+    }
+
+    fn count_at(&self, window: Duration, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.count * (-elapsed / window.as_secs_f64()).exp()
+    }
+}
+
+/// The request rates of many sources, such as IPs or node ids.
+#[derive(Debug)]
+pub struct RequestRates<K> {
+    /// The window the rates are averaged over.
+    window: Duration,
+    /// The rates of sources that have recently made requests.
+    rates: HashMap<K, RequestRate>,
+    /// The number of rates at which the next prune occurs.
+    prune_threshold: usize,
+}
+
+impl<K: Eq + Hash + Clone> RequestRates<K> {
+    pub fn new(window: Duration) -> Self {
+        RequestRates {
+            window,
+            rates: HashMap::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Records a request by `key` at `now`.
+    pub fn record(&mut self, key: K, now: Instant) {
+        if self.rates.len() >= self.prune_threshold {
+            self.prune(now);
+        }
+        self.rates
+            .entry(key)
+            .or_insert_with(|| RequestRate::new(now))
+            .record(self.window, now);
+    }
+
+    /// The requests per second of each source at `now`, averaged over the window.
+    pub fn per_second(&self, now: Instant) -> HashMap<K, f64> {
+        self.rates
+            .iter()
+            .map(|(key, rate)| (key.clone(), rate.per_second(self.window, now)))
+            .collect()
+    }
+
+    /// Removes the rates that have decayed to a negligible count. The threshold of the next
+    /// prune doubles with the number of rates remaining, so pruning is amortized over requests.
+    fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.rates
+            .retain(|_, rate| rate.count_at(window, now) >= NEGLIGIBLE_COUNT);
+        self.prune_threshold = (self.rates.len() * 2).max(MIN_PRUNE_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_decay_and_are_pruned() {
+        let window = Duration::from_secs(5);
+        let mut rates = RequestRates::new(window);
+        let now = Instant::now();
+        for _ in 0..10 {
+            rates.record(1, now);
+        }
+        assert!((rates.per_second(now)[&1] - 2.0).abs() < 1e-9);
+
+        // after one window, the count has decayed by a factor of e
+        let later = now + window;
+        let expected = 2.0 / std::f64::consts::E;
+        assert!((rates.per_second(later)[&1] - expected).abs() < 1e-9);
+
+        // negligible rates are dropped once enough sources are tracked
+        let much_later = now + window * 10;
+        for key in 2..=MIN_PRUNE_THRESHOLD as u32 {
+            rates.record(key, much_later);
+        }
+        rates.record(0, much_later);
+        assert!(!rates.per_second(much_later).contains_key(&1));
+    }
+}
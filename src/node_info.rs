@@ -1,5 +1,5 @@
 use super::*;
-use crate::Enr;
+use crate::{ipmode::IpMode, Enr};
 use enr::{CombinedPublicKey, NodeId};
 use std::net::SocketAddr;
 
@@ -51,18 +51,19 @@ impl NodeContact {
         matches!(self, NodeContact::Enr(_))
     }
 
-    pub fn udp_socket(&self) -> Result<SocketAddr, &'static str> {
+    /// The socket to contact the node on, given the address families we listen on.
+    pub fn udp_socket(&self, ip_mode: IpMode) -> Result<SocketAddr, &'static str> {
         match self {
-            NodeContact::Enr(enr) => enr
-                .udp_socket()
-                .ok_or("ENR does not contain an IP and UDP port"),
+            NodeContact::Enr(enr) => ip_mode
+                .contactable_socket(enr)
+                .ok_or("ENR does not contain a contactable IP and UDP port"),
             NodeContact::Raw { node_address, .. } => Ok(node_address.socket_addr),
         }
     }
 
-    pub fn node_address(&self) -> Result<NodeAddress, &'static str> {
+    pub fn node_address(&self, ip_mode: IpMode) -> Result<NodeAddress, &'static str> {
         let node_id = self.node_id();
-        let socket_addr = self.udp_socket()?;
+        let socket_addr = self.udp_socket(ip_mode)?;
         Ok(NodeAddress {
             node_id,
             socket_addr,
@@ -138,7 +139,8 @@ impl std::fmt::Display for NodeContact {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             NodeContact::Enr(enr) => {
-                write!(f, "Node: {}, addr: {:?}", enr.node_id(), enr.udp_socket())
+                let addr = enr.udp_socket().or_else(|| enr.udp6_socket());
+                write!(f, "Node: {}, addr: {:?}", enr.node_id(), addr)
             }
             NodeContact::Raw { node_address, .. } => write!(f, "{}", node_address),
         }
@@ -1,33 +1,217 @@
-use crate::node_info::NodeAddress;
+use crate::{node_info::NodeAddress, Enr};
 use enr::NodeId;
-use std::{collections::HashSet, net::IpAddr};
+pub use ipnet::IpNet;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// The reason a node or IP was banned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BanReason {
+    /// The node exceeded its share of the unsolicited request limit.
+    RateLimit,
+    /// The node responded with ENRs that did not match the request.
+    InvalidEnr,
+    /// The node's reputation score fell below the ban threshold.
+    LowReputation,
+    /// The ban was issued by the application.
+    Manual,
+}
+
+impl BanReason {
+    /// The label used for this reason in metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanReason::RateLimit => "rate_limit",
+            BanReason::InvalidEnr => "invalid_enr",
+            BanReason::LowReputation => "low_reputation",
+            BanReason::Manual => "manual",
+        }
+    }
+}
+
+impl std::fmt::Display for BanReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A ban of a node or IP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    /// Why the ban was issued.
+    pub reason: BanReason,
+    /// When the ban lapses. `None` if the ban is permanent.
+    pub expires: Option<Instant>,
+}
+
+impl Ban {
+    /// Creates a ban lasting `duration`, or a permanent ban if `duration` is `None`.
+    pub fn new(reason: BanReason, duration: Option<Duration>) -> Self {
+        Ban {
+            reason,
+            expires: duration.map(|duration| Instant::now() + duration),
+        }
+    }
+
+    /// Whether the ban has lapsed.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= Instant::now())
+    }
+
+    /// The time left before the ban lapses. `None` if the ban is permanent.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires
+            .map(|expires| expires.saturating_duration_since(Instant::now()))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PermitBanList {
     /// A set of IPs which pass all filters.
     pub permit_ips: HashSet<IpAddr>,
-    /// A set of IPs whose packets get dropped instantly.
-    pub ban_ips: HashSet<IpAddr>,
+    /// IPs whose packets get dropped instantly, with their bans.
+    pub ban_ips: HashMap<IpAddr, Ban>,
+    /// IPv4 and IPv6 subnets whose IPs pass all filters.
+    pub permit_subnets: HashSet<IpNet>,
+    /// IPv4 and IPv6 subnets whose IPs are banned, with their bans.
+    pub ban_subnets: HashMap<IpNet, Ban>,
     /// A set of NodeIds which pass all filters.
     pub permit_nodes: HashSet<NodeId>,
-    /// A set of NodeIds whose packets get dropped instantly.
-    pub ban_nodes: HashSet<NodeId>,
+    /// NodeIds whose packets get dropped instantly, with their bans.
+    pub ban_nodes: HashMap<NodeId, Ban>,
 }
 
 impl Default for PermitBanList {
     fn default() -> Self {
         PermitBanList {
             permit_ips: HashSet::new(),
-            ban_ips: HashSet::new(),
+            ban_ips: HashMap::new(),
+            permit_subnets: HashSet::new(),
+            ban_subnets: HashMap::new(),
             permit_nodes: HashSet::new(),
-            ban_nodes: HashSet::new(),
+            ban_nodes: HashMap::new(),
         }
     }
 }
 
 impl PermitBanList {
-    pub fn ban(&mut self, node_address: NodeAddress) {
-        self.ban_ips.insert(node_address.socket_addr.ip());
-        self.ban_nodes.insert(node_address.node_id);
+    /// Bans both the node id and the IP of a node. A `duration` of `None` bans them permanently.
+    pub fn ban(
+        &mut self,
+        node_address: NodeAddress,
+        reason: BanReason,
+        duration: Option<Duration>,
+    ) {
+        self.ban_ip(node_address.socket_addr.ip(), reason, duration);
+        self.ban_node(node_address.node_id, reason, duration);
+    }
+
+    /// Bans a node id. A `duration` of `None` bans it permanently.
+    pub fn ban_node(&mut self, node_id: NodeId, reason: BanReason, duration: Option<Duration>) {
+        self.ban_nodes.insert(node_id, Ban::new(reason, duration));
+    }
+
+    /// Bans an IP. A `duration` of `None` bans it permanently.
+    pub fn ban_ip(&mut self, ip: IpAddr, reason: BanReason, duration: Option<Duration>) {
+        self.ban_ips.insert(ip, Ban::new(reason, duration));
+    }
+
+    /// Bans every IP of a subnet. A `duration` of `None` bans it permanently.
+    pub fn ban_subnet(&mut self, subnet: IpNet, reason: BanReason, duration: Option<Duration>) {
+        self.ban_subnets
+            .insert(subnet.trunc(), Ban::new(reason, duration));
+    }
+
+    /// Whether an IP is permitted, either directly or by one of the permitted subnets.
+    pub fn is_ip_permitted(&self, ip: &IpAddr) -> bool {
+        self.permit_ips.contains(ip) || self.permit_subnets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Whether a node id is currently banned.
+    pub fn is_node_banned(&self, node_id: &NodeId) -> bool {
+        matches!(self.ban_nodes.get(node_id), Some(ban) if !ban.is_expired())
+    }
+
+    /// Whether an IP is currently banned, either directly or by one of the banned subnets.
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        matches!(self.ban_ips.get(ip), Some(ban) if !ban.is_expired())
+            || self
+                .ban_subnets
+                .iter()
+                .any(|(net, ban)| net.contains(ip) && !ban.is_expired())
+    }
+
+    /// Whether a node should be kept out of the routing table, as either its node id or an IP
+    /// it advertises is banned. Permitted IPs are never considered banned.
+    pub(crate) fn is_enr_banned(&self, enr: &Enr) -> bool {
+        let ip_banned = |ip: IpAddr| !self.is_ip_permitted(&ip) && self.is_ip_banned(&ip);
+        self.is_node_banned(&enr.node_id())
+            || matches!(enr.ip(), Some(ip) if ip_banned(ip.into()))
+            || matches!(enr.ip6(), Some(ip) if ip_banned(ip.into()))
+    }
+
+    /// Removes all bans that have lapsed.
+    pub fn remove_expired(&mut self) {
+        self.ban_nodes.retain(|_, ban| !ban.is_expired());
+        self.ban_ips.retain(|_, ban| !ban.is_expired());
+        self.ban_subnets.retain(|_, ban| !ban.is_expired());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire() {
+        let mut list = PermitBanList::default();
+        let permanent = NodeId::random();
+        let temporary = NodeId::random();
+        let lapsed = NodeId::random();
+        list.ban_node(permanent, BanReason::Manual, None);
+        list.ban_node(
+            temporary,
+            BanReason::RateLimit,
+            Some(Duration::from_secs(60)),
+        );
+        list.ban_node(lapsed, BanReason::InvalidEnr, Some(Duration::from_secs(0)));
+
+        assert!(list.is_node_banned(&permanent));
+        assert!(list.is_node_banned(&temporary));
+        assert!(!list.is_node_banned(&lapsed));
+        assert_eq!(list.ban_nodes[&permanent].remaining(), None);
+        assert!(list.ban_nodes[&temporary].remaining().unwrap() <= Duration::from_secs(60));
+
+        list.remove_expired();
+        assert_eq!(list.ban_nodes.len(), 2);
+        assert!(!list.ban_nodes.contains_key(&lapsed));
+    }
+
+    #[test]
+    fn subnet_rules_match_contained_ips() {
+        let mut list = PermitBanList::default();
+        list.ban_subnet("192.0.2.7/24".parse().unwrap(), BanReason::Manual, None);
+        list.ban_subnet("2001:db8::/32".parse().unwrap(), BanReason::Manual, None);
+        list.permit_subnets.insert("10.0.0.0/8".parse().unwrap());
+
+        assert!(list.is_ip_banned(&"192.0.2.200".parse().unwrap()));
+        assert!(!list.is_ip_banned(&"192.0.3.1".parse().unwrap()));
+        assert!(list.is_ip_banned(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!list.is_ip_banned(&"2001:db9::1".parse().unwrap()));
+        assert!(list.is_ip_permitted(&"10.1.2.3".parse().unwrap()));
+        assert!(!list.is_ip_permitted(&"11.1.2.3".parse().unwrap()));
+
+        let key = enr::CombinedKey::generate_secp256k1();
+        let enr = enr::EnrBuilder::new("v4")
+            .ip("192.0.2.1".parse().unwrap())
+            .udp(9000)
+            .build(&key)
+            .unwrap();
+        assert!(list.is_enr_banned(&enr));
+        list.permit_ips.insert("192.0.2.1".parse().unwrap());
+        assert!(!list.is_enr_banned(&enr));
     }
 }
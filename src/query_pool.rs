@@ -23,11 +23,12 @@
 
 mod peers;
 
-pub use peers::QueryState;
 pub(crate) use peers::{
     closest::{FindNodeQuery, FindNodeQueryConfig},
+    disjoint::DisjointQuery,
     predicate::{PredicateQuery, PredicateQueryConfig},
 };
+pub use peers::{QueryState, QueryStats};
 
 use crate::kbucket::{Key, PredicateKey};
 use fnv::FnvHashMap;
@@ -98,6 +99,24 @@ where
         self.add(peer_iter, target)
     }
 
+    /// Adds a query to the pool that iterates towards the closest peers to the target along
+    /// `paths` disjoint paths.
+    pub(crate) fn add_disjoint_query<I>(
+        &mut self,
+        config: FindNodeQueryConfig,
+        paths: usize,
+        target: TTarget,
+        peers: I,
+    ) -> QueryId
+    where
+        I: IntoIterator<Item = Key<TNodeId>>,
+    {
+        let target_key = target.key();
+        let disjoint_query = DisjointQuery::with_config(config, paths, target_key, peers);
+        let peer_iter = QueryPeerIter::Disjoint(disjoint_query);
+        self.add(peer_iter, target)
+    }
+
     /// Adds a query to the pool that returns peers that satisfy a predicate.
     pub(crate) fn add_predicate_query<I>(
         &mut self,
@@ -128,6 +147,11 @@ where
         self.queries.get_mut(&id)
     }
 
+    /// Removes a query from the pool, stopping it.
+    pub fn remove(&mut self, id: QueryId) -> Option<Query<TTarget, TNodeId, TResult>> {
+        self.queries.remove(&id)
+    }
+
     /// Polls the pool to advance the queries.
     pub fn poll(&mut self) -> QueryPoolState<'_, TTarget, TNodeId, TResult> {
         let now = Instant::now();
@@ -206,6 +230,7 @@ pub struct Query<TTarget, TNodeId, TResult> {
 /// The peer selection strategies that can be used by queries.
 enum QueryPeerIter<TNodeId, TResult> {
     FindNode(FindNodeQuery<TNodeId>),
+    Disjoint(DisjointQuery<TNodeId>),
     Predicate(PredicateQuery<TNodeId, TResult>),
 }
 
@@ -230,10 +255,16 @@ where
         self.id
     }
 
+    /// Gets the instant the query started, if it has been started.
+    pub fn started(&self) -> Option<Instant> {
+        self.started
+    }
+
     /// Informs the query that the attempt to contact `peer` failed.
     pub fn on_failure(&mut self, peer: &TNodeId) {
         match &mut self.peer_iter {
             QueryPeerIter::FindNode(iter) => iter.on_failure(&peer),
+            QueryPeerIter::Disjoint(iter) => iter.on_failure(peer),
             QueryPeerIter::Predicate(iter) => iter.on_failure(&peer),
         }
     }
@@ -249,14 +280,37 @@ where
             QueryPeerIter::FindNode(iter) => {
                 iter.on_success(peer, new_peers.iter().map(|result| result.into()).collect())
             }
+            QueryPeerIter::Disjoint(iter) => {
+                iter.on_success(peer, new_peers.iter().map(|result| result.into()).collect())
+            }
             QueryPeerIter::Predicate(iter) => iter.on_success(peer, new_peers),
         }
     }
 
+    /// Returns the number of peers of the query in each state.
+    pub fn stats(&self) -> QueryStats {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.stats(),
+            QueryPeerIter::Disjoint(iter) => iter.stats(),
+            QueryPeerIter::Predicate(iter) => iter.stats(),
+        }
+    }
+
+    /// Whether a result is one the query is looking for. All results match a query that iterates
+    /// towards the closest peers, whereas a predicate query only wants results that satisfy its
+    /// predicate.
+    pub fn matches(&self, result: &TResult) -> bool {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(_) | QueryPeerIter::Disjoint(_) => true,
+            QueryPeerIter::Predicate(iter) => iter.matches(result),
+        }
+    }
+
     /// Advances the state of the underlying peer iterator.
     fn next(&mut self, now: Instant) -> QueryState<TNodeId> {
         match &mut self.peer_iter {
             QueryPeerIter::FindNode(iter) => iter.next(now),
+            QueryPeerIter::Disjoint(iter) => iter.next(now),
             QueryPeerIter::Predicate(iter) => iter.next(now),
         }
     }
@@ -265,6 +319,7 @@ where
     pub fn into_result(self) -> QueryResult<TTarget, impl Iterator<Item = TNodeId>> {
         let peers = match self.peer_iter {
             QueryPeerIter::FindNode(iter) => iter.into_result(),
+            QueryPeerIter::Disjoint(iter) => iter.into_result(),
             QueryPeerIter::Predicate(iter) => iter.into_result(),
         };
         QueryResult {
@@ -42,6 +42,7 @@
 //! [`Finished`]: peers::PeersIterState::Finished
 
 pub mod closest;
+pub mod disjoint;
 pub mod predicate;
 
 /// The state of the query reported by [`Query::next`].
@@ -65,3 +66,25 @@ pub enum QueryState<TNodeId> {
     /// The query finished.
     Finished,
 }
+
+/// The number of peers of a query in each state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Peers that are known to the query but have not yet been contacted.
+    pub not_contacted: usize,
+    /// Peers the query is waiting on for a result.
+    pub waiting: usize,
+    /// Peers that did not deliver a result within the peer timeout.
+    pub unresponsive: usize,
+    /// Peers for which a request failed.
+    pub failed: usize,
+    /// Peers that delivered a result.
+    pub succeeded: usize,
+}
+
+impl QueryStats {
+    /// The number of peers that have been contacted by the query.
+    pub fn contacted(&self) -> usize {
+        self.waiting + self.unresponsive + self.failed + self.succeeded
+    }
+}
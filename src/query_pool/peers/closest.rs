@@ -24,7 +24,7 @@
 use super::*;
 use crate::{
     config::Discv5Config,
-    kbucket::{Distance, Key, MAX_NODES_PER_BUCKET},
+    kbucket::{Distance, Key},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
@@ -80,7 +80,7 @@ impl FindNodeQueryConfig {
     pub fn new_from_config(config: &Discv5Config) -> Self {
         Self {
             parallelism: config.query_parallelism,
-            num_results: MAX_NODES_PER_BUCKET,
+            num_results: config.kbucket_size,
             peer_timeout: config.query_peer_timeout,
         }
     }
@@ -323,6 +323,21 @@ where
         }
     }
 
+    /// Returns the number of peers of the query in each state.
+    pub fn stats(&self) -> QueryStats {
+        let mut stats = QueryStats::default();
+        for peer in self.closest_peers.values() {
+            match peer.state {
+                QueryPeerState::NotContacted => stats.not_contacted += 1,
+                QueryPeerState::Waiting(_) => stats.waiting += 1,
+                QueryPeerState::Unresponsive => stats.unresponsive += 1,
+                QueryPeerState::Failed => stats.failed += 1,
+                QueryPeerState::Succeeded => stats.succeeded += 1,
+            }
+        }
+        stats
+    }
+
     /// Consumes the query, returning the target and the closest peers.
     pub fn into_result(self) -> Vec<TNodeId> {
         self.closest_peers
@@ -0,0 +1,247 @@
+//! A lookup over disjoint paths, as described in the S/Kademlia paper.
+//!
+//! The initial peers are split between `d` iterative lookups, the paths, each of which is a
+//! [`FindNodeQuery`]. A peer is only ever contacted by the first path to select it; when another
+//! path selects the same peer, that path treats the peer as failed. Each response therefore
+//! only steers the path that requested it, so a malicious peer returning fake close nodes can
+//! capture at most the paths it is reached by. The results of all paths are merged at the end.
+
+use super::{
+    closest::{FindNodeQuery, FindNodeQueryConfig},
+    *,
+};
+use crate::kbucket::{Distance, Key};
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    time::Instant,
+};
+
+#[derive(Debug, Clone)]
+pub struct DisjointQuery<TNodeId> {
+    /// The target key we are looking for.
+    target_key: Key<TNodeId>,
+
+    /// The lookups making up the query.
+    paths: Vec<FindNodeQuery<TNodeId>>,
+
+    /// The path that contacted each peer, keyed by the distance of the peer to the target.
+    contacted: BTreeMap<Distance, usize>,
+
+    /// The path asked for the next peer to contact first, so that the paths take turns.
+    next_path: usize,
+
+    /// The number of results to produce.
+    num_results: usize,
+}
+
+impl<TNodeId> DisjointQuery<TNodeId>
+where
+    TNodeId: Into<Key<TNodeId>> + Eq + Clone,
+{
+    /// Creates a new query of `num_paths` paths, each with the given configuration. The known
+    /// closest peers are dealt to the paths in turn.
+    pub fn with_config<I>(
+        config: FindNodeQueryConfig,
+        num_paths: usize,
+        target_key: Key<TNodeId>,
+        known_closest_peers: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = Key<TNodeId>>,
+    {
+        let num_paths = num_paths.max(1);
+        let mut path_peers = vec![Vec::new(); num_paths];
+        for (i, key) in known_closest_peers
+            .into_iter()
+            .take(config.num_results)
+            .enumerate()
+        {
+            path_peers[i % num_paths].push(key);
+        }
+
+        DisjointQuery {
+            num_results: config.num_results,
+            paths: path_peers
+                .into_iter()
+                .map(|peers| FindNodeQuery::with_config(config.clone(), target_key.clone(), peers))
+                .collect(),
+            target_key,
+            contacted: BTreeMap::new(),
+            next_path: 0,
+        }
+    }
+
+    /// Callback for delivering the result of a successful request to a peer. The result is
+    /// delivered to the path that contacted the peer.
+    pub fn on_success(&mut self, node_id: &TNodeId, closer_peers: Vec<TNodeId>) {
+        if let Some(path) = self.path_of(node_id) {
+            self.paths[path].on_success(node_id, closer_peers);
+        }
+    }
+
+    /// Callback for informing the query about a failed request to a peer. The failure is
+    /// delivered to the path that contacted the peer.
+    pub fn on_failure(&mut self, node_id: &TNodeId) {
+        if let Some(path) = self.path_of(node_id) {
+            self.paths[path].on_failure(node_id);
+        }
+    }
+
+    /// Advances the state of the query, potentially getting a new peer to contact from one of
+    /// the paths.
+    ///
+    /// The query finishes once all of its paths have finished.
+    pub fn next(&mut self, now: Instant) -> QueryState<TNodeId> {
+        let mut finished = true;
+        let mut at_capacity = true;
+
+        let num_paths = self.paths.len();
+        for i in 0..num_paths {
+            let path = (self.next_path + i) % num_paths;
+            loop {
+                match self.paths[path].next(now) {
+                    QueryState::Waiting(Some(peer)) => {
+                        let key: Key<TNodeId> = peer.clone().into();
+                        match self.contacted.entry(key.distance(&self.target_key)) {
+                            Entry::Vacant(entry) => {
+                                entry.insert(path);
+                                self.next_path = (path + 1) % num_paths;
+                                return QueryState::Waiting(Some(peer));
+                            }
+                            Entry::Occupied(_) => {
+                                // the peer belongs to another path, so it can't be used by this
+                                // one
+                                self.paths[path].on_failure(&peer);
+                            }
+                        }
+                    }
+                    QueryState::Waiting(None) => {
+                        finished = false;
+                        at_capacity = false;
+                        break;
+                    }
+                    QueryState::WaitingAtCapacity => {
+                        finished = false;
+                        break;
+                    }
+                    QueryState::Finished => break,
+                }
+            }
+        }
+
+        if finished {
+            QueryState::Finished
+        } else if at_capacity {
+            QueryState::WaitingAtCapacity
+        } else {
+            QueryState::Waiting(None)
+        }
+    }
+
+    /// Returns the number of peers of the query in each state, summed over its paths. A peer
+    /// known to several paths is counted once for each of them.
+    pub fn stats(&self) -> QueryStats {
+        self.paths
+            .iter()
+            .map(|path| path.stats())
+            .fold(QueryStats::default(), |total, stats| QueryStats {
+                not_contacted: total.not_contacted + stats.not_contacted,
+                waiting: total.waiting + stats.waiting,
+                unresponsive: total.unresponsive + stats.unresponsive,
+                failed: total.failed + stats.failed,
+                succeeded: total.succeeded + stats.succeeded,
+            })
+    }
+
+    /// Consumes the query, returning the closest peers found by any of its paths.
+    pub fn into_result(self) -> Vec<TNodeId> {
+        let target_key = self.target_key;
+        self.paths
+            .into_iter()
+            .flat_map(|path| path.into_result())
+            .map(|peer| {
+                let key: Key<TNodeId> = peer.clone().into();
+                (key.distance(&target_key), peer)
+            })
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .take(self.num_results)
+            .collect()
+    }
+
+    /// Returns the path that contacted a peer.
+    fn path_of(&self, node_id: &TNodeId) -> Option<usize> {
+        let key: Key<TNodeId> = node_id.clone().into();
+        self.contacted.get(&key.distance(&self.target_key)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::NodeId;
+    use std::{collections::HashSet, time::Duration};
+
+    fn config() -> FindNodeQueryConfig {
+        FindNodeQueryConfig {
+            parallelism: 3,
+            num_results: 16,
+            peer_timeout: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn initial_peers_are_split_between_paths() {
+        let target = NodeId::random();
+        let peers = (0..6).map(|_| NodeId::random()).collect::<Vec<_>>();
+        let mut query = DisjointQuery::with_config(
+            config(),
+            3,
+            target.into(),
+            peers.iter().cloned().map(Key::from),
+        );
+        assert_eq!(query.paths.len(), 3);
+        for path in query.paths.iter() {
+            assert_eq!(path.stats().not_contacted, 2);
+        }
+
+        // every peer is contacted exactly once
+        let now = Instant::now();
+        let mut contacted = HashSet::new();
+        while let QueryState::Waiting(Some(peer)) = query.next(now) {
+            assert!(contacted.insert(peer));
+        }
+        assert_eq!(contacted, peers.into_iter().collect());
+    }
+
+    #[test]
+    fn peers_are_contacted_by_a_single_path() {
+        let target = NodeId::random();
+        let (first, second) = (NodeId::random(), NodeId::random());
+        let mut query = DisjointQuery::with_config(
+            config(),
+            2,
+            target.into(),
+            vec![Key::from(first), Key::from(second)],
+        );
+        let now = Instant::now();
+        assert_eq!(query.next(now), QueryState::Waiting(Some(first)));
+        assert_eq!(query.next(now), QueryState::Waiting(Some(second)));
+
+        // both paths learn of the same peer, which only the first path contacts
+        let shared = NodeId::random();
+        query.on_success(&first, vec![shared]);
+        query.on_success(&second, vec![shared]);
+        assert_eq!(query.next(now), QueryState::Waiting(Some(shared)));
+        assert_eq!(query.next(now), QueryState::Waiting(None));
+        query.on_success(&shared, vec![]);
+        assert_eq!(query.next(now), QueryState::Finished);
+        assert_eq!(query.stats().failed, 1);
+
+        // the results of the paths are merged, ordered by distance to the target
+        let target_key = Key::from(target);
+        let mut expected = vec![first, second, shared];
+        expected.sort_by_key(|peer| Key::from(*peer).distance(&target_key));
+        assert_eq!(query.into_result(), expected);
+    }
+}
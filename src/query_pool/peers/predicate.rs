@@ -1,7 +1,7 @@
 use super::*;
 use crate::{
     config::Discv5Config,
-    kbucket::{Distance, Key, PredicateKey, MAX_NODES_PER_BUCKET},
+    kbucket::{Distance, Key, PredicateKey},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
@@ -59,7 +59,7 @@ impl PredicateQueryConfig {
     pub(crate) fn new_from_config(config: &Discv5Config) -> Self {
         Self {
             parallelism: config.query_parallelism,
-            num_results: MAX_NODES_PER_BUCKET,
+            num_results: config.kbucket_size,
             peer_timeout: config.query_peer_timeout,
         }
     }
@@ -313,6 +313,26 @@ where
         }
     }
 
+    /// Returns the number of peers of the query in each state.
+    pub fn stats(&self) -> QueryStats {
+        let mut stats = QueryStats::default();
+        for peer in self.closest_peers.values() {
+            match peer.state {
+                QueryPeerState::NotContacted => stats.not_contacted += 1,
+                QueryPeerState::Waiting(_) => stats.waiting += 1,
+                QueryPeerState::Unresponsive => stats.unresponsive += 1,
+                QueryPeerState::Failed => stats.failed += 1,
+                QueryPeerState::Succeeded => stats.succeeded += 1,
+            }
+        }
+        stats
+    }
+
+    /// Whether a result satisfies the predicate of the query.
+    pub fn matches(&self, result: &TResult) -> bool {
+        (self.predicate)(result)
+    }
+
     /// Consumes the query, returning the peers who match the predicate.
     pub fn into_result(self) -> Vec<TNodeId> {
         self.closest_peers
@@ -0,0 +1,220 @@
+//! Reputation scores tracking the behaviour of peers.
+//!
+//! Each node id has a score that starts at zero, is lowered when the peer misbehaves and raised
+//! when it responds correctly. Scores decay towards zero, so old behaviour is gradually
+//! forgotten. The scores are used to:
+//!
+//! - Pick which disconnected routing table entry is challenged, and possibly replaced, when a new
+//!   node competes for a place in a full bucket.
+//! - Prefer well-behaved peers when seeding `FIND_NODE` queries.
+//! - Ban peers whose score falls below `Discv5Config::reputation_ban_threshold`.
+
+use crate::{metrics::InternalMetrics, permit_ban::BanReason, Discv5Config, PermitBanList};
+use enr::NodeId;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// The highest score a peer can reach, so that a long history of good behaviour cannot mask
+/// sudden misbehaviour.
+const MAX_SCORE: f64 = 20.0;
+/// The lowest score a peer can reach.
+const MIN_SCORE: f64 = -100.0;
+/// The number of scores held before the first prune.
+const MIN_PRUNE_THRESHOLD: usize = 64;
+/// Scores that have decayed closer to zero than this are pruned.
+const NEGLIGIBLE_SCORE: f64 = 0.1;
+
+/// Behaviour of a peer that affects its score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behaviour {
+    /// The peer responded correctly to a request.
+    ValidResponse,
+    /// A request to the peer timed out.
+    RequestTimeout,
+    /// The peer sent an invalid response, such as ENRs at distances that were not requested.
+    InvalidResponse,
+    /// A handshake with the peer failed after the peer proved it holds the key of its node id.
+    HandshakeFailure,
+    /// The peer sent requests over its session above its rate limit.
+    RateLimited,
+}
+
+impl Behaviour {
+    /// The change in score caused by the behaviour.
+    fn score_change(&self) -> f64 {
+        match self {
+            Behaviour::ValidResponse => 1.0,
+            Behaviour::RequestTimeout => -2.0,
+            Behaviour::InvalidResponse => -10.0,
+            Behaviour::HandshakeFailure => -5.0,
+            Behaviour::RateLimited => -1.0,
+        }
+    }
+}
+
+/// The score of a single peer.
+struct Score {
+    /// The score at `updated`.
+    value: f64,
+    /// The last time the score changed.
+    updated: Instant,
+}
+
+impl Score {
+    /// The score at `now`, decayed towards zero.
+    fn value_at(&self, half_life: Duration, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+    }
+}
+
+/// The scores of all peers with recent behaviour.
+struct Scores {
+    scores: HashMap<NodeId, Score>,
+    /// The number of scores at which the next prune occurs.
+    prune_threshold: usize,
+}
+
+/// Tracks the reputation of peers, banning those that misbehave persistently.
+pub struct Reputation {
+    /// The scores of peers.
+    scores: Mutex<Scores>,
+    /// The time taken for a score to decay by half.
+    half_life: Duration,
+    /// The score below which a peer is banned, if any.
+    ban_threshold: Option<f64>,
+    /// The duration of bans issued for a low score.
+    ban_duration: Option<Duration>,
+    /// The permit/ban list of the server.
+    permit_ban_list: Arc<RwLock<PermitBanList>>,
+    /// The metrics of the server.
+    metrics: Arc<InternalMetrics>,
+}
+
+impl Reputation {
+    pub fn new(
+        config: &Discv5Config,
+        permit_ban_list: Arc<RwLock<PermitBanList>>,
+        metrics: Arc<InternalMetrics>,
+    ) -> Self {
+        Reputation {
+            scores: Mutex::new(Scores {
+                scores: HashMap::new(),
+                prune_threshold: MIN_PRUNE_THRESHOLD,
+            }),
+            half_life: config.reputation_half_life,
+            ban_threshold: config.reputation_ban_threshold,
+            ban_duration: config.ban_duration,
+            permit_ban_list,
+            metrics,
+        }
+    }
+
+    /// The current score of a peer. Peers without recent behaviour have a score of zero.
+    pub fn score(&self, node_id: &NodeId) -> f64 {
+        self.scores
+            .lock()
+            .scores
+            .get(node_id)
+            .map_or(0.0, |score| score.value_at(self.half_life, Instant::now()))
+    }
+
+    /// Records the behaviour of a peer, banning it if its score falls below the ban threshold.
+    /// Returns `true` if the peer was banned as a result.
+    pub fn report(&self, node_id: &NodeId, behaviour: Behaviour) -> bool {
+        let now = Instant::now();
+        let value = {
+            let mut scores = self.scores.lock();
+            if scores.scores.len() >= scores.prune_threshold {
+                let half_life = self.half_life;
+                scores
+                    .scores
+                    .retain(|_, score| score.value_at(half_life, now).abs() >= NEGLIGIBLE_SCORE);
+                scores.prune_threshold = (scores.scores.len() * 2).max(MIN_PRUNE_THRESHOLD);
+            }
+            let score = scores.scores.entry(*node_id).or_insert(Score {
+                value: 0.0,
+                updated: now,
+            });
+            score.value = (score.value_at(self.half_life, now) + behaviour.score_change())
+                .clamp(MIN_SCORE, MAX_SCORE);
+            score.updated = now;
+            score.value
+        };
+
+        if let Some(ban_threshold) = self.ban_threshold {
+            if value < ban_threshold {
+                let mut permit_ban_list = self.permit_ban_list.write();
+                if !permit_ban_list.permit_nodes.contains(node_id)
+                    && !permit_ban_list.is_node_banned(node_id)
+                {
+                    warn!(
+                        "Node's reputation has fallen below the ban threshold and is now banned {}",
+                        node_id
+                    );
+                    permit_ban_list.ban_node(*node_id, BanReason::LowReputation, self.ban_duration);
+                    self.metrics.banned(BanReason::LowReputation);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reputation(ban_threshold: Option<f64>) -> (Reputation, Arc<RwLock<PermitBanList>>) {
+        let config = crate::Discv5ConfigBuilder::new()
+            .reputation_ban_threshold(ban_threshold)
+            .build();
+        let permit_ban_list = Arc::new(RwLock::new(PermitBanList::default()));
+        let reputation = Reputation::new(
+            &config,
+            permit_ban_list.clone(),
+            Arc::new(InternalMetrics::default()),
+        );
+        (reputation, permit_ban_list)
+    }
+
+    #[test]
+    fn scores_are_bounded() {
+        let (reputation, _) = reputation(None);
+        let node_id = NodeId::random();
+        for _ in 0..100 {
+            reputation.report(&node_id, Behaviour::ValidResponse);
+        }
+        assert!((reputation.score(&node_id) - MAX_SCORE).abs() < 0.01);
+        for _ in 0..100 {
+            reputation.report(&node_id, Behaviour::InvalidResponse);
+        }
+        assert!((reputation.score(&node_id) - MIN_SCORE).abs() < 0.01);
+        assert_eq!(reputation.score(&NodeId::random()), 0.0);
+    }
+
+    #[test]
+    fn low_scores_are_banned() {
+        let (reputation, permit_ban_list) = reputation(Some(-15.0));
+        let node_id = NodeId::random();
+        let permitted = NodeId::random();
+        permit_ban_list.write().permit_nodes.insert(permitted);
+
+        assert!(!reputation.report(&node_id, Behaviour::InvalidResponse));
+        assert!(!permit_ban_list.read().is_node_banned(&node_id));
+        assert!(reputation.report(&node_id, Behaviour::InvalidResponse));
+        assert!(!reputation.report(&permitted, Behaviour::InvalidResponse));
+        assert!(!reputation.report(&permitted, Behaviour::InvalidResponse));
+        assert_eq!(
+            permit_ban_list.read().ban_nodes[&node_id].reason,
+            BanReason::LowReputation
+        );
+        assert!(!permit_ban_list.read().is_node_banned(&permitted));
+    }
+}
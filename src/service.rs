@@ -16,24 +16,38 @@
 use self::{
     ip_vote::IpVote,
     query_info::{QueryInfo, QueryType},
+    table_maintenance::{TableFullness, TableMaintenance},
+    topic_table::{topic_hash, Registration, TopicTable},
 };
 use crate::{
     error::RequestError,
-    handler::{Handler, HandlerRequest, HandlerResponse},
-    kbucket::{self, ip_limiter, KBucketsTable, NodeStatus},
+    handler::{hashmap_delay::HashMapDelay, Handler, HandlerRequest, HandlerResponse},
+    ipmode::IpMode,
+    kbucket::{self, ip_limiter, AppliedPending, KBucketsTable, NodeStatus},
+    metrics::InternalMetrics,
     node_info::{NodeAddress, NodeContact},
     packet::MAX_PACKET_SIZE,
+    permit_ban::BanReason,
     query_pool::{
         FindNodeQueryConfig, PredicateQueryConfig, QueryId, QueryPool, QueryPoolState, TargetKey,
     },
-    rpc, Discv5Config, Discv5Event, Enr,
+    reputation::{Behaviour, Reputation},
+    rpc,
+    socket::Transports,
+    Discv5Config, Discv5Event, Enr, EventStream, EventStreamError, PermitBanList, QueryUpdate,
 };
 use enr::{CombinedKey, NodeId};
 use fnv::FnvHashMap;
-use futures::prelude::*;
+use futures::{future::BoxFuture, prelude::*, stream::FuturesUnordered};
 use parking_lot::RwLock;
 use rpc::*;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, task::Poll};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{
     sync::{mpsc, oneshot},
     time::Interval,
@@ -42,14 +56,26 @@ use tracing::{debug, error, info, trace, warn};
 
 mod ip_vote;
 mod query_info;
+mod table_maintenance;
 mod test;
+mod topic_table;
 
 /// The number of distances (buckets) we simultaneously request from each peer.
 pub(crate) const DISTANCES_TO_REQUEST_PER_PEER: usize = 3;
 
+/// The number of registrars, closest to the topic hash, a topic is registered with and searched
+/// at.
+pub(crate) const TOPIC_REGISTRARS: usize = 5;
+
+/// The maximum time in seconds we accept waiting for a ticket to mature.
+const MAX_TICKET_WAIT_TIME: u64 = 3600;
+
 /// The types of requests to send to the Discv5 service.
 pub enum ServiceRequest {
-    StartQuery(QueryKind, oneshot::Sender<Vec<Enr>>),
+    /// Starts a query, replying with its id.
+    StartQuery(QueryKind, QueryCallback, oneshot::Sender<QueryId>),
+    /// Stops a query and drops its outstanding requests.
+    CancelQuery(QueryId),
     FindEnr(NodeContact, oneshot::Sender<Result<Enr, RequestError>>),
     Talk(
         NodeContact,
@@ -57,15 +83,106 @@ pub enum ServiceRequest {
         Vec<u8>,
         oneshot::Sender<Result<Vec<u8>, RequestError>>,
     ),
-    RequestEventStream(oneshot::Sender<mpsc::Receiver<Discv5Event>>),
+    RequestEventStream(usize, oneshot::Sender<EventStream>),
+    RegisterTopic(Vec<u8>),
+    DeregisterTopic(Vec<u8>),
+    SearchTopic(Vec<u8>, oneshot::Sender<mpsc::Receiver<Enr>>),
+    RegisterTalkProtocol(Vec<u8>, oneshot::Sender<mpsc::Receiver<TalkRequest>>),
+    /// Pings the given nodes, updating their status in the routing table.
+    Revalidate(Vec<Enr>),
+    /// An event caused by the application, to be sent to the event streams.
+    Event(Discv5Event),
+}
+
+/// The number of unanswered TALK requests that can be queued for a single protocol. Requests
+/// received while the queue is full are answered with an empty response.
+const TALK_REQUEST_QUEUE_SIZE: usize = 100;
+
+/// An incoming TALK request for a registered protocol.
+///
+/// The request is answered by calling `respond()`. If the request is dropped without a
+/// response, an empty response is sent.
+pub struct TalkRequest {
+    /// The id of the request, used to match the response.
+    id: RequestId,
+    /// The address of the node that sent the request.
+    node_address: NodeAddress,
+    /// The protocol of the request.
+    protocol: Vec<u8>,
+    /// The body of the request.
+    body: Vec<u8>,
+    /// The channel to send the response to the handler. This is `None` once a response has been
+    /// sent.
+    sender: Option<mpsc::UnboundedSender<HandlerRequest>>,
+}
+
+impl TalkRequest {
+    /// The `NodeId` of the node that sent the request.
+    pub fn node_id(&self) -> &NodeId {
+        &self.node_address.node_id
+    }
+
+    /// The protocol the request was sent for.
+    pub fn protocol(&self) -> &[u8] {
+        &self.protocol
+    }
+
+    /// The body of the request.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Sends a response to the request.
+    pub fn respond(mut self, response: Vec<u8>) -> Result<(), RequestError> {
+        self.send_response(response)
+    }
+
+    fn send_response(&mut self, response: Vec<u8>) -> Result<(), RequestError> {
+        debug!("Sending TALK response to {}", self.node_address);
+        let sender = self
+            .sender
+            .take()
+            .ok_or_else(|| RequestError::ChannelFailed("Response already sent".into()))?;
+        let response = Response {
+            id: self.id.clone(),
+            body: ResponseBody::Talk { response },
+        };
+        sender
+            .send(HandlerRequest::Response(
+                self.node_address.clone(),
+                Box::new(response),
+            ))
+            .map_err(|_| RequestError::ServiceNotStarted)
+    }
+}
+
+impl Drop for TalkRequest {
+    fn drop(&mut self) {
+        if self.sender.is_some() {
+            let _ = self.send_response(Vec::new());
+        }
+    }
 }
 
-use crate::discv5::PERMIT_BAN_LIST;
+impl std::fmt::Debug for TalkRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TalkRequest")
+            .field("id", &self.id)
+            .field("node_address", &self.node_address)
+            .field("protocol", &hex::encode(&self.protocol))
+            .field("body", &hex::encode(&self.body))
+            .finish()
+    }
+}
 
 pub enum QueryKind {
     FindNode {
         target_node: NodeId,
     },
+    DisjointFindNode {
+        target_node: NodeId,
+        paths: usize,
+    },
     Predicate {
         target_node: NodeId,
         target_peer_no: usize,
@@ -86,6 +203,15 @@ pub struct Service {
     /// Storage of the ENR record for each node.
     kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
 
+    /// The permit/ban list of the server.
+    permit_ban_list: Arc<RwLock<PermitBanList>>,
+
+    /// The metrics of the server.
+    metrics: Arc<InternalMetrics>,
+
+    /// The reputation scores of peers.
+    reputation: Arc<Reputation>,
+
     /// All the iterative queries we are currently performing.
     queries: QueryPool<QueryInfo, NodeId, Enr>,
 
@@ -99,6 +225,31 @@ pub struct Service {
     /// A map of votes nodes have made about our external IP address. We accept the majority.
     ip_votes: Option<IpVote>,
 
+    /// The address families peers can be contacted on.
+    ip_mode: IpMode,
+
+    /// The topic advertisements registered with the local node.
+    topic_table: TopicTable,
+
+    /// The topics the local node advertises, along with the registrars of each topic.
+    registered_topics: HashMap<Vec<u8>, TopicRegistration>,
+
+    /// The registrars awaiting a REGTOPIC request. This holds the ticket to present once the wait
+    /// time of a ticket has elapsed, or an empty ticket once a registration is due for renewal.
+    ticket_timers: HashMapDelay<(Vec<u8>, NodeId), (NodeContact, Vec<u8>)>,
+
+    /// Ongoing lookups of the nodes closest to a topic hash.
+    topic_lookups: FuturesUnordered<BoxFuture<'static, (TopicLookup, Vec<Enr>)>>,
+
+    /// Ongoing topic searches, streaming advertisers back to the discv5 layer.
+    topic_searches: HashMap<u64, TopicSearch>,
+
+    /// The id of the next topic search.
+    next_topic_search_id: u64,
+
+    /// The streams of incoming TALK requests for each registered protocol.
+    talk_protocols: HashMap<Vec<u8>, mpsc::Sender<TalkRequest>>,
+
     /// The channel to send messages to the handler.
     handler_send: mpsc::UnboundedSender<HandlerRequest>,
 
@@ -117,8 +268,53 @@ pub struct Service {
     /// An interval to check and ping all nodes in the routing table.
     ping_heartbeat: Interval,
 
-    /// A channel that the service emits events on.
-    event_stream: Option<mpsc::Sender<Discv5Event>>,
+    /// Decides the lookups that maintain the routing table.
+    table_maintenance: TableMaintenance,
+
+    /// An interval to maintain the routing table.
+    table_maintenance_heartbeat: Interval,
+
+    /// The channels that the service emits events on, one per event stream.
+    event_streams: Vec<EventSender>,
+}
+
+/// The sending half of an event stream.
+struct EventSender {
+    sender: mpsc::Sender<Result<Discv5Event, EventStreamError>>,
+    /// The number of events dropped since the last one delivered, as the stream was full.
+    lagged: u64,
+}
+
+impl EventSender {
+    fn new(sender: mpsc::Sender<Result<Discv5Event, EventStreamError>>) -> Self {
+        EventSender { sender, lagged: 0 }
+    }
+
+    /// Sends an event, preceded by the number of events dropped if any were. Returns `false` if
+    /// the stream has been dropped.
+    fn send(&mut self, event: Discv5Event) -> bool {
+        if self.lagged > 0 {
+            match self
+                .sender
+                .try_send(Err(EventStreamError::Lagged(self.lagged)))
+            {
+                Ok(()) => self.lagged = 0,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.lagged += 1;
+                    return true;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return false,
+            }
+        }
+        match self.sender.try_send(Ok(event)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.lagged += 1;
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
 }
 
 /// Active RPC request awaiting a response from the handler.
@@ -139,6 +335,67 @@ pub enum CallbackResponse {
     Enr(oneshot::Sender<Result<Enr, RequestError>>),
     /// A response from a TALK request
     Talk(oneshot::Sender<Result<Vec<u8>, RequestError>>),
+    /// A response to a TOPICQUERY request, which is part of the given topic search.
+    TopicQuery(u64),
+}
+
+/// Where the results of a query are sent.
+#[derive(Debug)]
+pub enum QueryCallback {
+    /// The closest nodes found are sent once the query finishes.
+    Result(oneshot::Sender<Vec<Enr>>),
+    /// The nodes found and the progress of the query are sent as the query runs.
+    Stream(mpsc::UnboundedSender<QueryUpdate>),
+}
+
+impl QueryCallback {
+    /// Whether the receiver has been dropped, meaning the results of the query are not wanted.
+    pub fn is_closed(&self) -> bool {
+        match self {
+            QueryCallback::Result(callback) => callback.is_closed(),
+            QueryCallback::Stream(stream) => stream.is_closed(),
+        }
+    }
+}
+
+/// The advertiser-side state of a topic the local node advertises.
+struct TopicRegistration {
+    /// The registrars the topic is registered with or being registered with.
+    registrars: HashSet<NodeId>,
+    /// Whether a lookup for new registrars is in progress.
+    lookup_in_progress: bool,
+}
+
+/// The purpose of a lookup of the nodes closest to a topic hash.
+enum TopicLookup {
+    /// Finds registrars to register the topic with.
+    Register(Vec<u8>),
+    /// Finds registrars to send TOPICQUERY requests to for the given topic search.
+    Search(u64),
+}
+
+/// An ongoing search for the advertisers of a topic.
+struct TopicSearch {
+    /// The hash of the topic being searched for.
+    topic_hash: TopicHash,
+    /// The channel the advertisers are streamed on. The stream ends when this is dropped.
+    sender: mpsc::Sender<Enr>,
+    /// The advertisers already returned, to avoid duplicates.
+    found: HashSet<NodeId>,
+    /// The number of TOPICQUERY requests awaiting a response.
+    pending_requests: usize,
+}
+
+impl TopicSearch {
+    /// Streams an advertiser, unless it has already been returned. An advertiser that can't be
+    /// sent because the stream is full is not recorded as returned, so that it is sent if found
+    /// again.
+    fn found(&mut self, enr: Enr) {
+        let node_id = enr.node_id();
+        if self.found.insert(node_id) && self.sender.try_send(enr).is_err() {
+            self.found.remove(&node_id);
+        }
+    }
 }
 
 /// For multiple responses to a FindNodes request, this keeps track of the request count
@@ -165,12 +422,16 @@ impl Service {
     /// `local_enr` is the `ENR` representing the local node. This contains node identifying information, such
     /// as IP addresses and ports which we wish to broadcast to other nodes via this discovery
     /// mechanism.
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
         local_enr: Arc<RwLock<Enr>>,
         enr_key: Arc<RwLock<CombinedKey>>,
         kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        permit_ban_list: Arc<RwLock<PermitBanList>>,
+        metrics: Arc<InternalMetrics>,
+        reputation: Arc<Reputation>,
         config: Discv5Config,
-        listen_socket: SocketAddr,
+        transports: Transports,
     ) -> Result<(oneshot::Sender<()>, mpsc::Sender<ServiceRequest>), std::io::Error> {
         // process behaviour-level configuration parameters
         let ip_votes = if config.enr_update {
@@ -179,18 +440,25 @@ impl Service {
             None
         };
 
+        // the address families peers can be contacted on
+        let ip_mode = transports.listen_config()?.ip_mode();
+
         // build the session service
         let (handler_exit, handler_send, handler_recv) = Handler::spawn(
             local_enr.clone(),
             enr_key.clone(),
-            listen_socket,
+            transports,
             config.clone(),
+            permit_ban_list.clone(),
+            metrics.clone(),
+            reputation.clone(),
         )
         .await?;
 
         // create the required channels
         let (discv5_send, discv5_recv) = mpsc::channel(30);
         let (exit_send, exit) = oneshot::channel();
+        let local_id = local_enr.read().node_id();
 
         config
             .executor
@@ -201,16 +469,39 @@ impl Service {
                     local_enr,
                     enr_key,
                     kbuckets,
+                    permit_ban_list,
+                    metrics,
+                    reputation,
                     queries: QueryPool::new(config.query_timeout),
                     active_requests: Default::default(),
                     active_nodes_responses: HashMap::new(),
                     ip_votes,
+                    ip_mode,
+                    topic_table: TopicTable::new(
+                        config.topic_table_capacity,
+                        config.topic_queue_capacity,
+                        config.topic_ad_lifetime,
+                    ),
+                    registered_topics: HashMap::new(),
+                    ticket_timers: HashMapDelay::default(),
+                    topic_lookups: FuturesUnordered::new(),
+                    topic_searches: HashMap::new(),
+                    next_topic_search_id: 0,
+                    talk_protocols: HashMap::new(),
                     handler_send,
                     handler_recv,
                     handler_exit: Some(handler_exit),
                     ping_heartbeat: tokio::time::interval(config.ping_interval),
+                    table_maintenance: TableMaintenance::new(
+                        local_id,
+                        config.bucket_refresh_interval,
+                    ),
+                    table_maintenance_heartbeat: tokio::time::interval_at(
+                        tokio::time::Instant::now() + config.table_maintenance_interval,
+                        config.table_maintenance_interval,
+                    ),
                     discv5_recv,
-                    event_stream: None,
+                    event_streams: Vec::new(),
                     exit,
                     config: config.clone(),
                 };
@@ -235,15 +526,24 @@ impl Service {
                 }
                 Some(service_request) = self.discv5_recv.recv() => {
                     match service_request {
-                        ServiceRequest::StartQuery(query, callback) => {
-                            match query {
+                        ServiceRequest::StartQuery(query, callback, id_callback) => {
+                            let query_id = match query {
                                 QueryKind::FindNode { target_node } => {
-                                    self.start_findnode_query(target_node, callback);
+                                    self.start_findnode_query(target_node, callback)
+                                }
+                                QueryKind::DisjointFindNode { target_node, paths } => {
+                                    self.start_disjoint_query(target_node, paths, callback)
                                 }
                                 QueryKind::Predicate { target_node, target_peer_no, predicate } => {
-                                    self.start_predicate_query(target_node, target_peer_no, predicate, callback);
+                                    self.start_predicate_query(target_node, target_peer_no, predicate, callback)
                                 }
-                            }
+                            };
+                            // if the id is not wanted, the query is cancelled once its callback is
+                            // found to be closed
+                            let _ = id_callback.send(query_id);
+                        }
+                        ServiceRequest::CancelQuery(query_id) => {
+                            self.cancel_query(query_id);
                         }
                         ServiceRequest::FindEnr(node_contact, callback) => {
                             self.request_enr(node_contact, Some(callback));
@@ -251,23 +551,57 @@ impl Service {
                         ServiceRequest::Talk(node_contact, protocol, request, callback) => {
                             self.talk_request(node_contact, protocol, request, callback);
                         }
-                        ServiceRequest::RequestEventStream(callback) => {
-                            // the channel size needs to be large to handle many discovered peers
-                            // if we are reporting them on the event stream.
-                            let channel_size = if self.config.report_discovered_peers { 100 } else { 30 };
-                            let (event_stream, event_stream_recv) = mpsc::channel(channel_size);
-                            self.event_stream = Some(event_stream);
-                            if callback.send(event_stream_recv).is_err() {
+                        ServiceRequest::RequestEventStream(capacity, callback) => {
+                            let (event_stream, event_stream_recv) = mpsc::channel(capacity);
+                            if callback.send(EventStream::new(event_stream_recv)).is_ok() {
+                                self.event_streams.push(EventSender::new(event_stream));
+                            } else {
                                 error!("Failed to return the event stream channel");
                             }
                         }
+                        ServiceRequest::Event(event) => {
+                            self.send_event(event);
+                        }
+                        ServiceRequest::Revalidate(enrs) => {
+                            for enr in enrs {
+                                self.send_ping(enr);
+                            }
+                        }
+                        ServiceRequest::RegisterTopic(topic) => {
+                            self.register_topic(topic);
+                        }
+                        ServiceRequest::DeregisterTopic(topic) => {
+                            self.deregister_topic(&topic);
+                        }
+                        ServiceRequest::SearchTopic(topic, callback) => {
+                            self.search_topic(topic, callback);
+                        }
+                        ServiceRequest::RegisterTalkProtocol(protocol, callback) => {
+                            // registering a protocol again replaces the previous stream
+                            let (talk_send, talk_recv) = mpsc::channel(TALK_REQUEST_QUEUE_SIZE);
+                            if callback.send(talk_recv).is_err() {
+                                error!("Failed to return the TALK request stream");
+                            } else {
+                                self.talk_protocols.insert(protocol, talk_send);
+                            }
+                        }
                     }
                 }
                 Some(event) = self.handler_recv.recv() => {
                     match event {
                         HandlerResponse::Established(enr) => {
+                            self.send_event(Discv5Event::SessionEstablished(enr.clone()));
                             self.inject_session_established(enr);
                         }
+                        HandlerResponse::Banned(node_id, reason) => {
+                            self.send_event(Discv5Event::Banned { node_id, reason });
+                        }
+                        HandlerResponse::SessionExpired(node_address) => {
+                            self.send_event(Discv5Event::SessionExpired {
+                                node_id: node_address.node_id,
+                                socket_addr: node_address.socket_addr,
+                            });
+                        }
                         HandlerResponse::Request(node_address, request) => {
                                 self.handle_rpc_request(node_address, *request);
                             }
@@ -294,8 +628,8 @@ impl Service {
                         }
                     }
                 }
-                event = Service::bucket_maintenance_poll(&self.kbuckets) => {
-                    self.send_event(event);
+                applied = Service::bucket_maintenance_poll(&self.kbuckets) => {
+                    self.pending_applied(applied);
                 }
                 query_event = Service::query_event_poll(&mut self.queries) => {
                     match query_event {
@@ -306,6 +640,7 @@ impl Service {
                         // query is superfluous, however it may be useful in future versions.
                         QueryEvent::Finished(query) | QueryEvent::TimedOut(query) => {
                             let id = query.id();
+                            let duration = query.started().map(|started| started.elapsed()).unwrap_or_default();
                             let mut result = query.into_result();
                             // obtain the ENR's for the resulting nodes
                             let mut found_enrs = Vec::new();
@@ -321,21 +656,66 @@ impl Service {
                                     warn!("ENR not present in queries results");
                                 }
                             }
-                            if result.target.callback.send(found_enrs).is_err() {
+                            self.metrics.query_finished(duration, found_enrs.len());
+                            let sent = match result.target.callback {
+                                QueryCallback::Result(callback) => callback.send(found_enrs).is_ok(),
+                                QueryCallback::Stream(stream) => stream.send(QueryUpdate::Finished(found_enrs)).is_ok(),
+                            };
+                            if !sent {
                                 warn!("Callback dropped for query {}. Results dropped", *id);
                             }
                         }
                     }
                 }
+                Some((lookup, enrs)) = self.topic_lookups.next() => {
+                    self.topic_lookup_finished(lookup, enrs);
+                }
+                Some(Ok(((topic, _), (contact, ticket)))) = self.ticket_timers.next() => {
+                    self.send_register_topic(topic, contact, ticket);
+                }
                 _ = self.ping_heartbeat.tick() => {
                     self.ping_connected_peers();
+                    self.refresh_topic_registrations();
+                    self.permit_ban_list.write().remove_expired();
+                }
+                _ = self.table_maintenance_heartbeat.tick() => {
+                    self.maintain_table();
                 }
             }
         }
     }
 
+    /// Records the fullness of the routing table and starts a lookup to maintain it, if one is
+    /// due.
+    fn maintain_table(&mut self) {
+        let local_id = self.local_enr.read().node_id();
+        let fullness = TableFullness::of(local_id, &self.kbuckets.read(), self.config.kbucket_size);
+        trace!(
+            "Routing table holds {} nodes, with {} full buckets",
+            fullness.entries,
+            fullness.full_buckets
+        );
+        self.metrics
+            .table_updated(fullness.entries, fullness.full_buckets);
+
+        if let Some(target) = self
+            .table_maintenance
+            .next_lookup(&fullness, Instant::now())
+        {
+            debug!(
+                "Starting a lookup of {} to maintain the routing table",
+                target
+            );
+            let (callback, result) = oneshot::channel();
+            self.start_findnode_query(target, QueryCallback::Result(callback));
+            self.table_maintenance.running(result);
+        }
+    }
+
     /// Internal function that starts a query.
-    fn start_findnode_query(&mut self, target_node: NodeId, callback: oneshot::Sender<Vec<Enr>>) {
+    fn start_findnode_query(&mut self, target_node: NodeId, callback: QueryCallback) -> QueryId {
+        self.table_maintenance
+            .lookup_started(&target_node, Instant::now());
         let target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
             untrusted_enrs: Default::default(),
@@ -343,14 +723,43 @@ impl Service {
             callback,
         };
 
-        let target_key: kbucket::Key<NodeId> = target.key();
-        let known_closest_peers: Vec<kbucket::Key<NodeId>> = {
-            let mut kbuckets = self.kbuckets.write();
-            kbuckets.closest_keys(&target_key).collect()
+        let known_closest_peers = self.closest_known_peers(&target.key());
+        let query_config = FindNodeQueryConfig::new_from_config(&self.config);
+        self.queries
+            .add_findnode_query(query_config, target, known_closest_peers)
+    }
+
+    /// Internal function that starts a query along `paths` disjoint paths.
+    fn start_disjoint_query(
+        &mut self,
+        target_node: NodeId,
+        paths: usize,
+        callback: QueryCallback,
+    ) -> QueryId {
+        self.table_maintenance
+            .lookup_started(&target_node, Instant::now());
+        let target = QueryInfo {
+            query_type: QueryType::FindNode(target_node),
+            untrusted_enrs: Default::default(),
+            distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
+            callback,
         };
+
+        let known_closest_peers = self.closest_known_peers(&target.key());
         let query_config = FindNodeQueryConfig::new_from_config(&self.config);
         self.queries
-            .add_findnode_query(query_config, target, known_closest_peers);
+            .add_disjoint_query(query_config, paths, target, known_closest_peers)
+    }
+
+    /// Returns the keys of the routing table closest to the target, to seed a query with.
+    fn closest_known_peers(&self, target_key: &kbucket::Key<NodeId>) -> Vec<kbucket::Key<NodeId>> {
+        let mut kbuckets = self.kbuckets.write();
+        // The query is seeded with the first peers given, so peers with a negative score are
+        // only used if there are too few others.
+        let (preferred, others): (Vec<_>, Vec<_>) = kbuckets
+            .closest_keys(target_key)
+            .partition(|key| self.reputation.score(key.preimage()) >= 0.0);
+        preferred.into_iter().chain(others).collect()
     }
 
     /// Internal function that starts a query.
@@ -359,8 +768,10 @@ impl Service {
         target_node: NodeId,
         num_nodes: usize,
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
-        callback: oneshot::Sender<Vec<Enr>>,
-    ) {
+        callback: QueryCallback,
+    ) -> QueryId {
+        self.table_maintenance
+            .lookup_started(&target_node, Instant::now());
         let target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
             untrusted_enrs: Default::default(),
@@ -380,7 +791,17 @@ impl Service {
         let mut query_config = PredicateQueryConfig::new_from_config(&self.config);
         query_config.num_results = num_nodes;
         self.queries
-            .add_predicate_query(query_config, target, known_closest_peers, predicate);
+            .add_predicate_query(query_config, target, known_closest_peers, predicate)
+    }
+
+    /// Stops a query, dropping its untrusted ENRs along with the requests it is waiting on.
+    /// Responses to those requests are ignored.
+    fn cancel_query(&mut self, query_id: QueryId) {
+        if self.queries.remove(query_id).is_some() {
+            debug!("Query {} cancelled", *query_id);
+            self.active_requests
+                .retain(|_, active_request| active_request.query_id != Some(query_id));
+        }
     }
 
     /// Returns an ENR if one is known for the given NodeId.
@@ -407,6 +828,12 @@ impl Service {
     /// Processes an RPC request from a peer. Requests respond to the received socket address,
     /// rather than the IP of the known ENR.
     fn handle_rpc_request(&mut self, node_address: NodeAddress, req: Request) {
+        if self.config.report_requests {
+            self.send_event(Discv5Event::RequestReceived {
+                node_id: node_address.node_id,
+                kind: req.body.kind(),
+            });
+        }
         let id = req.id;
         match req.body {
             RequestBody::FindNode { distances } => {
@@ -451,23 +878,75 @@ impl Service {
                     .send(HandlerRequest::Response(node_address, Box::new(response)));
             }
             RequestBody::Talk { protocol, request } => {
-                // Send the callback's response to this protocol.
-                let response = (self.config.talkreq_callback)(&protocol, &request);
-                let response = Response {
+                if self.config.report_requests {
+                    self.send_event(Discv5Event::TalkRequestReceived {
+                        node_id: node_address.node_id,
+                        protocol: protocol.clone(),
+                    });
+                }
+                let talk_request = TalkRequest {
                     id,
-                    body: ResponseBody::Talk { response },
+                    node_address,
+                    protocol,
+                    body: request,
+                    sender: Some(self.handler_send.clone()),
                 };
-
-                debug!("Sending TALK response to {}", node_address);
+                // Requests for unregistered protocols are answered with an empty response when
+                // dropped.
+                if let Some(sender) = self.talk_protocols.get(&talk_request.protocol) {
+                    match sender.try_send(talk_request) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(talk_request)) => {
+                            debug!(
+                                "TALK request queue full, dropping request from {}",
+                                talk_request.node_address
+                            );
+                            self.metrics.talk_request_dropped();
+                        }
+                        Err(mpsc::error::TrySendError::Closed(talk_request)) => {
+                            // the stream has been dropped, unregister the protocol
+                            self.talk_protocols.remove(&talk_request.protocol);
+                        }
+                    }
+                }
+            }
+            RequestBody::RegisterTopic { topic, enr, ticket } => {
+                let body =
+                    match self
+                        .topic_table
+                        .register(node_address.node_id, &topic, enr, &ticket)
+                    {
+                        Ok(Registration::Registered) => {
+                            debug!("Registered topic advertisement from {}", node_address);
+                            ResponseBody::RegisterConfirmation { topic }
+                        }
+                        Ok(Registration::Ticket { ticket, wait_time }) => {
+                            // Wait times are sent in seconds, round up so the ticket is mature once
+                            // the wait is over.
+                            let wait_time = wait_time.as_secs()
+                                + if wait_time.subsec_nanos() > 0 { 1 } else { 0 };
+                            ResponseBody::Ticket { ticket, wait_time }
+                        }
+                        Err(e) => {
+                            debug!("Rejected REGTOPIC request from {}: {}", node_address, e);
+                            return;
+                        }
+                    };
+                let response = Response { id, body };
+                debug!("Sending {} response to {}", response.body, node_address);
                 let _ = self
                     .handler_send
                     .send(HandlerRequest::Response(node_address, Box::new(response)));
             }
-            RequestBody::RegisterTopic { .. } => {
-                debug!("Received RegisterTopic request which is unimplemented");
-            }
-            RequestBody::TopicQuery { .. } => {
-                debug!("Received TopicQuery request which is unimplemented");
+            RequestBody::TopicQuery { topic } => {
+                let mut ads = self.topic_table.ads(&topic);
+                ads.truncate(self.config.max_nodes_response);
+                debug!(
+                    "Sending {} topic advertisements to {}",
+                    ads.len(),
+                    node_address
+                );
+                self.send_nodes(node_address, id, ads);
             }
         }
     }
@@ -484,7 +963,7 @@ impl Service {
             );
 
             // Check that the responder matches the expected request
-            if let Ok(request_node_address) = active_request.contact.node_address() {
+            if let Ok(request_node_address) = active_request.contact.node_address(self.ip_mode) {
                 if request_node_address != node_address {
                     warn!("Received a response from an unexpected address. Expected {}, received {}, request_id {}", request_node_address, node_address, id);
                     return;
@@ -497,9 +976,16 @@ impl Service {
                     "Node gave an incorrect response type. Ignoring response from: {}",
                     active_request.contact
                 );
+                self.report_behaviour(&node_id, Behaviour::InvalidResponse);
                 return;
             }
+            self.report_behaviour(&node_id, Behaviour::ValidResponse);
             match response.body {
+                ResponseBody::Nodes { total, nodes }
+                    if matches!(active_request.request_body, RequestBody::TopicQuery { .. }) =>
+                {
+                    self.topic_query_response(id, active_request, total, nodes);
+                }
                 ResponseBody::Nodes { total, mut nodes } => {
                     // Currently a maximum of DISTANCES_TO_REQUEST_PER_PEER*BUCKET_SIZE peers can be returned. Datagrams have a max
                     // size of 1280 and ENR's have a max size of 300 bytes.
@@ -553,12 +1039,20 @@ impl Service {
                                 active_request.contact
                             );
                         }
-                        PERMIT_BAN_LIST.write().ban(
+                        self.permit_ban_list.write().ban(
                             active_request
                                 .contact
-                                .node_address()
+                                .node_address(self.ip_mode)
                                 .expect("Sanitized request"),
+                            BanReason::InvalidEnr,
+                            self.config.ban_duration,
                         );
+                        self.metrics.banned(BanReason::InvalidEnr);
+                        self.send_event(Discv5Event::Banned {
+                            node_id,
+                            reason: BanReason::InvalidEnr,
+                        });
+                        self.report_behaviour(&node_id, Behaviour::InvalidResponse);
                         nodes.retain(|enr| {
                             peer_key
                                 .log2_distance(&enr.node_id().clone().into())
@@ -579,12 +1073,20 @@ impl Service {
                                 "Peer sent invalid ENR. Blacklisting {}",
                                 active_request.contact
                             );
-                            PERMIT_BAN_LIST.write().ban(
+                            self.permit_ban_list.write().ban(
                                 active_request
                                     .contact
-                                    .node_address()
+                                    .node_address(self.ip_mode)
                                     .expect("Sanitized request"),
+                                BanReason::InvalidEnr,
+                                self.config.ban_duration,
                             );
+                            self.metrics.banned(BanReason::InvalidEnr);
+                            self.send_event(Discv5Event::Banned {
+                                node_id,
+                                reason: BanReason::InvalidEnr,
+                            });
+                            self.report_behaviour(&node_id, Behaviour::InvalidResponse);
                         }
                     }
 
@@ -601,9 +1103,7 @@ impl Service {
                         );
                         // if there are more requests coming, store the nodes and wait for
                         // another response
-                        // We allow for implementations to send at a minimum 3 nodes per response.
-                        // We allow for the number of nodes to be returned as the maximum we emit.
-                        if current_response.count < self.config.max_nodes_response / 3 + 1
+                        if current_response.count < self.max_nodes_responses()
                             && (current_response.count as u64) < total
                         {
                             current_response.count += 1;
@@ -639,24 +1139,20 @@ impl Service {
                 ResponseBody::Pong { enr_seq, ip, port } => {
                     let socket = SocketAddr::new(ip, port);
                     // perform ENR majority-based update if required.
-                    let local_socket = self.local_enr.read().udp_socket();
                     if let Some(ref mut ip_votes) = self.ip_votes {
                         ip_votes.insert(node_id, socket);
-                        if let Some(majority_socket) = ip_votes.majority() {
-                            if Some(majority_socket) != local_socket {
-                                info!("Local UDP socket updated to: {}", majority_socket);
-                                self.send_event(Discv5Event::SocketUpdated(majority_socket));
-                                // Update the UDP socket
-                                if self
-                                    .local_enr
-                                    .write()
-                                    .set_udp_socket(majority_socket, &self.enr_key.read())
-                                    .is_ok()
-                                {
-                                    // alert known peers to our updated enr
-                                    self.ping_connected_peers();
-                                }
-                            }
+                        // each address family is updated independently
+                        let (majority_ipv4, majority_ipv6) = ip_votes.majority();
+                        let mut updated = false;
+                        if let Some(majority_socket) = majority_ipv4 {
+                            updated |= self.update_local_enr_socket(majority_socket.into());
+                        }
+                        if let Some(majority_socket) = majority_ipv6 {
+                            updated |= self.update_local_enr_socket(majority_socket.into());
+                        }
+                        if updated {
+                            // alert known peers to our updated enr
+                            self.ping_connected_peers();
                         }
                     }
 
@@ -686,15 +1182,61 @@ impl Service {
                         _ => error!("Invalid callback for response"),
                     }
                 }
-                ResponseBody::Ticket { .. } => {
-                    error!("Received a TICKET response. This is unimplemented and should be unreachable.");
+                ResponseBody::Ticket { ticket, wait_time } => {
+                    let topic = match active_request.request_body {
+                        RequestBody::RegisterTopic { topic, .. } => topic,
+                        _ => unreachable!(),
+                    };
+                    if !self.is_registrar(&topic, &node_id) {
+                        return;
+                    }
+                    if wait_time > MAX_TICKET_WAIT_TIME {
+                        debug!(
+                            "Ticket wait time of {}s exceeds the maximum. Dropping registrar {}",
+                            wait_time, active_request.contact
+                        );
+                        self.remove_registrar(&topic, &node_id);
+                        return;
+                    }
+                    trace!(
+                        "Received a ticket from {}, waiting {}s",
+                        active_request.contact,
+                        wait_time
+                    );
+                    self.ticket_timers.insert_at(
+                        (topic, node_id),
+                        (active_request.contact, ticket),
+                        Duration::from_secs(wait_time),
+                    );
                 }
-                ResponseBody::RegisterConfirmation { .. } => {
-                    error!("Received a RegisterConfirmation response. This is unimplemented and should be unreachable.");
+                ResponseBody::RegisterConfirmation { topic } => {
+                    let requested_topic = match active_request.request_body {
+                        RequestBody::RegisterTopic { topic, .. } => topic,
+                        _ => unreachable!(),
+                    };
+                    if topic != requested_topic {
+                        warn!(
+                            "Registrar confirmed a topic that was not requested: {}",
+                            active_request.contact
+                        );
+                        self.remove_registrar(&requested_topic, &node_id);
+                        return;
+                    }
+                    if !self.is_registrar(&topic, &node_id) {
+                        return;
+                    }
+                    debug!("Topic registered with {}", active_request.contact);
+                    // renew the registration once the advertisement expires
+                    self.ticket_timers.insert_at(
+                        (topic, node_id),
+                        (active_request.contact, Vec::new()),
+                        self.config.topic_ad_lifetime,
+                    );
                 }
             }
         } else {
-            warn!(
+            // responses to requests of cancelled queries are expected here
+            debug!(
                 "Received an RPC response which doesn't match a request. Id: {}",
                 id
             );
@@ -773,6 +1315,233 @@ impl Service {
         self.send_rpc_request(active_request);
     }
 
+    /// Starts advertising a topic. Registrars are found by a lookup of the nodes closest to the
+    /// topic hash.
+    fn register_topic(&mut self, topic: Vec<u8>) {
+        if self.registered_topics.contains_key(&topic) {
+            debug!("Topic is already registered");
+            return;
+        }
+        let lookup_hash = topic_hash(&topic);
+        let registration = TopicRegistration {
+            registrars: HashSet::new(),
+            lookup_in_progress: true,
+        };
+        self.registered_topics.insert(topic.clone(), registration);
+        self.start_topic_lookup(lookup_hash, TopicLookup::Register(topic));
+    }
+
+    /// Stops advertising a topic. Existing advertisements expire at the registrars.
+    fn deregister_topic(&mut self, topic: &[u8]) {
+        if self.registered_topics.remove(topic).is_some() {
+            self.ticket_timers.retain(|(t, _), _| t.as_slice() != topic);
+        }
+    }
+
+    /// Starts a search for the advertisers of a topic. The advertisers are returned on a stream
+    /// which ends once all registrars have responded.
+    fn search_topic(&mut self, topic: Vec<u8>, callback: oneshot::Sender<mpsc::Receiver<Enr>>) {
+        let search_id = self.next_topic_search_id;
+        self.next_topic_search_id += 1;
+
+        let topic_hash = topic_hash(&topic);
+        let (sender, receiver) = mpsc::channel(self.config.max_nodes_response * TOPIC_REGISTRARS);
+        let mut search = TopicSearch {
+            topic_hash,
+            sender,
+            found: HashSet::new(),
+            pending_requests: 0,
+        };
+        // include the advertisements registered with the local node
+        for enr in self.topic_table.ads(&topic_hash) {
+            search.found(enr);
+        }
+        if callback.send(receiver).is_err() {
+            error!("Failed to return the topic search stream");
+            return;
+        }
+        self.topic_searches.insert(search_id, search);
+        self.start_topic_lookup(topic_hash, TopicLookup::Search(search_id));
+    }
+
+    /// Starts a query for the nodes closest to a topic hash.
+    fn start_topic_lookup(&mut self, topic_hash: TopicHash, lookup: TopicLookup) {
+        let (callback, result) = oneshot::channel();
+        self.start_findnode_query(NodeId::new(&topic_hash), QueryCallback::Result(callback));
+        self.topic_lookups.push(Box::pin(async move {
+            (lookup, result.await.unwrap_or_default())
+        }));
+    }
+
+    /// Sends REGTOPIC or TOPICQUERY requests to the closest nodes found by a topic lookup.
+    fn topic_lookup_finished(&mut self, lookup: TopicLookup, enrs: Vec<Enr>) {
+        match lookup {
+            TopicLookup::Register(topic) => {
+                let new_registrars = match self.registered_topics.get_mut(&topic) {
+                    Some(registration) => {
+                        registration.lookup_in_progress = false;
+                        let free_slots =
+                            TOPIC_REGISTRARS.saturating_sub(registration.registrars.len());
+                        // the iterator is lazy, only the registrars taken are inserted
+                        enrs.into_iter()
+                            .filter(|enr| registration.registrars.insert(enr.node_id()))
+                            .take(free_slots)
+                            .collect::<Vec<_>>()
+                    }
+                    // the topic has since been deregistered
+                    None => return,
+                };
+                debug!("Registering topic with {} registrars", new_registrars.len());
+                for enr in new_registrars {
+                    self.send_register_topic(topic.clone(), enr.into(), Vec::new());
+                }
+            }
+            TopicLookup::Search(search_id) => {
+                let topic = match self.topic_searches.get(&search_id) {
+                    Some(search) => search.topic_hash,
+                    None => return,
+                };
+                let mut pending_requests = 0;
+                for enr in enrs.into_iter().take(TOPIC_REGISTRARS) {
+                    let active_request = ActiveRequest {
+                        contact: enr.into(),
+                        request_body: RequestBody::TopicQuery { topic },
+                        query_id: None,
+                        callback: Some(CallbackResponse::TopicQuery(search_id)),
+                    };
+                    self.send_rpc_request(active_request);
+                    pending_requests += 1;
+                }
+                if pending_requests == 0 {
+                    // no registrars found, end the search
+                    self.topic_searches.remove(&search_id);
+                } else if let Some(search) = self.topic_searches.get_mut(&search_id) {
+                    search.pending_requests = pending_requests;
+                }
+            }
+        }
+    }
+
+    /// Sends a REGTOPIC request to a registrar, if the topic is still registered.
+    fn send_register_topic(&mut self, topic: Vec<u8>, contact: NodeContact, ticket: Vec<u8>) {
+        if !self.is_registrar(&topic, &contact.node_id()) {
+            return;
+        }
+        let request_body = RequestBody::RegisterTopic {
+            topic,
+            enr: self.local_enr.read().clone(),
+            ticket,
+        };
+        let active_request = ActiveRequest {
+            contact,
+            request_body,
+            query_id: None,
+            callback: None,
+        };
+        self.send_rpc_request(active_request);
+    }
+
+    /// Returns true if `node_id` is a registrar of a topic the local node advertises.
+    fn is_registrar(&self, topic: &[u8], node_id: &NodeId) -> bool {
+        self.registered_topics
+            .get(topic)
+            .map(|registration| registration.registrars.contains(node_id))
+            .unwrap_or(false)
+    }
+
+    /// Removes a registrar that has failed or rejected a registration.
+    fn remove_registrar(&mut self, topic: &[u8], node_id: &NodeId) {
+        if let Some(registration) = self.registered_topics.get_mut(topic) {
+            registration.registrars.remove(node_id);
+            self.ticket_timers.remove(&(topic.to_vec(), *node_id));
+        }
+    }
+
+    /// Looks up new registrars for any topic that is registered with too few registrars.
+    fn refresh_topic_registrations(&mut self) {
+        let topics = self
+            .registered_topics
+            .iter_mut()
+            .filter(|(_, registration)| {
+                !registration.lookup_in_progress && registration.registrars.len() < TOPIC_REGISTRARS
+            })
+            .map(|(topic, registration)| {
+                registration.lookup_in_progress = true;
+                topic.clone()
+            })
+            .collect::<Vec<_>>();
+        for topic in topics {
+            self.start_topic_lookup(topic_hash(&topic), TopicLookup::Register(topic));
+        }
+    }
+
+    /// Processes a NODES response to a TOPICQUERY request, streaming any new advertisers to the
+    /// search.
+    fn topic_query_response(
+        &mut self,
+        id: RequestId,
+        active_request: ActiveRequest,
+        total: u64,
+        nodes: Vec<Enr>,
+    ) {
+        let search_id = match active_request.callback {
+            Some(CallbackResponse::TopicQuery(search_id)) => search_id,
+            _ => {
+                error!("Invalid callback for TOPICQUERY response");
+                return;
+            }
+        };
+        if let Some(search) = self.topic_searches.get_mut(&search_id) {
+            for enr in nodes {
+                search.found(enr);
+            }
+        }
+
+        // wait for the remaining responses if there are any
+        let node_id = active_request.contact.node_id();
+        if total > 1 {
+            let mut current_response = self
+                .active_nodes_responses
+                .remove(&node_id)
+                .unwrap_or_default();
+            if current_response.count < self.max_nodes_responses()
+                && (current_response.count as u64) < total
+            {
+                current_response.count += 1;
+                self.active_nodes_responses
+                    .insert(node_id, current_response);
+                self.active_requests.insert(id, active_request);
+                return;
+            }
+        }
+        self.active_nodes_responses.remove(&node_id);
+        self.topic_search_request_finished(search_id);
+    }
+
+    /// Marks a TOPICQUERY request of a search as complete. The search ends once all requests are
+    /// complete.
+    fn topic_search_request_finished(&mut self, search_id: u64) {
+        if let Some(search) = self.topic_searches.get_mut(&search_id) {
+            search.pending_requests = search.pending_requests.saturating_sub(1);
+            if search.pending_requests == 0 {
+                self.topic_searches.remove(&search_id);
+            }
+        }
+    }
+
+    /// The maximum number of nodes sent in response to a FINDNODE request, which is enough for a
+    /// full bucket.
+    fn max_nodes_response(&self) -> usize {
+        self.config.max_nodes_response.max(self.config.kbucket_size)
+    }
+
+    /// The maximum number of NODES responses accepted for a single request. We allow for
+    /// implementations to send at a minimum 3 nodes per response, and for as many nodes as a
+    /// full bucket, or as we send ourselves, to be returned.
+    fn max_nodes_responses(&self) -> usize {
+        self.max_nodes_response() / 3 + 1
+    }
+
     /// Sends a NODES response, given a list of found ENR's. This function splits the nodes up
     /// into multiple responses to ensure the response stays below the maximum packet size.
     fn send_nodes_response(
@@ -782,7 +1551,8 @@ impl Service {
         mut distances: Vec<u64>,
     ) {
         // NOTE: At most we only allow 5 distances to be send (see the decoder). If each of these
-        // buckets are full, that equates to 80 ENR's to respond with.
+        // buckets are full, that equates to 5 * `kbucket_size` ENR's to respond with, of which at
+        // most `max_nodes_response` are sent. This is always enough for a full bucket.
 
         let mut nodes_to_send = Vec::new();
         distances.sort_unstable();
@@ -798,7 +1568,7 @@ impl Service {
         if !distances.is_empty() {
             let mut kbuckets = self.kbuckets.write();
             for node in kbuckets
-                .nodes_by_distances(distances, self.config.max_nodes_response)
+                .nodes_by_distances(distances, self.max_nodes_response())
                 .into_iter()
                 .filter_map(|entry| {
                     if entry.node.key.preimage() != &node_address.node_id {
@@ -812,6 +1582,17 @@ impl Service {
             }
         }
 
+        self.send_nodes(node_address, rpc_id, nodes_to_send);
+    }
+
+    /// Sends a list of ENR's in one or more NODES responses. The nodes are split into multiple
+    /// responses to ensure each response stays below the maximum packet size.
+    fn send_nodes(
+        &mut self,
+        node_address: NodeAddress,
+        rpc_id: RequestId,
+        nodes_to_send: Vec<Enr>,
+    ) {
         // if there are no nodes, send an empty response
         if nodes_to_send.is_empty() {
             let response = Response {
@@ -897,6 +1678,13 @@ impl Service {
         return_peer: NodeId,
         request_body: RequestBody,
     ) {
+        // a query whose callback has been dropped is no longer wanted
+        if matches!(self.queries.get_mut(query_id), Some(query) if query.target().callback.is_closed())
+        {
+            self.cancel_query(query_id);
+            return;
+        }
+
         // find the ENR associated with the query
         if let Some(enr) = self.find_enr(&return_peer) {
             let active_request = ActiveRequest {
@@ -906,6 +1694,7 @@ impl Service {
                 callback: None,
             };
             self.send_rpc_request(active_request);
+            self.update_query_stream(query_id, Vec::new());
         } else {
             error!("Query {} requested an unknown ENR", *query_id);
         }
@@ -928,15 +1717,23 @@ impl Service {
             .send(HandlerRequest::Request(contact, Box::new(request)));
     }
 
-    fn send_event(&mut self, event: Discv5Event) {
-        if let Some(stream) = self.event_stream.as_mut() {
-            if let Err(mpsc::error::TrySendError::Closed(_)) = stream.try_send(event) {
-                // If the stream has been dropped prevent future attempts to send events
-                self.event_stream = None;
-            }
+    /// Records the behaviour of a peer, reporting a ban if its reputation falls below the ban
+    /// threshold.
+    fn report_behaviour(&mut self, node_id: &NodeId, behaviour: Behaviour) {
+        if self.reputation.report(node_id, behaviour) {
+            self.send_event(Discv5Event::Banned {
+                node_id: *node_id,
+                reason: BanReason::LowReputation,
+            });
         }
     }
 
+    fn send_event(&mut self, event: Discv5Event) {
+        // streams that have been dropped are removed to prevent future attempts to send events
+        self.event_streams
+            .retain_mut(|stream| stream.send(event.clone()));
+    }
+
     /// Processes discovered peers from a query.
     fn discovered(&mut self, source: &NodeId, enrs: Vec<Enr>, query_id: Option<QueryId>) {
         let local_id = self.local_enr.read().node_id();
@@ -953,10 +1750,15 @@ impl Service {
             if (self.config.table_filter)(enr_ref) {
                 let key = kbucket::Key::from(enr_ref.node_id());
                 if !self.config.ip_limit
-                    || self
-                        .kbuckets
-                        .read()
-                        .check(&key, enr_ref, |v, o, l| ip_limiter(v, &o, l))
+                    || self.kbuckets.read().check(&key, enr_ref, |v, o, l| {
+                        ip_limiter(
+                            v,
+                            &o,
+                            l,
+                            self.config.ipv4_subnet_prefix,
+                            self.config.ipv6_subnet_prefix,
+                        )
+                    })
                 {
                     match self.kbuckets.write().entry(&key) {
                         kbucket::Entry::Present(mut entry, _) => {
@@ -982,6 +1784,7 @@ impl Service {
         if let Some(query_id) = query_id {
             if let Some(query) = self.queries.get_mut(query_id) {
                 let mut peer_count = 0;
+                let mut found = Vec::new();
                 for enr_ref in other_enr_iter.clone() {
                     if query
                         .target_mut()
@@ -991,17 +1794,38 @@ impl Service {
                         .is_none()
                     {
                         query.target_mut().untrusted_enrs.push(enr_ref.clone());
+                        found.push(enr_ref.clone());
                     }
                     peer_count += 1;
                 }
                 debug!("{} peers found for query id {:?}", peer_count, query_id);
-                query.on_success(source, &other_enr_iter.cloned().collect::<Vec<_>>())
+                query.on_success(source, &other_enr_iter.cloned().collect::<Vec<_>>());
+                self.update_query_stream(query_id, found);
             } else {
                 warn!("Response returned for ended query {:?}", query_id)
             }
         }
     }
 
+    /// Sends the nodes newly found by a query, along with its progress, to the stream of the
+    /// query if it is streamed. The query is stopped if its stream has been dropped.
+    fn update_query_stream(&mut self, query_id: QueryId, found: Vec<Enr>) {
+        let query = match self.queries.get_mut(query_id) {
+            Some(query) => query,
+            None => return,
+        };
+        if let QueryCallback::Stream(stream) = &query.target().callback {
+            let sent = found
+                .into_iter()
+                .filter(|enr| query.matches(enr))
+                .all(|enr| stream.send(QueryUpdate::Found(enr)).is_ok())
+                && stream.send(QueryUpdate::Progress(query.stats())).is_ok();
+            if !sent {
+                self.cancel_query(query_id);
+            }
+        }
+    }
+
     /// Update the connection status of a node in the routing table.
     fn connection_updated(
         &mut self,
@@ -1016,12 +1840,22 @@ impl Service {
                 return;
             }
 
+            // ignore banned peers, including those advertising an IP of a banned subnet
+            if self.permit_ban_list.read().is_enr_banned(enr) {
+                return;
+            }
+
             // should the ENR be inserted or updated to a value that would exceed the IP limit ban
             if self.config.ip_limit
-                && !self
-                    .kbuckets
-                    .read()
-                    .check(&key, enr, |v, o, l| ip_limiter(v, &o, l))
+                && !self.kbuckets.read().check(&key, enr, |v, o, l| {
+                    ip_limiter(
+                        v,
+                        &o,
+                        l,
+                        self.config.ipv4_subnet_prefix,
+                        self.config.ipv6_subnet_prefix,
+                    )
+                })
             {
                 // if the node status is connected and it would exceed the ip ban, consider it
                 // disconnected to be pruned.
@@ -1036,7 +1870,11 @@ impl Service {
                 if let Some(enr) = enr {
                     *entry.value() = enr;
                 }
+                if new_status == NodeStatus::Connected {
+                    *entry.last_seen() = Some(SystemTime::now());
+                }
                 if old_status != new_status {
+                    // a failed node is replaced by a replacement candidate of its bucket, if any
                     entry.update(new_status);
                 }
             }
@@ -1053,7 +1891,11 @@ impl Service {
                     // Note: If an ENR is not provided, no record is added
                     debug_assert!(enr.is_some());
                     if let Some(enr) = enr {
-                        match entry.insert(enr, new_status) {
+                        // a full bucket challenges its lowest scoring disconnected node
+                        let reputation = &self.reputation;
+                        match entry
+                            .insert_scored(enr, new_status, |enr| reputation.score(&enr.node_id()))
+                        {
                             kbucket::InsertResult::Inserted => {
                                 let event = Discv5Event::NodeInserted {
                                     node_id,
@@ -1067,6 +1909,9 @@ impl Service {
                             }
                         }
                     }
+                } else {
+                    // a failed node is no longer a candidate to replace others
+                    entry.remove_replacement();
                 }
             }
             _ => {}
@@ -1082,11 +1927,94 @@ impl Service {
         }
     }
 
+    /// Handles a node inserted into the routing table in place of another, either a pending node
+    /// or a replacement candidate of its bucket.
+    ///
+    /// The table filter, bans and IP limits were checked when the node was first seen, so they
+    /// are checked again and a node that no longer passes is dropped. A node inserted as
+    /// disconnected is pinged, to be marked as connected once it responds.
+    fn pending_applied(&mut self, applied: AppliedPending<NodeId, Enr>) {
+        let key = applied.inserted;
+        let node_id = *key.preimage();
+        let replaced = applied.evicted.map(|node| node.key.into_preimage());
+
+        let (enr, status) = match self.kbuckets.write().entry(&key) {
+            kbucket::Entry::Present(mut entry, status) => (entry.value().clone(), status),
+            _ => return,
+        };
+        let admitted = (self.config.table_filter)(&enr)
+            && !self.permit_ban_list.read().is_enr_banned(&enr)
+            && (!self.config.ip_limit
+                || self.kbuckets.read().check(&key, &enr, |v, o, l| {
+                    ip_limiter(
+                        v,
+                        &o,
+                        l,
+                        self.config.ipv4_subnet_prefix,
+                        self.config.ipv6_subnet_prefix,
+                    )
+                }));
+
+        if !admitted {
+            debug!("Dropping node {} inserted into the routing table", node_id);
+            // the next replacement candidate, if any, takes its place
+            self.kbuckets.write().remove(&key);
+            if let Some(evicted) = replaced {
+                let event = Discv5Event::NodeRemoved {
+                    node_id: evicted,
+                    replaced_by: None,
+                };
+                self.send_event(event);
+            }
+            return;
+        }
+
+        self.send_event(Discv5Event::NodeInserted { node_id, replaced });
+        if let Some(evicted) = replaced {
+            let event = Discv5Event::NodeRemoved {
+                node_id: evicted,
+                replaced_by: Some(node_id),
+            };
+            self.send_event(event);
+        }
+        if status == NodeStatus::Disconnected {
+            self.send_ping(enr);
+        }
+    }
+
+    /// Updates the local ENR's socket of the same address family as `socket`, if it differs from
+    /// the one currently advertised. Returns whether the ENR was updated.
+    fn update_local_enr_socket(&mut self, socket: SocketAddr) -> bool {
+        let local_socket = match socket {
+            SocketAddr::V4(_) => self.local_enr.read().udp_socket(),
+            SocketAddr::V6(_) => self.local_enr.read().udp6_socket(),
+        };
+        if Some(socket) == local_socket {
+            return false;
+        }
+        info!("Local UDP socket updated to: {}", socket);
+        match socket {
+            SocketAddr::V4(socket) => self.send_event(Discv5Event::Ipv4SocketUpdated(socket)),
+            SocketAddr::V6(socket) => self.send_event(Discv5Event::Ipv6SocketUpdated(socket)),
+        }
+        // Update the UDP socket
+        let updated = self
+            .local_enr
+            .write()
+            .set_udp_socket(socket, &self.enr_key.read())
+            .is_ok();
+        if updated {
+            let enr = self.local_enr.read().clone();
+            self.send_event(Discv5Event::LocalEnrUpdated(enr));
+        }
+        updated
+    }
+
     /// The equivalent of libp2p `inject_connected()` for a udp session. We have no stream, but a
     /// session key-pair has been negotiated.
     fn inject_session_established(&mut self, enr: Enr) {
         // Ignore sessions with non-contactable ENRs
-        if enr.udp_socket().is_none() {
+        if self.ip_mode.contactable_socket(&enr).is_none() {
             return;
         }
 
@@ -1102,6 +2030,10 @@ impl Service {
     fn rpc_failure(&mut self, id: RequestId, error: RequestError) {
         trace!("RPC Error removing request. Reason: {:?}, id {}", error, id);
         if let Some(active_request) = self.active_requests.remove(&id) {
+            if error == RequestError::Timeout {
+                self.report_behaviour(&active_request.contact.node_id(), Behaviour::RequestTimeout);
+            }
+
             // If this is initiated by the user, return an error on the callback. All callbacks
             // support a request error.
             match active_request.callback {
@@ -1118,6 +2050,13 @@ impl Service {
                         .unwrap_or_else(|_| debug!("Couldn't send TALK error response to user"));
                     return;
                 }
+                Some(CallbackResponse::TopicQuery(search_id)) => {
+                    // any partially received advertisers have already been returned
+                    self.active_nodes_responses
+                        .remove(&active_request.contact.node_id());
+                    self.topic_search_request_finished(search_id);
+                    return;
+                }
                 None => {
                     // no callback to send too
                 }
@@ -1149,6 +2088,7 @@ impl Service {
                             if let Some(query) = self.queries.get_mut(query_id) {
                                 query.on_failure(&node_id);
                             }
+                            self.update_query_stream(query_id, Vec::new());
                         } else {
                             debug!(
                                 "Failed RPC request: {}: {} ",
@@ -1157,6 +2097,13 @@ impl Service {
                         }
                     }
                 }
+                RequestBody::RegisterTopic { topic, .. } => {
+                    debug!(
+                        "Failed to register topic with {}, reason {:?}",
+                        active_request.contact, error
+                    );
+                    self.remove_registrar(&topic, &node_id);
+                }
                 // for all other requests, if any are queries, mark them as failures.
                 _ => {
                     if let Some(query_id) = active_request.query_id {
@@ -1167,6 +2114,7 @@ impl Service {
                             );
                             query.on_failure(&node_id);
                         }
+                        self.update_query_stream(query_id, Vec::new());
                     } else {
                         debug!(
                             "Failed RPC request: {} for node: {}, reason {:?} ",
@@ -1181,18 +2129,14 @@ impl Service {
     }
 
     /// A future that maintains the routing table and inserts nodes when required. This returns the
-    /// `Discv5Event::NodeInserted` variant if a new node has been inserted into the routing table.
+    /// pending entry that has been applied to the routing table, along with any node it evicted.
     async fn bucket_maintenance_poll(
         kbuckets: &Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
-    ) -> Discv5Event {
+    ) -> AppliedPending<NodeId, Enr> {
         future::poll_fn(move |_cx| {
             // Drain applied pending entries from the routing table.
             if let Some(entry) = kbuckets.write().take_applied_pending() {
-                let event = Discv5Event::NodeInserted {
-                    node_id: entry.inserted.into_preimage(),
-                    replaced: entry.evicted.map(|n| n.key.into_preimage()),
-                };
-                return Poll::Ready(event);
+                return Poll::Ready(entry);
             }
             Poll::Pending
         })
@@ -2,7 +2,8 @@ use enr::NodeId;
 use fnv::FnvHashMap;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    hash::Hash,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     time::{Duration, Instant},
 };
 
@@ -10,9 +11,14 @@ use std::{
 const PING_VOTE_TIMEOUT: u64 = 300;
 
 /// A collection of IP:Ports for our node reported from external peers.
+///
+/// IPv4 and IPv6 votes are tallied independently, so that a dual-stack node can hold a majority
+/// for each address family at the same time.
 pub(crate) struct IpVote {
-    /// The current collection of IP:Port votes.
-    votes: HashMap<NodeId, (SocketAddr, Instant)>,
+    /// The current collection of IPv4 IP:Port votes.
+    ipv4_votes: HashMap<NodeId, (SocketAddrV4, Instant)>,
+    /// The current collection of IPv6 IP:Port votes.
+    ipv6_votes: HashMap<NodeId, (SocketAddrV6, Instant)>,
     /// The minimum number of votes required before an IP/PORT is accepted.
     minimum_threshold: usize,
 }
@@ -24,45 +30,68 @@ impl IpVote {
             panic!("Setting enr_peer_update_min to a value less than 2 will cause issues with discovery with peers behind NAT");
         }
         IpVote {
-            votes: HashMap::new(),
+            ipv4_votes: HashMap::new(),
+            ipv6_votes: HashMap::new(),
             minimum_threshold,
         }
     }
 
+    /// Records a vote for the address family of `socket`.
     pub fn insert(&mut self, key: NodeId, socket: SocketAddr) {
-        self.votes.insert(
-            key,
-            (
-                socket,
-                Instant::now() + Duration::from_secs(PING_VOTE_TIMEOUT),
-            ),
-        );
+        let expires = Instant::now() + Duration::from_secs(PING_VOTE_TIMEOUT);
+        match socket {
+            SocketAddr::V4(socket) => {
+                self.ipv4_votes.insert(key, (socket, expires));
+            }
+            SocketAddr::V6(socket) => {
+                self.ipv6_votes.insert(key, (socket, expires));
+            }
+        }
     }
 
-    /// Returns the majority `SocketAddr` if it exists. If there are not enough votes to meet the threshold this returns None.
-    pub fn majority(&mut self) -> Option<SocketAddr> {
-        // remove any expired votes
-        let instant = Instant::now();
-        self.votes.retain(|_, v| v.1 > instant);
-
-        // count votes, take majority
-        let mut ip_count: FnvHashMap<SocketAddr, usize> = FnvHashMap::default();
-        for (socket, _) in self.votes.values() {
-            *ip_count.entry(*socket).or_insert_with(|| 0) += 1;
-        }
+    /// Returns the majority IPv4 and IPv6 sockets, if they exist. If there are not enough votes
+    /// for a family to meet the threshold, None is returned for that family.
+    pub fn majority(&mut self) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
+        (
+            majority(&mut self.ipv4_votes, self.minimum_threshold),
+            majority(&mut self.ipv6_votes, self.minimum_threshold),
+        )
+    }
+}
 
-        // find the maximum socket addr
-        ip_count
-            .into_iter()
-            .filter(|v| v.1 >= self.minimum_threshold)
-            .max_by_key(|v| v.1)
-            .map(|v| v.0)
+/// Removes expired votes and returns the socket with the most votes, if it meets the threshold.
+fn majority<S: Copy + Eq + Hash>(
+    votes: &mut HashMap<NodeId, (S, Instant)>,
+    minimum_threshold: usize,
+) -> Option<S> {
+    // remove any expired votes
+    let instant = Instant::now();
+    votes.retain(|_, v| v.1 > instant);
+
+    // count votes, take majority
+    let mut ip_count: FnvHashMap<S, usize> = FnvHashMap::default();
+    for (socket, _) in votes.values() {
+        *ip_count.entry(*socket).or_insert_with(|| 0) += 1;
     }
+
+    // find the maximum socket addr
+    ip_count
+        .into_iter()
+        .filter(|v| v.1 >= minimum_threshold)
+        .max_by_key(|v| v.1)
+        .map(|v| v.0)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{IpVote, NodeId, SocketAddr};
+    use super::{IpVote, NodeId, SocketAddr, SocketAddrV4};
+
+    fn v4(socket: SocketAddr) -> SocketAddrV4 {
+        match socket {
+            SocketAddr::V4(socket) => socket,
+            SocketAddr::V6(_) => panic!("Expected an IPv4 socket"),
+        }
+    }
 
     #[test]
     fn test_three_way_vote_draw() {
@@ -83,7 +112,7 @@ mod tests {
         votes.insert(NodeId::random(), socket_3);
         votes.insert(NodeId::random(), socket_3);
 
-        assert_eq!(votes.majority(), Some(socket_2));
+        assert_eq!(votes.majority(), (Some(v4(socket_2)), None));
     }
 
     #[test]
@@ -98,7 +127,7 @@ mod tests {
         votes.insert(NodeId::random(), socket_2);
         votes.insert(NodeId::random(), socket_3);
 
-        assert_eq!(votes.majority(), Some(socket_1));
+        assert_eq!(votes.majority(), (Some(v4(socket_1)), None));
     }
 
     #[test]
@@ -113,6 +142,24 @@ mod tests {
         votes.insert(NodeId::random(), socket_2);
         votes.insert(NodeId::random(), socket_3);
 
-        assert_eq!(votes.majority(), None);
+        assert_eq!(votes.majority(), (None, None));
+    }
+
+    #[test]
+    fn test_families_are_tallied_independently() {
+        let mut votes = IpVote::new(2);
+        let socket_v4 = SocketAddr::new("127.0.0.1".parse().unwrap(), 1);
+        let socket_v6 = SocketAddr::new("::1".parse().unwrap(), 1);
+
+        // a single IPv6 vote does not outweigh the IPv4 majority, nor vice versa
+        votes.insert(NodeId::random(), socket_v4);
+        votes.insert(NodeId::random(), socket_v4);
+        votes.insert(NodeId::random(), socket_v6);
+        assert_eq!(votes.majority(), (Some(v4(socket_v4)), None));
+
+        votes.insert(NodeId::random(), socket_v6);
+        let (ipv4, ipv6) = votes.majority();
+        assert_eq!(ipv4, Some(v4(socket_v4)));
+        assert_eq!(ipv6.map(SocketAddr::V6), Some(socket_v6));
     }
 }
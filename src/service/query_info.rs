@@ -1,8 +1,8 @@
+use super::QueryCallback;
 use crate::{kbucket::Key, rpc::RequestBody, Enr};
 use enr::NodeId;
 use sha2::digest::generic_array::GenericArray;
 use smallvec::SmallVec;
-use tokio::sync::oneshot;
 
 /// Information about a query.
 #[derive(Debug)]
@@ -14,7 +14,7 @@ pub struct QueryInfo {
     pub untrusted_enrs: SmallVec<[Enr; 16]>,
 
     /// A callback channel for the service that requested the query.
-    pub callback: oneshot::Sender<Vec<Enr>>,
+    pub callback: QueryCallback,
 
     /// The number of distances we request for each peer.
     pub distances_to_request: usize,
@@ -0,0 +1,212 @@
+//! Maintenance of the routing table through lookups.
+//!
+//! A bucket is refreshed with a lookup of a random node id in its range once no lookup has
+//! targeted it for the refresh interval, whether that lookup was started by the application or by
+//! the maintenance itself. Only the buckets from the closest non-empty bucket outwards are
+//! refreshed, as closer buckets are unlikely to ever hold nodes. A lookup of the local node id is
+//! run after startup and whenever the table is bootstrapped from empty, to find the nodes
+//! closest to the local node.
+
+use crate::{
+    kbucket::{self, NUM_BUCKETS},
+    Enr,
+};
+use enr::NodeId;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// The number of nodes held in the routing table and how many of its buckets are full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct TableFullness {
+    /// The number of nodes in the table.
+    pub entries: usize,
+    /// The number of buckets holding the maximum number of nodes.
+    pub full_buckets: usize,
+    /// The log2 distance of the closest non-empty bucket, if any.
+    pub closest_bucket: Option<u64>,
+}
+
+impl TableFullness {
+    /// Measures the fullness of a routing table whose buckets hold up to `bucket_size` nodes.
+    pub fn of(
+        local_id: NodeId,
+        kbuckets: &kbucket::KBucketsTable<NodeId, Enr>,
+        bucket_size: usize,
+    ) -> Self {
+        let local_key = kbucket::Key::from(local_id);
+        let mut bucket_sizes = [0usize; NUM_BUCKETS];
+        for entry in kbuckets.iter_ref() {
+            if let Some(distance) = entry.node.key.log2_distance(&local_key) {
+                bucket_sizes[distance as usize - 1] += 1;
+            }
+        }
+        TableFullness {
+            entries: bucket_sizes.iter().sum(),
+            full_buckets: bucket_sizes
+                .iter()
+                .filter(|size| **size >= bucket_size)
+                .count(),
+            closest_bucket: bucket_sizes
+                .iter()
+                .position(|size| *size > 0)
+                .map(|index| index as u64 + 1),
+        }
+    }
+}
+
+/// Decides which lookups are run to maintain the routing table.
+pub(crate) struct TableMaintenance {
+    /// The node id of the local node.
+    local_id: NodeId,
+    /// The time a bucket may go without a lookup before it is refreshed. `None` if lookups are
+    /// disabled.
+    refresh_interval: Option<Duration>,
+    /// The last time a lookup targeted each bucket, indexed by log2 distance - 1.
+    last_lookups: Vec<Option<Instant>>,
+    /// Whether a lookup of the local node id is run once the table holds nodes.
+    self_lookup_pending: bool,
+    /// The result of the running maintenance lookup, if any.
+    lookup: Option<oneshot::Receiver<Vec<Enr>>>,
+}
+
+impl TableMaintenance {
+    pub fn new(local_id: NodeId, refresh_interval: Option<Duration>) -> Self {
+        TableMaintenance {
+            local_id,
+            refresh_interval,
+            last_lookups: vec![None; NUM_BUCKETS],
+            self_lookup_pending: true,
+            lookup: None,
+        }
+    }
+
+    /// Records a lookup of `target` being started, which refreshes the bucket it falls in.
+    pub fn lookup_started(&mut self, target: &NodeId, now: Instant) {
+        let local_key = kbucket::Key::from(self.local_id);
+        if let Some(distance) = kbucket::Key::from(*target).log2_distance(&local_key) {
+            self.last_lookups[distance as usize - 1] = Some(now);
+        }
+    }
+
+    /// Returns the target of the next maintenance lookup, if one is due, given the current
+    /// fullness of the table. The result of the lookup must be handed back with `running()`, so
+    /// that only one maintenance lookup runs at a time.
+    pub fn next_lookup(&mut self, fullness: &TableFullness, now: Instant) -> Option<NodeId> {
+        let refresh_interval = self.refresh_interval?;
+        let closest_bucket = match fullness.closest_bucket {
+            Some(distance) => distance,
+            None => {
+                // the next nodes added bootstrap the table
+                self.self_lookup_pending = true;
+                return None;
+            }
+        };
+        if let Some(lookup) = self.lookup.as_mut() {
+            if let Err(oneshot::error::TryRecvError::Empty) = lookup.try_recv() {
+                return None;
+            }
+            self.lookup = None;
+        }
+
+        if self.self_lookup_pending {
+            self.self_lookup_pending = false;
+            return Some(self.local_id);
+        }
+
+        // refresh the bucket that has gone the longest without a lookup
+        let stale_distance = (closest_bucket..=NUM_BUCKETS as u64)
+            .filter_map(|distance| match self.last_lookups[distance as usize - 1] {
+                Some(last_lookup)
+                    if now.saturating_duration_since(last_lookup) < refresh_interval =>
+                {
+                    None
+                }
+                last_lookup => Some((last_lookup, distance)),
+            })
+            .min()?
+            .1;
+        Some(random_node_id_at_distance(&self.local_id, stale_distance))
+    }
+
+    /// Records the maintenance lookup that has been started.
+    pub fn running(&mut self, lookup: oneshot::Receiver<Vec<Enr>>) {
+        self.lookup = Some(lookup);
+    }
+}
+
+/// Generates a random node id at the given log2 distance from `local_id`.
+fn random_node_id_at_distance(local_id: &NodeId, distance: u64) -> NodeId {
+    debug_assert!(distance >= 1 && distance <= NUM_BUCKETS as u64);
+    // a random mask whose highest set bit is at the distance
+    let mut mask: [u8; 32] = rand::random();
+    let bit = distance as usize - 1;
+    let byte = 31 - bit / 8;
+    for b in mask.iter_mut().take(byte) {
+        *b = 0;
+    }
+    let highest = 1u8 << (bit % 8);
+    mask[byte] = (mask[byte] & (highest - 1)) | highest;
+
+    let mut raw = local_id.raw();
+    for (b, m) in raw.iter_mut().zip(mask.iter()) {
+        *b ^= m;
+    }
+    NodeId::new(&raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_node_ids_are_at_the_distance() {
+        let local_id = NodeId::random();
+        let local_key = kbucket::Key::from(local_id);
+        for distance in 1..=NUM_BUCKETS as u64 {
+            let node_id = random_node_id_at_distance(&local_id, distance);
+            assert_eq!(
+                kbucket::Key::from(node_id).log2_distance(&local_key),
+                Some(distance)
+            );
+        }
+    }
+
+    #[test]
+    fn stale_buckets_are_refreshed_after_a_self_lookup() {
+        let local_id = NodeId::random();
+        let interval = Duration::from_secs(60);
+        let mut maintenance = TableMaintenance::new(local_id, Some(interval));
+        let now = Instant::now();
+        let fullness = TableFullness {
+            entries: 1,
+            full_buckets: 0,
+            closest_bucket: Some(254),
+        };
+
+        // nothing is looked up until the table holds nodes
+        assert_eq!(
+            maintenance.next_lookup(&TableFullness::default(), now),
+            None
+        );
+        assert_eq!(maintenance.next_lookup(&fullness, now), Some(local_id));
+
+        // the stalest of the buckets from the closest non-empty bucket outwards is refreshed,
+        // once the previous maintenance lookup finishes
+        let local_key = kbucket::Key::from(local_id);
+        let distance = |node_id: NodeId| kbucket::Key::from(node_id).log2_distance(&local_key);
+        maintenance.lookup_started(&random_node_id_at_distance(&local_id, 254), now);
+        maintenance.lookup_started(&random_node_id_at_distance(&local_id, 256), now);
+        let (send, recv) = oneshot::channel();
+        maintenance.running(recv);
+        assert_eq!(maintenance.next_lookup(&fullness, now), None);
+        drop(send);
+        let target = maintenance.next_lookup(&fullness, now).unwrap();
+        assert_eq!(distance(target), Some(255));
+        maintenance.lookup_started(&target, now);
+
+        // recently refreshed buckets are left alone until the refresh interval passes
+        assert_eq!(maintenance.next_lookup(&fullness, now), None);
+        let target = maintenance.next_lookup(&fullness, now + interval).unwrap();
+        assert_eq!(distance(target), Some(254));
+    }
+}
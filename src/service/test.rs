@@ -2,22 +2,30 @@
 
 use crate::{
     handler::Handler,
+    handler::HandlerRequest,
+    ipmode::IpMode,
     kbucket,
     kbucket::{KBucketsTable, NodeStatus},
+    metrics::InternalMetrics,
     node_info::NodeContact,
+    permit_ban::BanReason,
     query_pool::{QueryId, QueryPool},
+    reputation::Reputation,
     rpc,
     rpc::RequestId,
-    service::{ActiveRequest, Service},
-    Discv5ConfigBuilder,
+    service::{
+        table_maintenance::TableMaintenance, topic_table::TopicTable, ActiveRequest, EventSender,
+        QueryCallback, QueryEvent, Service, TopicSearch,
+    },
+    socket::Transports,
+    Discv5ConfigBuilder, Discv5Event, EventStream, EventStreamError, MemoryNetwork,
 };
-use enr::{CombinedKey, Enr, EnrBuilder};
+use enr::{CombinedKey, Enr, EnrBuilder, EnrKey, NodeId};
 use parking_lot::RwLock;
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
-    time::Duration,
 };
 use tokio::sync::{mpsc, oneshot};
 
@@ -35,39 +43,71 @@ async fn build_service(
     let config = Discv5ConfigBuilder::new()
         .executor(Box::new(crate::executor::TokioExecutor::default()))
         .build();
+    let permit_ban_list = Arc::new(RwLock::new(config.permit_ban_list.clone()));
+    let metrics = Arc::new(InternalMetrics::default());
+    let reputation = Arc::new(Reputation::new(
+        &config,
+        permit_ban_list.clone(),
+        metrics.clone(),
+    ));
     // build the session service
     let (_handler_exit, handler_send, handler_recv) = Handler::spawn(
         local_enr.clone(),
         enr_key.clone(),
-        listen_socket,
+        Transports::single(MemoryNetwork::default().bind(listen_socket).unwrap()).unwrap(),
         config.clone(),
+        permit_ban_list.clone(),
+        metrics.clone(),
+        reputation.clone(),
     )
     .await
     .unwrap();
 
     let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
         local_enr.read().node_id().into(),
-        Duration::from_secs(60),
+        config.kbucket_pending_timeout,
+        config.kbucket_size,
+        config.kbucket_replacement_cache_size,
+        config.max_nodes_per_subnet_bucket,
+        config.max_nodes_per_subnet_table,
     )));
 
     // create the required channels
     let (_discv5_send, discv5_recv) = mpsc::channel(30);
     let (_exit_send, exit) = oneshot::channel();
+    let local_id = local_enr.read().node_id();
 
     Service {
         local_enr,
         enr_key,
         kbuckets,
+        permit_ban_list,
+        metrics,
+        reputation,
         queries: QueryPool::new(config.query_timeout),
         active_requests: Default::default(),
         active_nodes_responses: HashMap::new(),
         ip_votes: None,
+        ip_mode: IpMode::Ip4,
+        topic_table: TopicTable::new(
+            config.topic_table_capacity,
+            config.topic_queue_capacity,
+            config.topic_ad_lifetime,
+        ),
+        registered_topics: HashMap::new(),
+        ticket_timers: Default::default(),
+        topic_lookups: Default::default(),
+        topic_searches: HashMap::new(),
+        next_topic_search_id: 0,
+        talk_protocols: HashMap::new(),
         handler_send,
         handler_recv,
         handler_exit: Some(_handler_exit),
         ping_heartbeat: tokio::time::interval(config.ping_interval),
+        table_maintenance: TableMaintenance::new(local_id, config.bucket_refresh_interval),
+        table_maintenance_heartbeat: tokio::time::interval(config.table_maintenance_interval),
         discv5_recv,
-        event_stream: None,
+        event_streams: Vec::new(),
         exit,
         config,
     }
@@ -122,7 +162,7 @@ async fn test_updating_connection_on_ping() {
     };
 
     let node_contact = NodeContact::Enr(Box::new(enr2));
-    let expected_return_addr = node_contact.node_address().unwrap();
+    let expected_return_addr = node_contact.node_address(IpMode::Ip4).unwrap();
 
     service.active_requests.insert(
         RequestId(vec![1]),
@@ -140,3 +180,319 @@ async fn test_updating_connection_on_ping() {
     let node = buckets.iter_ref().next().unwrap();
     assert_eq!(node.status, NodeStatus::Connected);
 }
+
+#[tokio::test]
+async fn test_banned_subnets_are_kept_out_of_the_table() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(10003)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp_socket().unwrap();
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr,
+    )
+    .await;
+    service.permit_ban_list.write().ban_subnet(
+        "192.0.2.0/24".parse().unwrap(),
+        BanReason::Manual,
+        None,
+    );
+
+    let peer = |ip: &str| {
+        let key = CombinedKey::generate_secp256k1();
+        EnrBuilder::new("v4")
+            .ip(ip.parse().unwrap())
+            .udp(9000)
+            .build(&key)
+            .unwrap()
+    };
+    let banned = peer("192.0.2.10");
+    let allowed = peer("198.51.100.10");
+    for enr in [banned.clone(), allowed.clone()].iter() {
+        service.connection_updated(enr.node_id(), Some(enr.clone()), NodeStatus::Connected);
+    }
+
+    let mut kbuckets = service.kbuckets.write();
+    let mut in_table = |enr: &Enr<CombinedKey>| {
+        matches!(
+            kbuckets.entry(&kbucket::Key::from(enr.node_id())),
+            kbucket::Entry::Present(..)
+        )
+    };
+    assert!(!in_table(&banned));
+    assert!(in_table(&allowed));
+}
+
+#[tokio::test]
+async fn test_topic_registration_and_query() {
+    init();
+    let enr_key1 = CombinedKey::generate_secp256k1();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip(ip)
+        .udp(10003)
+        .build(&enr_key1)
+        .unwrap();
+    let enr_key2 = CombinedKey::generate_secp256k1();
+    let enr2 = EnrBuilder::new("v4")
+        .ip(ip)
+        .udp(10004)
+        .build(&enr_key2)
+        .unwrap();
+
+    let socket_addr = enr.udp_socket().unwrap();
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key1)),
+        socket_addr,
+    )
+    .await;
+    // intercept the responses sent to the handler
+    let (handler_send, mut handler_recv) = mpsc::unbounded_channel();
+    service.handler_send = handler_send;
+
+    let node_address = NodeContact::Enr(Box::new(enr2.clone()))
+        .node_address(IpMode::Ip4)
+        .unwrap();
+
+    let request = rpc::Request {
+        id: RequestId(vec![1]),
+        body: rpc::RequestBody::RegisterTopic {
+            topic: b"topic".to_vec(),
+            enr: enr2.clone(),
+            ticket: Vec::new(),
+        },
+    };
+    service.handle_rpc_request(node_address.clone(), request);
+    match handler_recv.recv().await {
+        Some(HandlerRequest::Response(_, response)) => assert_eq!(
+            response.body,
+            rpc::ResponseBody::RegisterConfirmation {
+                topic: b"topic".to_vec()
+            }
+        ),
+        _ => panic!("Expected a response"),
+    }
+
+    let request = rpc::Request {
+        id: RequestId(vec![2]),
+        body: rpc::RequestBody::TopicQuery {
+            topic: crate::service::topic_table::topic_hash(b"topic"),
+        },
+    };
+    service.handle_rpc_request(node_address, request);
+    match handler_recv.recv().await {
+        Some(HandlerRequest::Response(_, response)) => assert_eq!(
+            response.body,
+            rpc::ResponseBody::Nodes {
+                total: 1,
+                nodes: vec![enr2]
+            }
+        ),
+        _ => panic!("Expected a response"),
+    }
+}
+
+#[tokio::test]
+async fn test_event_streams_buffer_independently() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(10005)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp_socket().unwrap();
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr,
+    )
+    .await;
+
+    let mut stream = |capacity| {
+        let (sender, recv) = mpsc::channel(capacity);
+        service.event_streams.push(EventSender::new(sender));
+        EventStream::new(recv)
+    };
+    let mut slow = stream(2);
+    let mut fast = stream(4);
+
+    let event = |port| Discv5Event::Ipv4SocketUpdated(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let port = |event: Result<Discv5Event, EventStreamError>| match event {
+        Ok(Discv5Event::Ipv4SocketUpdated(socket)) => socket.port(),
+        x => panic!("Unexpected event {:?}", x),
+    };
+    for p in 1..=3 {
+        service.send_event(event(p));
+    }
+    assert_eq!(port(slow.recv().await), 1);
+    assert_eq!(port(slow.recv().await), 2);
+    service.send_event(event(4));
+
+    // the slow stream is told of the event it missed before receiving the next
+    assert_eq!(slow.recv().await.unwrap_err(), EventStreamError::Lagged(1));
+    assert_eq!(port(slow.recv().await), 4);
+    for p in 1..=4 {
+        assert_eq!(port(fast.recv().await), p);
+    }
+
+    // dropped streams are removed
+    drop(fast);
+    service.send_event(event(5));
+    assert_eq!(service.event_streams.len(), 1);
+}
+
+#[tokio::test]
+async fn test_cancelled_queries_drop_their_requests() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(10006)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp_socket().unwrap();
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr,
+    )
+    .await;
+    let (handler_send, _handler_recv) = mpsc::unbounded_channel();
+    service.handler_send = handler_send;
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(10007)
+        .build(&peer_key)
+        .unwrap();
+    service.connection_updated(peer.node_id(), Some(peer), NodeStatus::Connected);
+
+    // sends the first request of the query
+    async fn start_query(service: &mut Service, callback: QueryCallback) -> QueryId {
+        let query_id = service.start_findnode_query(NodeId::random(), callback);
+        match Service::query_event_poll(&mut service.queries).await {
+            QueryEvent::Waiting(query_id, node_id, request_body) => {
+                service.send_rpc_query(query_id, node_id, request_body)
+            }
+            _ => panic!("Expected the query to contact the peer"),
+        }
+        query_id
+    }
+
+    let (callback, _result) = oneshot::channel();
+    let query_id = start_query(&mut service, QueryCallback::Result(callback)).await;
+    assert_eq!(service.active_requests.len(), 1);
+    service.cancel_query(query_id);
+    assert!(service.queries.iter().next().is_none());
+    assert!(service.active_requests.is_empty());
+
+    // a query whose result is no longer wanted is cancelled before sending a request
+    let (callback, result) = oneshot::channel();
+    drop(result);
+    start_query(&mut service, QueryCallback::Result(callback)).await;
+    assert!(service.queries.iter().next().is_none());
+    assert!(service.active_requests.is_empty());
+}
+
+#[tokio::test]
+async fn test_promoted_candidates_are_checked_against_the_ip_limits() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip("127.0.0.1".parse().unwrap())
+        .udp(10008)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp_socket().unwrap();
+    let local_key = kbucket::Key::from(enr.node_id());
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr,
+    )
+    .await;
+    // buckets of a single node, with a single node per subnet across the table
+    service.config.ip_limit = true;
+    *service.kbuckets.write() = KBucketsTable::new(
+        local_key.clone(),
+        service.config.kbucket_pending_timeout,
+        1,
+        service.config.kbucket_replacement_cache_size,
+        service.config.max_nodes_per_subnet_bucket,
+        1,
+    );
+
+    let peer = |ip: &str, distance: u64| loop {
+        let key = CombinedKey::generate_secp256k1();
+        if local_key.log2_distance(&NodeId::from(key.public()).into()) == Some(distance) {
+            break EnrBuilder::new("v4")
+                .ip(ip.parse().unwrap())
+                .udp(9000)
+                .build(&key)
+                .unwrap();
+        }
+    };
+    // the candidate is kept for the full bucket, before a node of its subnet joins another bucket
+    let connected = peer("198.51.100.10", 256);
+    let candidate = peer("192.0.2.10", 256);
+    let neighbour = peer("192.0.2.20", 255);
+    for enr in [connected.clone(), candidate.clone(), neighbour.clone()].iter() {
+        service.connection_updated(enr.node_id(), Some(enr.clone()), NodeStatus::Connected);
+    }
+
+    // the candidate replaces the failed node, but is dropped as its subnet is now full
+    service.connection_updated(connected.node_id(), None, NodeStatus::Disconnected);
+    let applied = service.kbuckets.write().take_applied_pending().unwrap();
+    assert_eq!(applied.inserted, kbucket::Key::from(candidate.node_id()));
+    service.pending_applied(applied);
+
+    let mut kbuckets = service.kbuckets.write();
+    let mut in_table = |enr: &Enr<CombinedKey>| {
+        matches!(
+            kbuckets.entry(&kbucket::Key::from(enr.node_id())),
+            kbucket::Entry::Present(..)
+        )
+    };
+    assert!(!in_table(&candidate));
+    assert!(!in_table(&connected));
+    assert!(in_table(&neighbour));
+}
+
+#[tokio::test]
+async fn test_topic_search_resends_advertisers_dropped_by_a_full_stream() {
+    init();
+    let advertiser = |port| {
+        let key = CombinedKey::generate_secp256k1();
+        EnrBuilder::new("v4")
+            .ip("127.0.0.1".parse().unwrap())
+            .udp(port)
+            .build(&key)
+            .unwrap()
+    };
+    let (first, second) = (advertiser(9000), advertiser(9001));
+    let (sender, mut receiver) = mpsc::channel(1);
+    let mut search = TopicSearch {
+        topic_hash: Default::default(),
+        sender,
+        found: HashSet::new(),
+        pending_requests: 0,
+    };
+
+    // the second advertiser doesn't fit in the stream
+    search.found(first.clone());
+    search.found(second.clone());
+    assert_eq!(receiver.recv().await.unwrap(), first);
+
+    // it is sent when found again, while the first is not sent twice
+    search.found(first);
+    search.found(second.clone());
+    assert_eq!(receiver.recv().await.unwrap(), second);
+    assert!(receiver.try_recv().is_err());
+}
@@ -0,0 +1,555 @@
+//! The topic table of a registrar.
+//!
+//! Nodes advertise a topic by registering their ENR with registrars via REGTOPIC requests. A
+//! registrar stores these advertisements in a queue per topic. Both the size of each queue and the
+//! total number of advertisements across all topics are bounded. When an advertisement cannot be
+//! stored, the registrar issues a ticket along with the time the advertiser must wait before
+//! registering again.
+//!
+//! Tickets are encrypted with a key known only to the local node. They cannot be forged by
+//! advertisers. Each ticket carries the time its holder first asked to register, and when space
+//! becomes available it goes to the advertiser that has waited the longest. The registrar only
+//! remembers the advertisers waiting for each topic until their tickets lapse, and no more of them
+//! than it can store advertisements. Further advertisers are turned away until some lapse.
+
+use crate::{rpc::TopicHash, Enr};
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes128Gcm,
+};
+use enr::NodeId;
+use rlp::RlpStream;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// The time after a ticket's wait time has elapsed in which the ticket can still be used to
+/// register.
+const REGISTRATION_WINDOW: Duration = Duration::from_secs(10);
+
+/// The length of the nonce that prefixes each encrypted ticket.
+const TICKET_NONCE_LENGTH: usize = 12;
+
+/// Computes the hash of a topic. This is the value sent in TOPICQUERY requests and is used to
+/// find the registrars of a topic.
+pub fn topic_hash(topic: &[u8]) -> TopicHash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Sha256::digest(topic));
+    hash
+}
+
+/// The result of a registration attempt.
+#[derive(Debug)]
+pub(crate) enum Registration {
+    /// The advertisement has been stored in the topic table.
+    Registered,
+    /// The advertisement could not be stored. The advertiser must wait `wait_time` before
+    /// registering again with the given ticket.
+    Ticket {
+        ticket: Vec<u8>,
+        wait_time: Duration,
+    },
+}
+
+/// A stored advertisement.
+struct Ad {
+    /// The ENR of the advertiser.
+    enr: Enr,
+    /// The time the advertisement was stored.
+    registered: Instant,
+}
+
+/// An advertiser holding a ticket for a topic.
+struct Waiter {
+    /// The time the advertiser first asked to register, since the creation of the topic table.
+    first_issued: Duration,
+    /// The time the advertiser's ticket can no longer be used, since the creation of the topic
+    /// table.
+    expires: Duration,
+}
+
+/// The contents of a ticket before encryption.
+#[derive(Debug, Clone, PartialEq)]
+struct Ticket {
+    /// The node the ticket was issued to.
+    node_id: NodeId,
+    /// The topic the ticket was issued for.
+    topic: TopicHash,
+    /// The time the node first asked to register, in milliseconds since the creation of the topic
+    /// table. This is kept when a ticket is re-issued, so that the total time waited is known.
+    first_issued: u64,
+    /// The time the ticket was issued, in milliseconds since the creation of the topic table.
+    issued: u64,
+    /// The time the advertiser must wait before registering, in milliseconds.
+    wait_time: u64,
+}
+
+/// Stores advertisements of topics registered with the local node.
+pub(crate) struct TopicTable {
+    /// The advertisement queues for each topic, ordered from oldest to newest.
+    topics: HashMap<TopicHash, VecDeque<Ad>>,
+    /// The advertisers waiting to register for each topic.
+    waiting: HashMap<TopicHash, HashMap<NodeId, Waiter>>,
+    /// The total number of advertisers waiting across all topics, at most `table_capacity`.
+    waiting_len: usize,
+    /// The total number of advertisements across all topics.
+    len: usize,
+    /// The maximum number of advertisements across all topics.
+    table_capacity: usize,
+    /// The maximum number of advertisements for a single topic.
+    queue_capacity: usize,
+    /// The time an advertisement is stored for.
+    ad_lifetime: Duration,
+    /// The key used to encrypt tickets.
+    ticket_key: [u8; 16],
+    /// The reference point for the times stored in tickets.
+    created: Instant,
+}
+
+impl TopicTable {
+    pub fn new(table_capacity: usize, queue_capacity: usize, ad_lifetime: Duration) -> Self {
+        TopicTable {
+            topics: HashMap::new(),
+            waiting: HashMap::new(),
+            waiting_len: 0,
+            len: 0,
+            table_capacity,
+            queue_capacity,
+            ad_lifetime,
+            ticket_key: rand::random(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Processes a REGTOPIC request from `node_id`.
+    ///
+    /// An advertisement is stored if there is space for it in the table and no other advertiser
+    /// has waited longer for it. Otherwise a ticket is issued with the time until space becomes
+    /// available. Once this time has elapsed, the ticket can be used to register within the
+    /// `REGISTRATION_WINDOW`, and the time waited counts towards any ticket issued in its place.
+    /// Nodes that already have an advertisement for the topic are issued a ticket that matures
+    /// when their advertisement expires. New advertisers are turned away while `table_capacity`
+    /// advertisers are already waiting.
+    pub fn register(
+        &mut self,
+        node_id: NodeId,
+        topic: &[u8],
+        enr: Enr,
+        ticket: &[u8],
+    ) -> Result<Registration, &'static str> {
+        if enr.node_id() != node_id {
+            return Err("ENR does not match the registering node");
+        }
+        self.prune();
+
+        let topic = topic_hash(topic);
+        let now = self.created.elapsed();
+
+        let mut first_issued = now;
+        if !ticket.is_empty() {
+            let ticket = self.decode_ticket(ticket)?;
+            if ticket.node_id != node_id || ticket.topic != topic {
+                return Err("Ticket was issued for a different node or topic");
+            }
+            let ready = Duration::from_millis(ticket.issued + ticket.wait_time);
+            if now < ready {
+                // the advertiser is early, re-issue the ticket with the remaining wait time
+                return Ok(Registration::Ticket {
+                    ticket: self.encode_ticket(&ticket),
+                    wait_time: ready - now,
+                });
+            }
+            // A ticket that has expired gives no advantage over registering without a ticket.
+            if now > ready + REGISTRATION_WINDOW {
+                debug!("Expired ticket presented by node: {}", node_id);
+            } else {
+                first_issued = Duration::from_millis(ticket.first_issued);
+            }
+        }
+
+        let ad_lifetime = self.ad_lifetime;
+        if let Some(ad) = self
+            .topics
+            .get_mut(&topic)
+            .and_then(|queue| queue.iter_mut().find(|ad| ad.enr.node_id() == node_id))
+        {
+            if ad.enr.seq() < enr.seq() {
+                ad.enr = enr;
+            }
+            let wait_time = (ad.registered + ad_lifetime).saturating_duration_since(Instant::now());
+            return Ok(self.issue_ticket(node_id, topic, now, wait_time));
+        }
+
+        let mut wait_time = self.wait_time(&topic);
+        if wait_time == Duration::from_secs(0) {
+            // Space is available, but it is kept for advertisers that have waited longer until
+            // their tickets lapse.
+            if let Some(expires) = self.longer_waiters_expire(&topic, &node_id, first_issued) {
+                wait_time = expires.saturating_sub(now);
+            }
+        }
+        if wait_time > Duration::from_secs(0) {
+            let waiters = self.waiting.entry(topic).or_default();
+            if !waiters.contains_key(&node_id) {
+                if self.waiting_len >= self.table_capacity {
+                    return Err("Too many advertisers waiting");
+                }
+                self.waiting_len += 1;
+            }
+            waiters.insert(
+                node_id,
+                Waiter {
+                    first_issued,
+                    expires: now + wait_time + REGISTRATION_WINDOW,
+                },
+            );
+            return Ok(self.issue_ticket(node_id, topic, first_issued, wait_time));
+        }
+
+        if let Some(waiters) = self.waiting.get_mut(&topic) {
+            if waiters.remove(&node_id).is_some() {
+                self.waiting_len -= 1;
+            }
+        }
+        self.topics.entry(topic).or_default().push_back(Ad {
+            enr,
+            registered: Instant::now(),
+        });
+        self.len += 1;
+        Ok(Registration::Registered)
+    }
+
+    /// Returns the ENRs currently advertising `topic`, oldest first.
+    pub fn ads(&mut self, topic: &TopicHash) -> Vec<Enr> {
+        self.prune();
+        self.topics
+            .get(topic)
+            .map(|queue| queue.iter().map(|ad| ad.enr.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The time until an advertisement for `topic` can be stored.
+    fn wait_time(&self, topic: &TopicHash) -> Duration {
+        let now = Instant::now();
+        let remaining = |queue: &VecDeque<Ad>| {
+            queue
+                .front()
+                .map(|ad| (ad.registered + self.ad_lifetime).saturating_duration_since(now))
+        };
+
+        let mut wait_time = Duration::from_secs(0);
+        if let Some(queue) = self.topics.get(topic) {
+            if queue.len() >= self.queue_capacity {
+                wait_time = remaining(queue).unwrap_or(wait_time);
+            }
+        }
+        if self.len >= self.table_capacity {
+            if let Some(table_wait) = self.topics.values().filter_map(remaining).min() {
+                wait_time = std::cmp::max(wait_time, table_wait);
+            }
+        }
+        wait_time
+    }
+
+    /// The time at which the tickets of all advertisers that have waited longer than
+    /// `first_issued` for `topic` lapse, if there are any such advertisers.
+    fn longer_waiters_expire(
+        &self,
+        topic: &TopicHash,
+        node_id: &NodeId,
+        first_issued: Duration,
+    ) -> Option<Duration> {
+        self.waiting
+            .get(topic)?
+            .iter()
+            .filter(|(waiter_id, waiter)| {
+                *waiter_id != node_id && waiter.first_issued < first_issued
+            })
+            .map(|(_, waiter)| waiter.expires)
+            .max()
+    }
+
+    /// Removes all expired advertisements and the advertisers whose tickets have lapsed.
+    fn prune(&mut self) {
+        let ad_lifetime = self.ad_lifetime;
+        let mut removed = 0;
+        self.topics.retain(|_, queue| {
+            while let Some(ad) = queue.front() {
+                if ad.registered.elapsed() < ad_lifetime {
+                    break;
+                }
+                queue.pop_front();
+                removed += 1;
+            }
+            !queue.is_empty()
+        });
+        self.len -= removed;
+
+        let now = self.created.elapsed();
+        let mut lapsed = 0;
+        self.waiting.retain(|_, waiters| {
+            let waiting = waiters.len();
+            waiters.retain(|_, waiter| waiter.expires > now);
+            lapsed += waiting - waiters.len();
+            !waiters.is_empty()
+        });
+        self.waiting_len -= lapsed;
+    }
+
+    fn issue_ticket(
+        &self,
+        node_id: NodeId,
+        topic: TopicHash,
+        first_issued: Duration,
+        wait_time: Duration,
+    ) -> Registration {
+        let ticket = Ticket {
+            node_id,
+            topic,
+            first_issued: first_issued.as_millis() as u64,
+            issued: self.created.elapsed().as_millis() as u64,
+            wait_time: wait_time.as_millis() as u64,
+        };
+        Registration::Ticket {
+            ticket: self.encode_ticket(&ticket),
+            wait_time,
+        }
+    }
+
+    /// RLP encodes and encrypts a ticket.
+    fn encode_ticket(&self, ticket: &Ticket) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        s.begin_list(5);
+        s.append(&(&ticket.node_id.raw() as &[u8]));
+        s.append(&(&ticket.topic as &[u8]));
+        s.append(&ticket.first_issued);
+        s.append(&ticket.issued);
+        s.append(&ticket.wait_time);
+
+        let nonce: [u8; TICKET_NONCE_LENGTH] = rand::random();
+        let aead = Aes128Gcm::new(&self.ticket_key.into());
+        let cipher = aead
+            .encrypt(&nonce.into(), s.out().as_ref())
+            .expect("Encryption of a ticket cannot fail");
+        let mut encoded = nonce.to_vec();
+        encoded.extend_from_slice(&cipher);
+        encoded
+    }
+
+    /// Decrypts and decodes a ticket issued by this node.
+    fn decode_ticket(&self, ticket: &[u8]) -> Result<Ticket, &'static str> {
+        if ticket.len() <= TICKET_NONCE_LENGTH {
+            return Err("Ticket is too short");
+        }
+        let (nonce, cipher) = ticket.split_at(TICKET_NONCE_LENGTH);
+        let mut nonce_bytes = [0u8; TICKET_NONCE_LENGTH];
+        nonce_bytes.copy_from_slice(nonce);
+        let aead = Aes128Gcm::new(&self.ticket_key.into());
+        let plain = aead
+            .decrypt(&nonce_bytes.into(), cipher)
+            .map_err(|_| "Ticket was not issued by this node")?;
+
+        let rlp = rlp::Rlp::new(&plain);
+        let decode_err = |_| "Invalid ticket encoding";
+        let node_id = NodeId::parse(&rlp.val_at::<Vec<u8>>(0).map_err(decode_err)?)?;
+        let topic_bytes = rlp.val_at::<Vec<u8>>(1).map_err(decode_err)?;
+        if topic_bytes.len() != 32 {
+            return Err("Invalid ticket topic");
+        }
+        let mut topic = [0u8; 32];
+        topic.copy_from_slice(&topic_bytes);
+        Ok(Ticket {
+            node_id,
+            topic,
+            first_issued: rlp.val_at::<u64>(2).map_err(decode_err)?,
+            issued: rlp.val_at::<u64>(3).map_err(decode_err)?,
+            wait_time: rlp.val_at::<u64>(4).map_err(decode_err)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, EnrBuilder};
+
+    fn enr() -> Enr {
+        let key = CombinedKey::generate_secp256k1();
+        EnrBuilder::new("v4").build(&key).unwrap()
+    }
+
+    #[test]
+    fn registers_while_space_available() {
+        let mut table = TopicTable::new(10, 2, Duration::from_secs(60));
+        let enr = enr();
+        let result = table.register(enr.node_id(), b"topic", enr.clone(), &[]);
+        assert!(matches!(result, Ok(Registration::Registered)));
+        assert_eq!(table.ads(&topic_hash(b"topic")), vec![enr]);
+    }
+
+    #[test]
+    fn issues_ticket_when_queue_full() {
+        let mut table = TopicTable::new(10, 2, Duration::from_secs(60));
+        for _ in 0..2 {
+            let enr = enr();
+            assert!(matches!(
+                table.register(enr.node_id(), b"topic", enr.clone(), &[]),
+                Ok(Registration::Registered)
+            ));
+        }
+
+        let enr = enr();
+        match table.register(enr.node_id(), b"topic", enr.clone(), &[]) {
+            Ok(Registration::Ticket { wait_time, .. }) => {
+                assert!(wait_time > Duration::from_secs(50))
+            }
+            _ => panic!("Expected a ticket"),
+        }
+        // other topics are unaffected
+        assert!(matches!(
+            table.register(enr.node_id(), b"other", enr.clone(), &[]),
+            Ok(Registration::Registered)
+        ));
+        assert_eq!(table.len, 3);
+    }
+
+    #[test]
+    fn issues_ticket_when_table_full() {
+        let mut table = TopicTable::new(2, 2, Duration::from_secs(60));
+        for topic in [b"a", b"b"].iter() {
+            let enr = enr();
+            assert!(matches!(
+                table.register(enr.node_id(), *topic, enr.clone(), &[]),
+                Ok(Registration::Registered)
+            ));
+        }
+        let enr = enr();
+        assert!(matches!(
+            table.register(enr.node_id(), b"c", enr.clone(), &[]),
+            Ok(Registration::Ticket { .. })
+        ));
+    }
+
+    #[test]
+    fn turns_away_advertisers_when_too_many_wait() {
+        let mut table = TopicTable::new(2, 2, Duration::from_secs(60));
+        for topic in [b"a", b"b"].iter() {
+            let enr = enr();
+            table.register(enr.node_id(), *topic, enr, &[]).unwrap();
+        }
+
+        // as many advertisers can wait as there is space in the table
+        let waiters = [enr(), enr()];
+        for (waiter, topic) in waiters.iter().zip([b"c", b"d"].iter()) {
+            assert!(matches!(
+                table.register(waiter.node_id(), *topic, waiter.clone(), &[]),
+                Ok(Registration::Ticket { .. })
+            ));
+        }
+        let enr = enr();
+        assert!(table.register(enr.node_id(), b"e", enr, &[]).is_err());
+        assert_eq!(table.waiting_len, 2);
+
+        // advertisers already waiting are still issued tickets
+        assert!(matches!(
+            table.register(waiters[0].node_id(), b"c", waiters[0].clone(), &[]),
+            Ok(Registration::Ticket { .. })
+        ));
+        assert_eq!(table.waiting_len, 2);
+    }
+
+    #[test]
+    fn rejects_foreign_tickets() {
+        let mut table = TopicTable::new(1, 1, Duration::from_secs(60));
+        let enr1 = enr();
+        let enr2 = enr();
+        table
+            .register(enr1.node_id(), b"topic", enr1.clone(), &[])
+            .unwrap();
+        let ticket = match table.register(enr2.node_id(), b"topic", enr2.clone(), &[]) {
+            Ok(Registration::Ticket { ticket, .. }) => ticket,
+            _ => panic!("Expected a ticket"),
+        };
+
+        // a ticket issued to another node
+        let enr3 = enr();
+        assert!(table
+            .register(enr3.node_id(), b"topic", enr3, &ticket)
+            .is_err());
+
+        // a ticket issued by another registrar
+        let other_table = TopicTable::new(1, 1, Duration::from_secs(60));
+        assert!(other_table.decode_ticket(&ticket).is_err());
+        assert_eq!(
+            table.decode_ticket(&ticket).unwrap().node_id,
+            enr2.node_id()
+        );
+    }
+
+    #[test]
+    fn registers_with_ticket_after_expiry() {
+        let mut table = TopicTable::new(1, 1, Duration::from_millis(100));
+        let enr1 = enr();
+        let enr2 = enr();
+        table.register(enr1.node_id(), b"topic", enr1, &[]).unwrap();
+        let ticket = match table.register(enr2.node_id(), b"topic", enr2.clone(), &[]) {
+            Ok(Registration::Ticket { ticket, wait_time }) => {
+                assert!(wait_time <= Duration::from_millis(100));
+                ticket
+            }
+            _ => panic!("Expected a ticket"),
+        };
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(
+            table.register(enr2.node_id(), b"topic", enr2.clone(), &ticket),
+            Ok(Registration::Registered)
+        ));
+        assert_eq!(table.ads(&topic_hash(b"topic")), vec![enr2]);
+        assert_eq!(table.len, 1);
+    }
+
+    #[test]
+    fn longest_waiting_registers_first() {
+        let mut table = TopicTable::new(10, 1, Duration::from_millis(100));
+        let (enr1, enr2, enr3) = (enr(), enr(), enr());
+        table.register(enr1.node_id(), b"topic", enr1, &[]).unwrap();
+
+        // the queue is full, so the next advertisers wait
+        let ticket2 = match table.register(enr2.node_id(), b"topic", enr2.clone(), &[]) {
+            Ok(Registration::Ticket { ticket, .. }) => ticket,
+            _ => panic!("Expected a ticket"),
+        };
+        std::thread::sleep(Duration::from_millis(10));
+        let ticket3 = match table.register(enr3.node_id(), b"topic", enr3.clone(), &[]) {
+            Ok(Registration::Ticket { ticket, .. }) => ticket,
+            _ => panic!("Expected a ticket"),
+        };
+        let first_issued3 = table.decode_ticket(&ticket3).unwrap().first_issued;
+
+        // once space is available, the advertiser that has waited longer is registered
+        std::thread::sleep(Duration::from_millis(150));
+        let ticket3 = match table.register(enr3.node_id(), b"topic", enr3.clone(), &ticket3) {
+            Ok(Registration::Ticket { ticket, .. }) => ticket,
+            _ => panic!("Expected a ticket"),
+        };
+        let newcomer = enr();
+        assert!(matches!(
+            table.register(newcomer.node_id(), b"topic", newcomer.clone(), &[]),
+            Ok(Registration::Ticket { .. })
+        ));
+        assert!(matches!(
+            table.register(enr2.node_id(), b"topic", enr2.clone(), &ticket2),
+            Ok(Registration::Registered)
+        ));
+        assert_eq!(table.ads(&topic_hash(b"topic")), vec![enr2]);
+
+        // the time waited is kept by tickets issued in place of a presented ticket
+        let reissued = table.decode_ticket(&ticket3).unwrap();
+        assert_eq!(reissued.first_issued, first_issued3);
+        assert!(reissued.issued > first_issued3);
+    }
+}
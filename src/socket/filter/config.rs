@@ -1,3 +1,18 @@
+/// What the packet filter does with a node exceeding `max_requests_per_node_per_second`.
+///
+/// The filter runs before packets are decrypted, so the node id it acts on is taken from the
+/// unauthenticated packet header and can be spoofed by anyone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Drop the node's excess packets.
+    Drop,
+    /// Drop the node's excess packets and log a warning.
+    DropAndLog,
+    /// Drop the node's excess packets and ban the node for the configured ban duration. As the
+    /// node id is not authenticated, an attacker can use this to ban any other node.
+    Ban,
+}
+
 #[derive(Debug, Clone)]
 pub struct FilterConfig {
     /// Whether the packet filter is enabled or not.
@@ -8,6 +23,9 @@ pub struct FilterConfig {
     /// The maximum number of requests per NodeId per second. This must be less than
     /// `max_requests_per_second`.
     pub max_requests_per_node_per_second: Option<f64>,
+    /// The action taken against a node exceeding `max_requests_per_node_per_second`. Defaults to
+    /// `RateLimitAction::Drop`.
+    pub node_rate_limit_action: RateLimitAction,
     /// The maximum requests tolerated per IP per second. This must be less than
     /// `max_requests_per_second`.
     pub max_requests_per_ip_per_second: Option<f64>,
@@ -19,6 +37,7 @@ impl Default for FilterConfig {
             enabled: false,
             max_requests_per_second: 10,
             max_requests_per_node_per_second: Some(10.0),
+            node_rate_limit_action: RateLimitAction::Drop,
             max_requests_per_ip_per_second: Some(10.0),
         }
     }
@@ -56,6 +75,13 @@ impl FilterConfigBuilder {
         self
     }
 
+    /// Sets the action taken against a node exceeding its request limit. See `RateLimitAction`
+    /// for why banning is unsafe against spoofed node ids.
+    pub fn node_rate_limit_action(&mut self, action: RateLimitAction) -> &mut Self {
+        self.config.node_rate_limit_action = action;
+        self
+    }
+
     /// Sets the maximum unsolicited requests per ip per second.
     pub fn max_requests_per_ip_per_second(&mut self, reqs_per_ip_per_second: f64) -> &mut Self {
         self.config.max_requests_per_ip_per_second = Some(reqs_per_ip_per_second);
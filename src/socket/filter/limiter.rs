@@ -0,0 +1,117 @@
+//! Keyed token buckets used by the packet filter to limit request rates.
+//!
+//! Each key owns a bucket that refills at a constant rate up to a fixed capacity, and each
+//! request consumes a token. Checking a request is O(1). A bucket that has refilled completely
+//! carries no state, so such buckets are pruned, which keeps the memory used proportional to the
+//! number of keys seen within the last burst window.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// The number of buckets held before the first prune.
+const MIN_PRUNE_THRESHOLD: usize = 64;
+
+/// The tokens of a single key.
+struct Bucket {
+    /// The tokens available at `updated`.
+    tokens: f64,
+    /// The last time the bucket was refilled.
+    updated: Instant,
+}
+
+/// A rate limiter holding a token bucket per key.
+pub struct RateLimiter<K> {
+    /// The tokens added to each bucket per second.
+    rate: f64,
+    /// The maximum number of tokens a bucket holds, i.e. the largest burst allowed.
+    capacity: f64,
+    /// The buckets of keys that have recently made requests.
+    buckets: HashMap<K, Bucket>,
+    /// The number of buckets at which the next prune occurs.
+    prune_threshold: usize,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Creates a rate limiter allowing `rate` requests per second for each key, in bursts of up
+    /// to `burst` worth of requests. Each key may always make at least one request.
+    pub fn new(rate: f64, burst: Duration) -> Self {
+        RateLimiter {
+            rate,
+            capacity: (rate * burst.as_secs_f64()).max(1.0),
+            buckets: HashMap::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Records a request by `key` at `now`, returning whether it is within the limit.
+    pub fn allow(&mut self, key: K, now: Instant) -> bool {
+        if self.buckets.len() >= self.prune_threshold {
+            self.prune(now);
+        }
+
+        let (rate, capacity) = (self.rate, self.capacity);
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes the buckets that have refilled completely. The threshold of the next prune
+    /// doubles with the number of buckets remaining, so pruning is amortized over requests.
+    fn prune(&mut self, now: Instant) {
+        let (rate, capacity) = (self.rate, self.capacity);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < capacity
+        });
+        self.prune_threshold = (self.buckets.len() * 2).max(MIN_PRUNE_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_are_limited_and_refilled() {
+        let mut limiter = RateLimiter::new(2.0, Duration::from_secs(2));
+        let now = Instant::now();
+
+        // a burst of the capacity is allowed, after which the bucket is empty
+        assert_eq!((0..6).filter(|_| limiter.allow(1, now)).count(), 4);
+        // other keys have their own buckets
+        assert!(limiter.allow(2, now));
+
+        // tokens are added at the rate
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(1, later));
+        assert!(!limiter.allow(1, later));
+    }
+
+    #[test]
+    fn refilled_buckets_are_pruned() {
+        let mut limiter = RateLimiter::new(1.0, Duration::from_secs(1));
+        let now = Instant::now();
+        for key in 0..MIN_PRUNE_THRESHOLD {
+            limiter.allow(key, now);
+        }
+        assert_eq!(limiter.buckets.len(), MIN_PRUNE_THRESHOLD);
+
+        // once refilled, the earlier buckets are dropped on the next insert
+        limiter.allow(MIN_PRUNE_THRESHOLD, now + Duration::from_secs(1));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
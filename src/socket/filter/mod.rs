@@ -1,164 +1,251 @@
 //! A filter which decides whether to accept/reject incoming UDP packets.
-
-use crate::{discv5::PERMIT_BAN_LIST, metrics::METRICS, node_info::NodeAddress, packet::Packet};
-use cache::ReceivedPacketCache;
+//!
+//! Request rates are limited by token buckets, globally and per IP or node id, so each packet is
+//! checked in constant time.
+
+use crate::{
+    metrics::InternalMetrics, node_info::NodeAddress, packet::Packet, permit_ban::BanReason,
+    PermitBanList,
+};
 use enr::NodeId;
-use std::{collections::HashMap, net::SocketAddr, sync::atomic::Ordering};
+pub(crate) use limiter::RateLimiter;
+use parking_lot::RwLock;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
 use tracing::{debug, warn};
 
-mod cache;
 mod config;
-pub use config::{FilterConfig, FilterConfigBuilder};
+mod limiter;
+pub use config::{FilterConfig, FilterConfigBuilder, RateLimitAction};
 
-/// The maximum percentage of our unsolicited requests per second limit a node is able to consume
-/// for  `NUMBER_OF_WINDOWS` duration before being banned.
-/// This allows us to ban the IP/NodeId of an attacker spamming us with requests.
-const MAX_PERCENT_OF_LIMIT_PER_NODE: f64 = 0.9;
-/// The number of windows to remember before banning a node.
-const NUMBER_OF_WINDOWS: usize = 5;
+/// The period of requests a token bucket can absorb in a single burst.
+const BURST_WINDOW: Duration = Duration::from_secs(1);
 
 /// The packet filter which decides whether we accept or reject incoming packets.
 pub(crate) struct Filter {
     /// Configuration for the packet filter.
     config: FilterConfig,
-    /// An ordered (by time) collection of recently seen packets by SocketAddr. The packet data is not
-    /// stored here. This stores a 5 seconds of history to calculate a 5 second moving average for
-    /// the metrics.
-    raw_packets_received: ReceivedPacketCache<SocketAddr>,
-    /// An ordered (by time) collection of seen NodeIds that have passed the first filter check and
-    /// have an associated NodeId.
-    received_by_node: ReceivedPacketCache<NodeId>,
+    /// Limits the rate of all unsolicited packets to `max_requests_per_second`.
+    total_limiter: RateLimiter<()>,
+    /// Limits the rate of unsolicited packets from each IP, if configured.
+    ip_limiter: Option<RateLimiter<IpAddr>>,
+    /// Limits the rate of unsolicited packets from each node id, if configured.
+    node_limiter: Option<RateLimiter<NodeId>>,
+    /// The permit/ban list of the server.
+    permit_ban_list: Arc<RwLock<PermitBanList>>,
+    /// The duration of bans issued for exceeding the request limit.
+    ban_duration: Option<Duration>,
+    /// The metrics of the server, updated as packets are received.
+    metrics: Arc<InternalMetrics>,
+    /// The channel on which bans issued by the filter are reported.
+    ban_events: mpsc::UnboundedSender<(NodeId, BanReason)>,
 }
 
 impl Filter {
-    pub fn new(config: &FilterConfig) -> Filter {
+    pub fn new(
+        config: &FilterConfig,
+        permit_ban_list: Arc<RwLock<PermitBanList>>,
+        ban_duration: Option<Duration>,
+        metrics: Arc<InternalMetrics>,
+        ban_events: mpsc::UnboundedSender<(NodeId, BanReason)>,
+    ) -> Filter {
         Filter {
             config: config.clone(),
-            raw_packets_received: ReceivedPacketCache::new(
-                config.max_requests_per_second,
-                METRICS.moving_window,
-            ),
-            received_by_node: ReceivedPacketCache::new(
-                config.max_requests_per_second * NUMBER_OF_WINDOWS,
-                METRICS.moving_window,
-            ),
+            total_limiter: RateLimiter::new(config.max_requests_per_second as f64, BURST_WINDOW),
+            ip_limiter: config
+                .max_requests_per_ip_per_second
+                .map(|rate| RateLimiter::new(rate, BURST_WINDOW)),
+            node_limiter: config
+                .max_requests_per_node_per_second
+                .map(|rate| RateLimiter::new(rate, BURST_WINDOW)),
+            permit_ban_list,
+            ban_duration,
+            metrics,
+            ban_events,
         }
     }
 
     /// The first check. This determines if a new UDP packet should be decoded or dropped.
     /// Only unsolicited packets arrive here.
     pub fn initial_pass(&mut self, src: &SocketAddr) -> bool {
-        if PERMIT_BAN_LIST.read().permit_ips.get(&src.ip()).is_some() {
+        if self.permit_ban_list.read().is_ip_permitted(&src.ip()) {
             return true;
         }
 
-        if PERMIT_BAN_LIST.read().ban_ips.get(&src.ip()).is_some() {
+        if self.permit_ban_list.read().is_ip_banned(&src.ip()) {
             debug!("Dropped unsolicited packet from banned src: {:?}", src);
+            self.metrics.packet_dropped("banned_ip");
             return false;
         }
 
-        // Add the un-solicited request to the cache
-        // If this is over the maximum requests per ENFORCED_SIZE_TIME, it will be rejected and return false.
-        let result = self.raw_packets_received.cache_insert(*src);
-
-        // build the metrics
-        METRICS
-            .unsolicited_requests_per_window
-            .store(self.raw_packets_received.len(), Ordering::Relaxed);
-
-        // It might not be worth the effort keeping an average for the metrics in the cache.
-        // Builds the hashmap of IPs to requests
-        let hashmap = {
-            let mut hashmap = HashMap::with_capacity(10);
-            for ip in self
-                .raw_packets_received
-                .iter()
-                .map(|packet| packet.content.ip())
-            {
-                *hashmap.entry(ip).or_default() += 1.0 / (METRICS.moving_window as f64);
-            }
-            hashmap
-        };
-        *METRICS.requests_per_ip_per_second.write() = hashmap;
+        // Only packets within the overall limit are recorded in the metrics, which bounds the
+        // number of sources they track.
+        let now = Instant::now();
+        let within_limit = self.total_limiter.allow((), now);
+        if within_limit {
+            self.metrics.unsolicited_request(src.ip(), now);
+        }
 
         // run the filters
         if self.config.enabled {
+            if !within_limit {
+                self.metrics.packet_dropped("rate_limit");
+                return false;
+            }
             // if there is a restriction per IP, enforce it
-            if let Some(max_requests_per_ip_per_second) = self.config.max_requests_per_ip_per_second
-            {
-                if let Some(requests) = METRICS.requests_per_ip_per_second.read().get(&src.ip()) {
-                    if requests >= &max_requests_per_ip_per_second {
-                        debug!(
-                            "Dropped unsolicited packet from IP rate limit: {:?}",
-                            src.ip()
-                        );
-                        return false;
-                    }
+            if let Some(ip_limiter) = self.ip_limiter.as_mut() {
+                if !ip_limiter.allow(src.ip(), now) {
+                    debug!(
+                        "Dropped unsolicited packet from IP rate limit: {:?}",
+                        src.ip()
+                    );
+                    self.metrics.packet_dropped("ip_rate_limit");
+                    return false;
                 }
             }
-            result // filter based on whether the packet could get added to the cache or not
-        } else {
-            true
         }
+        true
     }
 
     pub fn final_pass(&mut self, node_address: &NodeAddress, _packet: &Packet) -> bool {
-        if PERMIT_BAN_LIST
+        if self
+            .permit_ban_list
             .read()
             .permit_nodes
-            .get(&node_address.node_id)
-            .is_some()
+            .contains(&node_address.node_id)
         {
             return true;
         }
 
-        if PERMIT_BAN_LIST
+        if self
+            .permit_ban_list
             .read()
-            .ban_nodes
-            .get(&node_address.node_id)
-            .is_some()
+            .is_node_banned(&node_address.node_id)
         {
             debug!(
                 "Dropped unsolicited packet from banned node_id: {}",
                 node_address
             );
+            self.metrics.packet_dropped("banned_node");
             return false;
         }
 
         if self.config.enabled {
-            // Add the un-solicited request to the cache
-            // If this is over the maximum requests per ENFORCED_SIZE_TIME, it will be rejected and return false.
-            // The unsolicited filter via IP should ensure this is never reached.
-            if !self.received_by_node.cache_insert(node_address.node_id) {
-                warn!("Message rejected as reached the maximum request limit for NodeId's");
-                return false;
-            }
-
-            // If a single node has used > MAX_PERCENT_OF_LIMIT_PER_NODE of unsolicited
-            // requests, ban them.
-            // If we have reached our maximum limit each time, the maximum number of messages is:
-            // max_requests_per_second*METRICS.moving_window.
-            if self
-                .received_by_node
-                .iter()
-                .filter(|x| x.content == node_address.node_id)
-                .count() as f64
-                > self.config.max_requests_per_second as f64
-                    * METRICS.moving_window as f64
-                    * MAX_PERCENT_OF_LIMIT_PER_NODE
-            {
-                warn!(
-                    "Node has exceeded its request limit and is now banned {}",
-                    node_address.node_id
-                );
-                PERMIT_BAN_LIST
-                    .write()
-                    .ban_nodes
-                    .insert(node_address.node_id);
-                return false;
+            let now = Instant::now();
+            self.metrics.node_request(node_address.node_id, now);
+
+            // if there is a restriction per node, enforce it
+            if let Some(node_limiter) = self.node_limiter.as_mut() {
+                if !node_limiter.allow(node_address.node_id, now) {
+                    match self.config.node_rate_limit_action {
+                        RateLimitAction::Drop => {}
+                        RateLimitAction::DropAndLog => {
+                            warn!(
+                                "Dropped unsolicited packet from node rate limit: {}",
+                                node_address
+                            );
+                        }
+                        RateLimitAction::Ban => {
+                            warn!(
+                                "Node has exceeded its request limit and is now banned {}",
+                                node_address.node_id
+                            );
+                            self.permit_ban_list.write().ban_node(
+                                node_address.node_id,
+                                BanReason::RateLimit,
+                                self.ban_duration,
+                            );
+                            self.metrics.banned(BanReason::RateLimit);
+                            let _ = self
+                                .ban_events
+                                .send((node_address.node_id, BanReason::RateLimit));
+                        }
+                    }
+                    self.metrics.packet_dropped("node_rate_limit");
+                    return false;
+                }
             }
         }
 
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `count` packets from `node_id` through the final pass, returning how many passed.
+    fn final_passes(filter: &mut Filter, node_id: NodeId, count: usize) -> usize {
+        let node_address = NodeAddress {
+            socket_addr: "127.0.0.1:9000".parse().unwrap(),
+            node_id,
+        };
+        let packet = Packet::new_random(&node_id).unwrap();
+        (0..count)
+            .filter(|_| filter.final_pass(&node_address, &packet))
+            .count()
+    }
+
+    fn filter(
+        action: RateLimitAction,
+    ) -> (
+        Filter,
+        Arc<RwLock<PermitBanList>>,
+        mpsc::UnboundedReceiver<(NodeId, BanReason)>,
+    ) {
+        let config = FilterConfig {
+            enabled: true,
+            max_requests_per_node_per_second: Some(1.0),
+            node_rate_limit_action: action,
+            ..Default::default()
+        };
+        let permit_ban_list = Arc::new(RwLock::new(PermitBanList::default()));
+        let metrics = Arc::new(InternalMetrics::default());
+        let (ban_events_send, ban_events) = mpsc::unbounded_channel();
+        let filter = Filter::new(
+            &config,
+            permit_ban_list.clone(),
+            Some(Duration::from_secs(60)),
+            metrics,
+            ban_events_send,
+        );
+        (filter, permit_ban_list, ban_events)
+    }
+
+    #[test]
+    fn node_rate_limit_drops_excess_packets() {
+        let (mut filter, permit_ban_list, mut ban_events) = filter(RateLimitAction::Drop);
+        let node_id = NodeId::random();
+
+        // one request per second, in bursts of a single request
+        assert_eq!(final_passes(&mut filter, node_id, 8), 1);
+        assert!(!permit_ban_list.read().is_node_banned(&node_id));
+        assert!(ban_events.try_recv().is_err());
+        let metrics = crate::metrics::Metrics::from(&*filter.metrics);
+        let requests = metrics.requests_per_node_per_second[&node_id];
+        assert!((requests - 8.0 / 5.0).abs() < 1e-3);
+
+        // other nodes are unaffected
+        assert_eq!(final_passes(&mut filter, NodeId::random(), 1), 1);
+    }
+
+    #[test]
+    fn node_rate_limit_bans_offending_node() {
+        let (mut filter, permit_ban_list, mut ban_events) = filter(RateLimitAction::Ban);
+        let node_id = NodeId::random();
+
+        assert_eq!(final_passes(&mut filter, node_id, 8), 1);
+        let ban = permit_ban_list.read().ban_nodes[&node_id].clone();
+        assert_eq!(ban.reason, BanReason::RateLimit);
+        assert!(ban.remaining().unwrap() <= Duration::from_secs(60));
+        assert_eq!(
+            ban_events.try_recv().unwrap(),
+            (node_id, BanReason::RateLimit)
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! An in-memory [`Transport`] for simulating networks of many nodes in a single process.
+//!
+//! Each [`MemoryTransport`] is bound to an address on a shared [`MemoryNetwork`]. Datagrams are
+//! delivered between transports subject to the network's [`NetworkConditions`], which can add
+//! latency, reordering and loss. A transport can also be placed behind a simulated NAT, which
+//! rewrites its source address and drops datagrams from peers it has not sent to first.
+//!
+//! Random decisions are drawn from a seeded RNG, so a simulation can be repeated.
+
+use super::transport::Transport;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// A datagram in flight, with its source address.
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// The conditions applied to every datagram sent over a [`MemoryNetwork`].
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// The delay before a datagram is delivered.
+    pub latency: Duration,
+    /// The maximum random delay added on top of `latency`. Datagrams sent in quick succession may
+    /// be delivered out of order.
+    pub jitter: Duration,
+    /// The probability, between 0 and 1, that a datagram is dropped.
+    pub loss: f64,
+}
+
+/// A simulated network that [`MemoryTransport`]s are bound to.
+///
+/// Cloning the network returns another handle to the same network.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+struct Network {
+    /// The conditions applied to each datagram.
+    conditions: NetworkConditions,
+    /// The RNG deciding loss and jitter.
+    rng: StdRng,
+    /// The bound transports, by the address peers reach them on.
+    endpoints: HashMap<SocketAddr, Endpoint>,
+}
+
+struct Endpoint {
+    /// Delivers datagrams to the transport.
+    sender: mpsc::UnboundedSender<Datagram>,
+    /// For transports behind a NAT, the addresses the transport has sent to. Datagrams from any
+    /// other address are dropped.
+    nat_mappings: Option<HashSet<SocketAddr>>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        MemoryNetwork::new(NetworkConditions::default(), 0)
+    }
+}
+
+impl MemoryNetwork {
+    /// Creates a network with the given conditions. `seed` seeds the RNG used for loss and jitter.
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        MemoryNetwork {
+            inner: Arc::new(Mutex::new(Network {
+                conditions,
+                rng: StdRng::seed_from_u64(seed),
+                endpoints: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Replaces the conditions applied to datagrams sent from now on.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        self.inner.lock().conditions = conditions;
+    }
+
+    /// Binds a transport, reachable by all peers, to `addr`.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        self.bind_endpoint(addr, addr, None)
+    }
+
+    /// Binds a transport to the `internal` address behind a NAT with the `external` address.
+    ///
+    /// Datagrams sent by the transport appear to come from `external`. Datagrams sent to
+    /// `external` are only delivered if the transport has previously sent to their source.
+    pub fn bind_behind_nat(
+        &self,
+        internal: SocketAddr,
+        external: SocketAddr,
+    ) -> io::Result<MemoryTransport> {
+        self.bind_endpoint(internal, external, Some(HashSet::new()))
+    }
+
+    fn bind_endpoint(
+        &self,
+        local_addr: SocketAddr,
+        public_addr: SocketAddr,
+        nat_mappings: Option<HashSet<SocketAddr>>,
+    ) -> io::Result<MemoryTransport> {
+        let mut network = self.inner.lock();
+        // addresses of dropped transports can be re-used
+        if let Some(endpoint) = network.endpoints.get(&public_addr) {
+            if !endpoint.sender.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Address already bound: {}", public_addr),
+                ));
+            }
+        }
+        let (sender, recv) = mpsc::unbounded_channel();
+        network.endpoints.insert(
+            public_addr,
+            Endpoint {
+                sender,
+                nat_mappings,
+            },
+        );
+        Ok(MemoryTransport {
+            network: self.clone(),
+            local_addr,
+            public_addr,
+            recv: tokio::sync::Mutex::new(recv),
+        })
+    }
+
+    /// Routes a datagram from the transport reachable at `src`, applying the network conditions.
+    fn send(&self, data: &[u8], src: SocketAddr, dst: SocketAddr) {
+        let (sender, delay) = {
+            let mut network = self.inner.lock();
+            // sending opens the sender's NAT for the destination
+            if let Some(mappings) = network
+                .endpoints
+                .get_mut(&src)
+                .and_then(|endpoint| endpoint.nat_mappings.as_mut())
+            {
+                mappings.insert(dst);
+            }
+
+            let conditions = network.conditions.clone();
+            if conditions.loss > 0.0 && network.rng.gen_bool(conditions.loss.min(1.0)) {
+                return;
+            }
+            let jitter = if conditions.jitter > Duration::from_secs(0) {
+                network
+                    .rng
+                    .gen_range(Duration::from_secs(0), conditions.jitter)
+            } else {
+                Duration::from_secs(0)
+            };
+
+            let endpoint = match network.endpoints.get(&dst) {
+                Some(endpoint) => endpoint,
+                None => return,
+            };
+            if let Some(mappings) = endpoint.nat_mappings.as_ref() {
+                if !mappings.contains(&src) {
+                    return;
+                }
+            }
+            (endpoint.sender.clone(), conditions.latency + jitter)
+        };
+
+        let datagram = (data.to_vec(), src);
+        if delay == Duration::from_secs(0) {
+            let _ = sender.send(datagram);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = sender.send(datagram);
+            });
+        }
+    }
+}
+
+/// A transport bound to an address on a [`MemoryNetwork`].
+pub struct MemoryTransport {
+    /// The network the transport is bound to.
+    network: MemoryNetwork,
+    /// The address the transport is bound to.
+    local_addr: SocketAddr,
+    /// The address peers reach the transport on. This differs from `local_addr` behind a NAT.
+    public_addr: SocketAddr,
+    /// The datagrams delivered to the transport.
+    recv: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Transport for MemoryTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+        self.network.send(buf, self.public_addr, dst);
+        Box::pin(futures::future::ready(Ok(buf.len())))
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(async move {
+            let (data, src) = self.recv.lock().await.recv().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "Network has shut down")
+            })?;
+            // datagrams larger than the buffer are truncated, as with UDP
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, src))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network
+            .inner
+            .lock()
+            .endpoints
+            .remove(&self.public_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn recv(transport: &MemoryTransport) -> Option<Datagram> {
+        let mut buf = [0u8; 16];
+        let recv = transport.recv_from(&mut buf);
+        match tokio::time::timeout(Duration::from_millis(100), recv).await {
+            Ok(Ok((len, src))) => Some((buf[..len].to_vec(), src)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_delivered() {
+        let network = MemoryNetwork::default();
+        let a = network.bind("10.0.0.1:9000".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:9000".parse().unwrap()).unwrap();
+
+        a.send_to(&[1, 2, 3], b.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&b).await,
+            Some((vec![1, 2, 3], a.local_addr().unwrap()))
+        );
+        assert!(network.bind(b.local_addr().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn lossy_network_drops_datagrams() {
+        let conditions = NetworkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::new(conditions, 1);
+        let a = network.bind("10.0.0.1:9000".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:9000".parse().unwrap()).unwrap();
+
+        a.send_to(&[1], b.local_addr().unwrap()).await.unwrap();
+        assert_eq!(recv(&b).await, None);
+    }
+
+    #[tokio::test]
+    async fn nat_drops_unsolicited_datagrams() {
+        let network = MemoryNetwork::default();
+        let external: SocketAddr = "1.1.1.1:9000".parse().unwrap();
+        let natted = network
+            .bind_behind_nat("192.168.0.1:9000".parse().unwrap(), external)
+            .unwrap();
+        let peer = network.bind("10.0.0.2:9000".parse().unwrap()).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        // unsolicited datagrams are dropped
+        peer.send_to(&[1], external).await.unwrap();
+        assert_eq!(recv(&natted).await, None);
+
+        // the source address is rewritten, and replies pass once the NAT is open
+        natted.send_to(&[2], peer_addr).await.unwrap();
+        assert_eq!(recv(&peer).await, Some((vec![2], external)));
+        peer.send_to(&[3], external).await.unwrap();
+        assert_eq!(recv(&natted).await, Some((vec![3], peer_addr)));
+    }
+}
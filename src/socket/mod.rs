@@ -1,25 +1,161 @@
-use crate::Executor;
+use crate::{
+    ipmode::IpMode, metrics::InternalMetrics, permit_ban::BanReason, Executor, PermitBanList,
+};
 use parking_lot::RwLock;
 use recv::*;
 use send::*;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::{mpsc, oneshot};
+use socket2::{Domain, Protocol, Type};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
 
 mod filter;
+pub mod memory;
 mod recv;
 mod send;
+mod transport;
 
-pub use filter::{FilterConfig, FilterConfigBuilder};
+pub(crate) use filter::RateLimiter;
+pub use filter::{FilterConfig, FilterConfigBuilder, RateLimitAction};
 pub use recv::InboundPacket;
 pub use send::OutboundPacket;
+pub use transport::Transport;
+
+/// The sockets the server listens on. A server may listen on a single IPv4 or IPv6 socket, or on
+/// one socket of each family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenConfig {
+    /// Listen on a single IPv4 socket.
+    Ipv4(SocketAddrV4),
+    /// Listen on a single IPv6 socket.
+    Ipv6(SocketAddrV6),
+    /// Listen on both an IPv4 and an IPv6 socket. Outbound packets are sent from the socket
+    /// matching the family of the destination.
+    DualStack {
+        ipv4: SocketAddrV4,
+        ipv6: SocketAddrV6,
+    },
+}
+
+impl ListenConfig {
+    /// The IPv4 listening socket, if any.
+    pub fn ipv4(&self) -> Option<SocketAddr> {
+        match self {
+            ListenConfig::Ipv4(ipv4) | ListenConfig::DualStack { ipv4, .. } => {
+                Some(SocketAddr::V4(*ipv4))
+            }
+            ListenConfig::Ipv6(_) => None,
+        }
+    }
+
+    /// The IPv6 listening socket, if any.
+    pub fn ipv6(&self) -> Option<SocketAddr> {
+        match self {
+            ListenConfig::Ipv6(ipv6) | ListenConfig::DualStack { ipv6, .. } => {
+                Some(SocketAddr::V6(*ipv6))
+            }
+            ListenConfig::Ipv4(_) => None,
+        }
+    }
+
+    /// Whether `socket_addr` is one of the listening sockets.
+    pub fn contains(&self, socket_addr: &SocketAddr) -> bool {
+        self.ipv4().as_ref() == Some(socket_addr) || self.ipv6().as_ref() == Some(socket_addr)
+    }
+
+    /// The address families peers can be contacted on.
+    pub(crate) fn ip_mode(&self) -> IpMode {
+        match self {
+            ListenConfig::Ipv4(_) => IpMode::Ip4,
+            ListenConfig::Ipv6(_) => IpMode::Ip6,
+            ListenConfig::DualStack { .. } => IpMode::DualStack,
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenConfig {
+    fn from(socket_addr: SocketAddr) -> Self {
+        match socket_addr {
+            SocketAddr::V4(ipv4) => ListenConfig::Ipv4(ipv4),
+            SocketAddr::V6(ipv6) => ListenConfig::Ipv6(ipv6),
+        }
+    }
+}
+
+/// The bound transports of each address family, shared between the send and recv tasks.
+#[derive(Clone)]
+pub struct Transports {
+    pub ipv4: Option<Arc<dyn Transport>>,
+    pub ipv6: Option<Arc<dyn Transport>>,
+}
+
+impl Transports {
+    /// Uses a single transport, for the address family of its local address.
+    pub fn single(transport: impl Transport) -> Result<Self, std::io::Error> {
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        Ok(match transport.local_addr()? {
+            SocketAddr::V4(_) => Transports {
+                ipv4: Some(transport),
+                ipv6: None,
+            },
+            SocketAddr::V6(_) => Transports {
+                ipv4: None,
+                ipv6: Some(transport),
+            },
+        })
+    }
+
+    /// The local addresses of the transports.
+    pub fn listen_config(&self) -> Result<ListenConfig, std::io::Error> {
+        let local_addr = |transport: &Option<Arc<dyn Transport>>| {
+            transport
+                .as_ref()
+                .map(|transport| transport.local_addr())
+                .transpose()
+        };
+        match (local_addr(&self.ipv4)?, local_addr(&self.ipv6)?) {
+            (Some(SocketAddr::V4(ipv4)), Some(SocketAddr::V6(ipv6))) => {
+                Ok(ListenConfig::DualStack { ipv4, ipv6 })
+            }
+            (Some(SocketAddr::V4(ipv4)), None) => Ok(ListenConfig::Ipv4(ipv4)),
+            (None, Some(SocketAddr::V6(ipv6))) => Ok(ListenConfig::Ipv6(ipv6)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Transports must be bound to an address of their family",
+            )),
+        }
+    }
+
+    /// The transport used to reach `dst`, if we listen on its address family.
+    pub fn for_destination(&self, dst: &SocketAddr) -> Option<&Arc<dyn Transport>> {
+        match dst {
+            SocketAddr::V4(_) => self.ipv4.as_ref(),
+            SocketAddr::V6(_) => self.ipv6.as_ref(),
+        }
+    }
+}
+
 /// Convenience objects for setting up the recv handler.
 pub struct SocketConfig {
     /// The executor to spawn the tasks.
     pub executor: Box<dyn Executor + Send + Sync>,
-    /// The listening socket.
-    pub socket_addr: SocketAddr,
     /// Configuration details for the packet filter.
     pub filter_config: FilterConfig,
+    /// The permit/ban list applied by the packet filter.
+    pub permit_ban_list: Arc<RwLock<PermitBanList>>,
+    /// The duration of bans issued by the packet filter.
+    pub ban_duration: Option<Duration>,
+    /// The metrics updated by the packet filter.
+    pub metrics: Arc<InternalMetrics>,
+    /// The channel on which the packet filter reports the nodes it bans.
+    pub ban_events: mpsc::UnboundedSender<(enr::NodeId, BanReason)>,
     /// The expected responses reference.
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The local node id used to decrypt messages.
@@ -35,28 +171,52 @@ pub struct Socket {
 }
 
 impl Socket {
-    /// This creates and binds a new UDP socket.
-    // In general this function can be expanded to handle more advanced socket creation.
-    pub(crate) async fn new_socket(
-        socket: &SocketAddr,
-    ) -> Result<tokio::net::UdpSocket, std::io::Error> {
-        tokio::net::UdpSocket::bind(socket).await
+    /// This creates and binds the UDP sockets of a `ListenConfig`.
+    /// This needs to be run inside of a tokio executor.
+    pub(crate) fn new_sockets(listen_config: &ListenConfig) -> Result<Transports, std::io::Error> {
+        let bind = |addr: SocketAddr| -> Result<Arc<dyn Transport>, std::io::Error> {
+            Ok(Arc::new(Self::new_socket(&addr)?))
+        };
+        Ok(Transports {
+            ipv4: listen_config.ipv4().map(bind).transpose()?,
+            ipv6: listen_config.ipv6().map(bind).transpose()?,
+        })
+    }
+
+    /// This creates and binds a new UDP socket. IPv6 sockets only accept IPv6 traffic, so that
+    /// an IPv4 socket can be bound to the same port.
+    fn new_socket(socket_addr: &SocketAddr) -> Result<UdpSocket, std::io::Error> {
+        let socket = socket2::Socket::new(
+            Domain::for_address(*socket_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        if socket_addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&(*socket_addr).into())?;
+        UdpSocket::from_std(socket.into())
     }
 
-    /// Creates a UDP socket, spawns a send/recv task and returns the channels.
+    /// Spawns a send/recv task for the bound transports and returns the channels.
     /// If this struct is dropped, the send/recv tasks will shutdown.
     /// This needs to be run inside of a tokio executor.
     pub(crate) fn new(
-        socket: tokio::net::UdpSocket,
+        transports: Transports,
         config: SocketConfig,
     ) -> Result<Self, std::io::Error> {
-        // Arc the udp socket for the send/recv tasks.
-        let recv_udp = Arc::new(socket);
-        let send_udp = recv_udp.clone();
+        // The transports are shared between the send/recv tasks.
+        let recv_udp = transports.clone();
+        let send_udp = transports;
 
         // spawn the recv handler
         let recv_config = RecvHandlerConfig {
             filter_config: config.filter_config,
+            permit_ban_list: config.permit_ban_list,
+            ban_duration: config.ban_duration,
+            metrics: config.metrics,
+            ban_events: config.ban_events,
             executor: config.executor.clone(),
             recv: recv_udp,
             local_node_id: config.local_node_id,
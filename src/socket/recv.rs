@@ -2,14 +2,17 @@
 //!
 //! Every UDP packet passes a filter before being processed.
 
-use super::filter::{Filter, FilterConfig};
-use crate::{node_info::NodeAddress, packet::*, Executor};
-use parking_lot::RwLock;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot},
+use super::{
+    filter::{Filter, FilterConfig},
+    Transport, Transports,
+};
+use crate::{
+    metrics::InternalMetrics, node_info::NodeAddress, packet::*, permit_ban::BanReason, Executor,
+    PermitBanList,
 };
+use parking_lot::RwLock;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, trace, warn};
 
 /// The object sent back by the Recv handler.
@@ -27,23 +30,25 @@ pub struct InboundPacket {
 /// Convenience objects for setting up the recv handler.
 pub struct RecvHandlerConfig {
     pub filter_config: FilterConfig,
+    pub permit_ban_list: Arc<RwLock<PermitBanList>>,
+    pub ban_duration: Option<Duration>,
+    pub metrics: Arc<InternalMetrics>,
+    pub ban_events: mpsc::UnboundedSender<(enr::NodeId, BanReason)>,
     pub executor: Box<dyn Executor>,
-    pub recv: Arc<UdpSocket>,
+    pub recv: Transports,
     pub local_node_id: enr::NodeId,
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
 }
 
 /// The main task that handles inbound UDP packets.
 pub(crate) struct RecvHandler {
-    /// The UDP recv socket.
-    recv: Arc<UdpSocket>,
+    /// The transports to receive on.
+    recv: Transports,
     /// The list of waiting responses. These are used to allow incoming packets from sources
     /// that we are expected a response from bypassing the rate-limit filters.
     expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The packet filter which decides whether to accept or reject inbound packets.
     filter: Filter,
-    /// The buffer to accept inbound datagrams.
-    recv_buffer: [u8; MAX_PACKET_SIZE],
     /// The local node id used to decrypt headers of messages.
     node_id: enr::NodeId,
     /// The channel to send the packet handler.
@@ -64,8 +69,13 @@ impl RecvHandler {
 
         let mut recv_handler = RecvHandler {
             recv: config.recv,
-            filter: Filter::new(&config.filter_config),
-            recv_buffer: [0; MAX_PACKET_SIZE],
+            filter: Filter::new(
+                &config.filter_config,
+                config.permit_ban_list,
+                config.ban_duration,
+                config.metrics,
+                config.ban_events,
+            ),
             node_id: config.local_node_id,
             expected_responses: config.expected_responses,
             handler,
@@ -82,10 +92,18 @@ impl RecvHandler {
 
     /// The main future driving the recv handler. This will shutdown when the exit future is fired.
     async fn start(&mut self) {
+        // The buffers to accept inbound datagrams on each socket.
+        let mut ipv4_buffer = [0; MAX_PACKET_SIZE];
+        let mut ipv6_buffer = [0; MAX_PACKET_SIZE];
+        let ipv4 = self.recv.ipv4.clone();
+        let ipv6 = self.recv.ipv6.clone();
         loop {
             tokio::select! {
-                Ok((length, src)) = self.recv.recv_from(&mut self.recv_buffer) => {
-                    self.handle_inbound(src, length).await;
+                Ok((length, src)) = recv_from(&ipv4, &mut ipv4_buffer) => {
+                    self.handle_inbound(src, &ipv4_buffer[..length]).await;
+                }
+                Ok((length, src)) = recv_from(&ipv6, &mut ipv6_buffer) => {
+                    self.handle_inbound(src, &ipv6_buffer[..length]).await;
                 }
                 _ = &mut self.exit => {
                     debug!("Recv handler shutdown");
@@ -97,7 +115,7 @@ impl RecvHandler {
 
     /// Handles in incoming packet. Passes through the filter, decodes and sends to the packet
     /// handler.
-    async fn handle_inbound(&mut self, src_address: SocketAddr, length: usize) {
+    async fn handle_inbound(&mut self, src_address: SocketAddr, data: &[u8]) {
         // Permit all expected responses
         let permitted = self.expected_responses.read().get(&src_address).is_some();
 
@@ -108,14 +126,13 @@ impl RecvHandler {
             return;
         }
         // Decodes the packet
-        let (packet, authenticated_data) =
-            match Packet::decode(&self.node_id, &self.recv_buffer[..length]) {
-                Ok(p) => p,
-                Err(e) => {
-                    debug!("Packet decoding failed: {:?}", e); // could not decode the packet, drop it
-                    return;
-                }
-            };
+        let (packet, authenticated_data) = match Packet::decode(&self.node_id, data) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Packet decoding failed: {:?}", e); // could not decode the packet, drop it
+                return;
+            }
+        };
 
         // If this is not a challenge packet, we immediately know its src_id and so pass it
         // through the second filter.
@@ -146,3 +163,15 @@ impl RecvHandler {
             .unwrap_or_else(|e| warn!("Could not send packet to handler: {}", e));
     }
 }
+
+/// Receives a datagram on `transport`. If we do not listen on the transport's address family,
+/// this never resolves.
+async fn recv_from(
+    transport: &Option<Arc<dyn Transport>>,
+    buffer: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match transport {
+        Some(transport) => transport.recv_from(buffer).await,
+        None => futures::future::pending().await,
+    }
+}
@@ -1,10 +1,7 @@
 //! This is a standalone task that encodes and sends Discv5 UDP packets
+use super::Transports;
 use crate::{node_info::NodeAddress, packet::*, Executor};
-use std::sync::Arc;
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot},
-};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, trace, warn};
 
 pub struct OutboundPacket {
@@ -16,8 +13,8 @@ pub struct OutboundPacket {
 
 /// The main task that handles outbound UDP packets.
 pub(crate) struct SendHandler {
-    /// The UDP send socket.
-    send: Arc<UdpSocket>,
+    /// The transports to send on.
+    send: Transports,
     /// The channel to respond to send requests.
     handler_recv: mpsc::Receiver<OutboundPacket>,
     /// Exit channel to shutdown the handler.
@@ -30,7 +27,7 @@ impl SendHandler {
     /// shutdown the handler.
     pub(crate) fn spawn(
         executor: Box<dyn Executor>,
-        send: Arc<UdpSocket>,
+        send: Transports,
     ) -> (mpsc::Sender<OutboundPacket>, oneshot::Sender<()>) {
         let (exit_send, exit) = oneshot::channel();
         let (handler_send, handler_recv) = mpsc::channel(30);
@@ -58,7 +55,15 @@ impl SendHandler {
                     if encoded_packet.len() > MAX_PACKET_SIZE {
                         warn!("Sending packet larger than max size: {} max: {}", encoded_packet.len(), MAX_PACKET_SIZE);
                     }
-                    if let Err(e) = self.send.send_to(&encoded_packet, &packet.node_address.socket_addr).await {
+                    let dst = packet.node_address.socket_addr;
+                    let transport = match self.send.for_destination(&dst) {
+                        Some(transport) => transport,
+                        None => {
+                            trace!("No listening socket for the address family of: {}", dst);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = transport.send_to(&encoded_packet, dst).await {
                         trace!("Could not send packet. Error: {:?}", e);
                     }
                 }
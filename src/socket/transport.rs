@@ -0,0 +1,42 @@
+//! The transport the send/recv tasks exchange datagrams over.
+//!
+//! By default a server sends and receives over bound UDP sockets. Any other datagram transport,
+//! such as the in-memory [`MemoryNetwork`](super::memory::MemoryNetwork) used for simulations,
+//! can be supplied by implementing [`Transport`].
+
+use futures::future::BoxFuture;
+use std::{io, net::SocketAddr};
+use tokio::net::UdpSocket;
+
+/// A datagram transport bound to a single local address.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a datagram to `dst`, returning the number of bytes sent.
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: SocketAddr) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Receives a single datagram into `buf`, returning its length and source address.
+    ///
+    /// The returned future must be cancel safe: if it is dropped before completing, no datagram
+    /// may be lost.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8])
+        -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+
+    /// The local address the transport is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(UdpSocket::send_to(self, buf, dst))
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}